@@ -1,292 +1,737 @@
+pub use crate::errors::{
+    ImportError, RequestRejection, ResponseFailure, SendMessageError, ShareError,
+};
 use common::*;
 #[cfg(feature = "ssr")]
 use google_ai_rs::client::Client;
-use leptos::prelude::ServerFnError;
-#[cfg(feature = "ssr")]
-use leptos::serde_json::Value;
 use leptos::server;
+use leptos::server_fn::codec::Json;
+use leptos::server_fn::ServerFnError;
 
-#[server]
-pub async fn send_message(_req: SendMessageRequest) -> Result<SendMessageResponse, ServerFnError> {
+/// 使用するモデル
+#[cfg(feature = "ssr")]
+const MODEL_NAME: &str = "gemini-2.0-flash";
+
+/// 共有リンクに指定できる有効期限の上限（日）
+#[cfg(feature = "ssr")]
+const MAX_SHARE_EXPIRY_DAYS: u32 = 365;
+
+/// 出力が途中で切れた場合に再試行する最大回数
+#[cfg(feature = "ssr")]
+const MAX_TRUNCATION_RETRIES: usize = 2;
+
+// UI状態のスナップショットはネストが深いためJSONで送受信する
+#[server(input = Json)]
+pub async fn send_message(
+    _req: SendMessageRequest,
+) -> Result<SendMessageResponse, SendMessageError> {
     #[cfg(feature = "ssr")]
     {
+        // 0. リクエスト検証（上限と履歴の整合性）
+        use crate::request_validation::{validate_request, RequestLimits};
+        validate_request(&_req, &RequestLimits::from_env())
+            .map_err(SendMessageError::InvalidRequest)?;
+
         // 1. APIキー取得
         let api_key = std::env::var("GEMINI_API_KEY")
-            .map_err(|e| ServerFnError::new(format!("APIキーが見つかりません: {}", e)))?;
+            .map_err(|e| SendMessageError::Internal(format!("APIキーが見つかりません: {}", e)))?;
 
         // 2. クライアント初期化
         let client = Client::new(api_key)
             .await
-            .map_err(|e| ServerFnError::new(format!("クライアント初期化エラー: {}", e)))?;
-
-        let model = client.generative_model("gemini-2.0-flash");
-        let mut chat = model.start_chat();
-
-        // 3. プロンプト作成（拡張版）
-        let prompt_template = r#"あなたはUI変更のためのJSONデータを生成するアシスタントです。
-
-            ルール:
-            - 出力は純粋なJSONのみ
-            - CSSプロパティのみを使用（background-color, color, font-size, font-family, font-weight, border, padding, margin等）
-            - 危険なプロパティ（javascript:, expression()等）は禁止
-            - スタイル変更は永続的に適用される
-            - 特定要素指定時は他の要素のスタイルを保持する
-            - 「文字の色」「文字サイズ」「文字の太さ」等の指示は、既存の全てのメッセージ要素（ID: 0, 1, 2...）に適用する
-            - 要素のIDは0から始まり、現在のメッセージ数に応じて増加する
-            - メッセージ要素のスタイルは、親のdiv要素に適用して子要素のpタグ（message-textクラス）に継承させる
-            - フォント関連のスタイル（color, font-size, font-weight等）は親要素に適用することで子要素に継承される
-
-            現在のメッセージ状態:
-            {MESSAGE_CONTEXT}
-
-            対応可能な操作:
-
-            1. スタイル変更:
-            - 全体背景を青くして: {"success": true, "message": "背景を青に変更しました", "chat_container_styles": "background-color: #3b82f6;", "change_style_elements": [], "new_elements": []}
-            - 2番目の要素を青くして: {"success": true, "message": "2番目の吹き出しを青にしました", "chat_container_styles": "", "change_style_elements": [{"id": 2, "styles": "background-color: #3b82f6; color: white;"}], "new_elements": []}
-            - 文字を太字にして: {"success": true, "message": "文字を太字にしました", "chat_container_styles": "", "change_style_elements": [{"id": 0, "styles": "font-weight: bold;"}, {"id": 1, "styles": "font-weight: bold;"}], "new_elements": []}
-            - 文字の色を白にして: {"success": true, "message": "文字の色を白に変更しました", "chat_container_styles": "", "change_style_elements": [{"id": 0, "styles": "color: white;"}, {"id": 1, "styles": "color: white;"}], "new_elements": []}
-            - 文字サイズを大きくして: {"success": true, "message": "文字サイズを大きくしました", "chat_container_styles": "", "change_style_elements": [{"id": 0, "styles": "font-size: 18px;"}, {"id": 1, "styles": "font-size: 18px;"}], "new_elements": []}
-
-            2. 新しい要素の追加:
-            - ボタンを追加して: {"success": true, "message": "ボタンを追加しました", "chat_container_styles": "", "change_style_elements": [], "new_elements": [{"id": 0, "tag": "button", "text": "クリックしてください", "styles": "background-color: #007bff; color: white; padding: 10px 20px; border: none; border-radius: 5px; cursor: pointer;", "attributes": null}]}
-            - 画像を追加して: {"success": true, "message": "画像を追加しました", "chat_container_styles": "", "change_style_elements": [], "new_elements": [{"id": 0, "tag": "img", "text": null, "styles": "max-width: 100%; height: auto; border-radius: 8px; margin: 10px 0; display: block;", "attributes": {"src": "https://picsum.photos/300/200", "alt": "サンプル画像"}}]}
-            - 猫の画像を表示して: {"success": true, "message": "猫の画像を表示しました", "chat_container_styles": "", "change_style_elements": [], "new_elements": [{"id": 0, "tag": "img", "text": null, "styles": "max-width: 300px; height: 200px; border-radius: 10px; margin: 15px auto; display: block; box-shadow: 0 4px 8px rgba(0,0,0,0.1);", "attributes": {"src": "https://cataas.com/cat", "alt": "可愛い猫の画像"}}]}
-            - リンクを追加して: {"success": true, "message": "リンクを追加しました", "chat_container_styles": "", "change_style_elements": [], "new_elements": [{"id": 0, "tag": "a", "text": "こちらをクリック", "styles": "color: #007bff; text-decoration: underline; font-weight: bold;", "attributes": {"href": "https://example.com"}}]}
-            - リンクボタンを作って: {"success": true, "message": "リンクボタンを作成しました", "chat_container_styles": "", "change_style_elements": [], "new_elements": [{"id": 0, "tag": "a", "text": "Googleへ移動", "styles": "display: inline-block; background-color: #007bff; color: white; padding: 10px 20px; border: none; border-radius: 5px; cursor: pointer; text-decoration: none; font-weight: bold;", "attributes": {"href": "https://google.com", "target": "_blank"}}]}
-            - google.comに飛ぶボタンを作って: {"success": true, "message": "Googleに飛ぶボタンを作成しました", "chat_container_styles": "", "change_style_elements": [], "new_elements": [{"id": 0, "tag": "a", "text": "Googleへ", "styles": "display: inline-block; background-color: #4285f4; color: white; padding: 12px 24px; border: none; border-radius: 6px; cursor: pointer; text-decoration: none; font-weight: bold; transition: background-color 0.3s;", "attributes": {"href": "https://google.com", "target": "_blank"}}]}
-            - テキストを追加して: {"success": true, "message": "テキストを追加しました", "chat_container_styles": "", "change_style_elements": [], "new_elements": [{"id": 0, "tag": "p", "text": "追加されたテキストです", "styles": "color: #333; font-size: 16px; margin: 10px 0;", "attributes": null}]}
-            - 区切り線を追加して: {"success": true, "message": "区切り線を追加しました", "chat_container_styles": "", "change_style_elements": [], "new_elements": [{"id": 0, "tag": "hr", "text": null, "styles": "border: none; height: 2px; background-color: #ddd; margin: 20px 0;", "attributes": null}]}
-
-            3. 複合操作:
-            - 背景を変えてボタンも追加して: {"success": true, "message": "背景を変更し、ボタンも追加しました", "chat_container_styles": "background-color: #f8f9fa;", "change_style_elements": [], "new_elements": [{"id": 0, "tag": "button", "text": "新しいボタン", "styles": "background-color: #28a745; color: white; padding: 12px 24px; border: none; border-radius: 6px; cursor: pointer; margin: 10px 0;", "attributes": null}]}
-
-            利用可能なHTMLタグ:
-            - button: ボタン要素（textフィールドにボタンテキスト、attributesはnull）
-            - img: 画像要素（textはnull、attributesにsrcとaltを指定、stylesにdisplay: blockを推奨）
-            - a: リンク要素（textフィールドにリンクテキスト、attributesにhrefとtargetを指定）
-            - p: 段落要素（textフィールドにテキスト、attributesはnull）
-            - div: 汎用コンテナ要素（textフィールドにテキスト、attributesはnull）
-            - span: インライン要素（textフィールドにテキスト、attributesはnull）
-            - h1, h2, h3, h4, h5, h6: 見出し要素（textフィールドにテキスト、attributesはnull）
-            - hr: 区切り線要素（textはnull、attributesはnull）
-            - br: 改行要素（textはnull、attributesはnull）
-
-            画像の重要なポイント:
-            - 必ず<img>タグを使用
-            - src属性に完全なURLを指定（https://から始まる）
-            - alt属性で画像の説明を提供
-            - stylesにdisplay: blockを追加して適切に表示
-            - max-width: 100%でレスポンシブ対応
-            - border-radiusで角を丸くする
-
-            リンクボタンの重要なポイント:
-            - 外部サイトへのリンクは必ず<a>タグを使用（<button>タグでは外部リンク不可）
-            - href属性に完全なURLを指定（https://から始まる）
-            - target="_blank"を指定して新しいタブで開く
-            - text-decoration: noneでアンダーラインを消す
-            - display: inline-blockでブロック要素として表示
-
-            重要: 全ての要素には必ずidフィールドを含めること（通常は0から開始）
-
-            ユーザーリクエスト: {USER_REQ}
-
-            JSON出力:"#;
-
-        let prompt = prompt_template
-            .replace("{USER_REQ}", &_req.text)
-            .replace("{MESSAGE_CONTEXT}", &format!(
-                "現在のメッセージ一覧（総数: {}）:\n{}\n\n注意: ユーザーが新しいメッセージを送信した後、AIの返信メッセージのIDは {} になります。",
-                _req.messages.len(),
-                _req.messages.iter()
-                    .map(|msg| format!("ID: {}, is_user: {}, text: \"{}\"", msg.id, msg.is_user, msg.text))
-                    .collect::<Vec<_>>()
-                    .join("\n"),
-                _req.messages.len()
-            ));
-
-        // 4. モデル呼び出し
-        let response = chat
-            .send_message(prompt)
-            .await
-            .map_err(|e| ServerFnError::new(format!("API呼び出しエラー: {}", e)))?;
-
-        // 5. JSON抽出
-        let raw_text = response
-            .candidates
-            .first()
-            .and_then(|c| c.content.as_ref())
-            .and_then(|c| c.parts.first())
-            .map(|p| p.to_text())
-            .ok_or(ServerFnError::new(
-                "AIからの応答がテキストではありませんでした".to_string(),
-            ))?;
-
-        // コードフェンスや "JSON:" 接頭辞を除去
-        fn normalize_ai_output(text: &str) -> String {
-            let mut s = text.trim().to_string();
-            if let Some(pos) = s.to_lowercase().find("json:") {
-                s = s[(pos + 5)..].trim().to_string();
+            .map_err(|e| SendMessageError::Internal(format!("クライアント初期化エラー: {}", e)))?;
+
+        // 3. プロンプト作成（指示はシステム指示、ユーザー由来のテキストはデータとして分離）
+        use crate::context_window::estimate_tokens;
+        use crate::context_window::ContextBudget;
+        use crate::prompt::{build_contents, contents_tokens};
+        let prompts = crate::prompt_templates::current(_req.locale)
+            .map_err(|e| SendMessageError::Internal(format!("プロンプト読み込みエラー: {}", e)))?;
+        // 出力例はライブラリから今回の要求に近いものだけを選んで埋め込む
+        use crate::few_shot::example_count_from_env;
+        let system_instruction = prompts.system_instruction(&_req.text, example_count_from_env());
+        let contents = build_contents(
+            &prompts.user,
+            &system_instruction,
+            &_req,
+            &ContextBudget::for_provider("gemini"),
+        );
+        // プロバイダーがトークン数を返さなかった場合の推定値
+        let estimated_prompt_tokens =
+            estimate_tokens(&system_instruction) + contents_tokens(&contents);
+        // 生成パラメータはサーバーの設定にリクエストごとの上書きを上限内で重ねる
+        use crate::generation::{GenerationLimits, GenerationSettings};
+        let limits = GenerationLimits::from_env();
+        let mut generation = GenerationSettings::from_env().resolve(&_req.generation, &limits);
+        let model = client
+            .generative_model(MODEL_NAME)
+            .with_system_instruction(system_instruction);
+
+        // 4. モデル呼び出し（過去のやり取りはユーザー/モデルのターンとして渡す）
+        //    出力が途中で切れた場合は、最大出力トークン数を広げて再試行する
+        use crate::model_response::response_texts;
+        let mut reported: Option<(u64, u64)> = None;
+        let mut attempts = 0;
+        let raw_texts = loop {
+            attempts += 1;
+            log::debug!("generation={:?}", generation);
+            let response = generation
+                .apply(model.clone())
+                .generate_content(contents.clone())
+                .await
+                .map_err(|e| SendMessageError::Internal(format!("API呼び出しエラー: {}", e)))?;
+            if let Some(m) = response.usage_metadata.as_ref() {
+                let (prompt, completion) = reported.unwrap_or_default();
+                reported = Some((
+                    prompt + m.prompt_token_count.max(0) as u64,
+                    completion + m.candidates_token_count.max(0) as u64,
+                ));
             }
-            if s.starts_with("```") {
-                let mut t = s.trim_start_matches('`').to_string();
-                if let Some(idx) = t.find('\n') {
-                    t = t[idx + 1..].to_string();
+
+            // 5. 応答の確認（ブロック、打ち切り、空の応答はそれぞれの理由で返す）
+            let failure = match response_texts(&response) {
+                Ok(texts) => break texts,
+                Err(failure) => failure,
+            };
+            let retry =
+                if failure == ResponseFailure::Truncated && attempts <= MAX_TRUNCATION_RETRIES {
+                    generation.with_larger_output_budget(&limits)
+                } else {
+                    None
+                };
+            match retry {
+                Some(larger) => {
+                    log::warn!(
+                        "応答が途中で切れたため再試行します: max_output_tokens={:?}",
+                        larger.max_output_tokens
+                    );
+                    generation = larger;
                 }
-                if let Some(end) = t.rfind("```") {
-                    t = t[..end].trim().to_string();
+                None => {
+                    log::warn!("モデルの応答を使えません: {:?}", failure);
+                    return Err(SendMessageError::ModelResponse(failure));
                 }
-                s = t;
             }
-            s
-        }
+        };
 
-        // 最初の完全なJSONオブジェクトを抽出
-        fn extract_first_json_object(text: &str) -> Option<String> {
-            let bytes = text.as_bytes();
-            let mut start = None;
-            let mut depth: i32 = 0;
-            for (i, &b) in bytes.iter().enumerate() {
-                if b == b'{' {
-                    if start.is_none() {
-                        start = Some(i);
-                    }
-                    depth += 1;
-                } else if b == b'}' {
-                    if depth > 0 {
-                        depth -= 1;
-                    }
-                    if depth == 0 {
-                        if let Some(s) = start {
-                            return Some(text[s..=i].to_string());
-                        }
-                    }
-                }
+        // 6. JSON解析・サニタイズ・コントラストチェック
+        //    候補が複数あれば、そのまま採用できる最初のもの（なければ最も問題の少ないもの）を選ぶ
+        use crate::ai_output::select_ai_output;
+        use crate::contrast::ContrastChecker;
+        let mut res = select_ai_output(&raw_texts, &_req, &ContrastChecker::from_env())?;
+
+        // 7. 比較できるよう、使用したプロンプトのバージョンを記録する
+        let version = format!("{}/{}", _req.locale.code(), prompts.version());
+        log::info!("prompt_version={} success={}", version, res.success);
+        res.prompt_version = Some(version);
+
+        // 8. トークン数と料金を記録する
+        use crate::usage::{token_usage, PriceTable};
+        // 再試行した場合は、すべての呼び出しの合計を記録する
+        let usage = token_usage(
+            MODEL_NAME,
+            reported,
+            estimated_prompt_tokens * attempts,
+            &raw_texts.concat(),
+            &PriceTable::from_env(),
+        );
+        log::info!(
+            "usage model={} prompt_tokens={} completion_tokens={} estimated={} cost_usd={:?}",
+            usage.model,
+            usage.prompt_tokens,
+            usage.completion_tokens,
+            usage.estimated,
+            usage.cost_usd
+        );
+        res.usage = Some(usage);
+
+        // 9. 保存先があれば、返信を適用した後の会話とUI状態をセッションに保存し、版として記録する
+        use crate::storage::{current_owner, is_valid_id, snapshot_after_reply};
+        if let (Some(session_id), Some((store, owner))) = (
+            _req.session_id.as_deref().filter(|id| is_valid_id(id)),
+            current_owner(true),
+        ) {
+            // 分岐に使うメッセージごとのUI状態の変更は、保存済みのものに返信の分を加える
+            let ui_history = store
+                .load(&owner, session_id)
+                .ok()
+                .flatten()
+                .map(|saved| saved.ui_history)
+                .unwrap_or_default();
+            let snapshot = snapshot_after_reply(&_req, &res, ui_history);
+            if let Err(e) = store.save_version(&owner, session_id, &snapshot, None) {
+                log::error!("セッションを保存できません: {}", e);
             }
-            None
         }
+        Ok(res)
+    }
 
-        let normalized = normalize_ai_output(raw_text.trim());
-        let candidate_json =
-            extract_first_json_object(&normalized).unwrap_or_else(|| normalized.clone());
-
-        // 6. CSSサニタイザーを初期化
-        use crate::css_sanitizer::CssSanitizer;
-        let sanitizer = CssSanitizer::new();
-
-        // 7. JSON解析とサニタイズ
-        let v: Value = match leptos::serde_json::from_str::<Value>(&candidate_json) {
-            Ok(val) => val,
-            Err(e) => {
-                log::warn!(
-                    "JSON parse failed. Fallback applied. error={}, content={}",
-                    e,
-                    candidate_json
-                );
-                return Ok(SendMessageResponse {
-                    success: false,
-                    message: "AIの出力がJSONとして不正だったため、UIは変更していません。もう一度具体的に指示してください。".to_string(),
-                    chat_container_styles: None,
-                    change_style_elements: None,
-                    new_elements: None,
-                });
-            }
+    #[cfg(not(feature = "ssr"))]
+    {
+        // クライアント側では、この関数は自動的にHTTPリクエストに変換されます
+        unreachable!("Server function should not be called directly on client side")
+    }
+}
+
+/// 保存先と所有者ID（保存先がなければエラー）
+#[cfg(feature = "ssr")]
+fn session_owner() -> Result<(crate::storage::SharedSessionStore, String), ServerFnError> {
+    crate::storage::current_owner(true)
+        .ok_or_else(|| ServerFnError::new("セッションの保存先がありません"))
+}
+
+/// 所有者のセッションのIDとして受け付けられるか
+#[cfg(feature = "ssr")]
+fn checked_session_id(session_id: &str) -> Result<&str, ServerFnError> {
+    if crate::storage::is_valid_id(session_id) {
+        Ok(session_id)
+    } else {
+        Err(ServerFnError::new(
+            crate::storage::StoreError::NotFound.to_string(),
+        ))
+    }
+}
+
+/// メッセージごとのUI状態の変更が、UI状態と同じ上限に収まっているか
+#[cfg(feature = "ssr")]
+fn checked_ui_history(snapshot: &SessionSnapshot) -> Result<(), ServerFnError> {
+    let limits = crate::request_validation::RequestLimits::from_env();
+    let too_large = snapshot.ui_history.values().any(|delta| {
+        serde_json::to_vec(delta).map_or(true, |json| json.len() > limits.max_ui_state_bytes)
+    });
+    if too_large {
+        return Err(ServerFnError::new("UI状態の変更の履歴が大きすぎます"));
+    }
+    Ok(())
+}
+
+/// セッション名・テーマ名を整える（空ならエラー）
+#[cfg(feature = "ssr")]
+fn checked_session_name(name: &str) -> Result<String, ServerFnError> {
+    crate::sessions::normalize_name(name).ok_or_else(|| ServerFnError::new("名前が空です"))
+}
+
+/// 名前付きセッションの一覧（新しく保存した順）。保存先がなければ None
+#[server]
+pub async fn list_sessions() -> Result<Option<Vec<SessionSummary>>, ServerFnError> {
+    #[cfg(feature = "ssr")]
+    {
+        let Some((store, owner)) = crate::storage::current_owner(true) else {
+            return Ok(None);
         };
+        store
+            .list(&owner)
+            .map(Some)
+            .map_err(|e| ServerFnError::new(e.to_string()))
+    }
 
-        // 基本的な型チェック
-        if v.get("success").and_then(|x| x.as_bool()).is_none() {
-            return Err(ServerFnError::new(
-                "JSONフィールド 'success' が bool ではありません",
-            ));
-        }
-        if v.get("message").and_then(|x| x.as_str()).is_none() {
-            return Err(ServerFnError::new(
-                "JSONフィールド 'message' が string ではありません",
-            ));
+    #[cfg(not(feature = "ssr"))]
+    {
+        unreachable!("Server function should not be called directly on client side")
+    }
+}
+
+/// 名前を付けて空のセッションを作る
+#[server]
+pub async fn create_session(name: String) -> Result<SessionSummary, ServerFnError> {
+    #[cfg(feature = "ssr")]
+    {
+        let (store, owner) = session_owner()?;
+        let name = checked_session_name(&name)?;
+        let session = store
+            .create(
+                &owner,
+                &crate::storage::new_session_id(),
+                &name,
+                None,
+                &SessionSnapshot::default(),
+            )
+            .map_err(|e| ServerFnError::new(e.to_string()))?;
+        // 作ったセッションはそのまま表示される
+        crate::storage::set_active_session(Some(&session.id));
+        Ok(session)
+    }
+
+    #[cfg(not(feature = "ssr"))]
+    {
+        let _ = name;
+        unreachable!("Server function should not be called directly on client side")
+    }
+}
+
+/// セッションの会話とUI状態を複製し、新しい名前を付ける
+#[server]
+pub async fn duplicate_session(
+    session_id: String,
+    name: String,
+) -> Result<SessionSummary, ServerFnError> {
+    #[cfg(feature = "ssr")]
+    {
+        let (store, owner) = session_owner()?;
+        let name = checked_session_name(&name)?;
+        let snapshot = store
+            .load(&owner, checked_session_id(&session_id)?)
+            .map_err(|e| ServerFnError::new(e.to_string()))?
+            .ok_or_else(|| ServerFnError::new(crate::storage::StoreError::NotFound.to_string()))?;
+        store
+            .create(
+                &owner,
+                &crate::storage::new_session_id(),
+                &name,
+                None,
+                &snapshot,
+            )
+            .map_err(|e| ServerFnError::new(e.to_string()))
+    }
+
+    #[cfg(not(feature = "ssr"))]
+    {
+        let _ = (session_id, name);
+        unreachable!("Server function should not be called directly on client side")
+    }
+}
+
+#[server]
+pub async fn rename_session(session_id: String, name: String) -> Result<(), ServerFnError> {
+    #[cfg(feature = "ssr")]
+    {
+        let (store, owner) = session_owner()?;
+        store
+            .rename(
+                &owner,
+                checked_session_id(&session_id)?,
+                &checked_session_name(&name)?,
+            )
+            .map_err(|e| ServerFnError::new(e.to_string()))
+    }
+
+    #[cfg(not(feature = "ssr"))]
+    {
+        let _ = (session_id, name);
+        unreachable!("Server function should not be called directly on client side")
+    }
+}
+
+#[server]
+pub async fn delete_session(session_id: String) -> Result<(), ServerFnError> {
+    #[cfg(feature = "ssr")]
+    {
+        use crate::storage::{active_session_from_headers, set_active_session};
+        let (store, owner) = session_owner()?;
+        store
+            .delete(&owner, checked_session_id(&session_id)?)
+            .map_err(|e| ServerFnError::new(e.to_string()))?;
+        // 最後に開いていたセッションなら、次に描画するセッションを一覧の先頭に戻す
+        let was_active = leptos::prelude::use_context::<http::request::Parts>()
+            .and_then(|parts| active_session_from_headers(&parts.headers))
+            .is_some_and(|active| active == session_id);
+        if was_active {
+            set_active_session(None);
         }
+        Ok(())
+    }
 
-        // 8. データ抽出とサニタイズ
-        let success = v.get("success").and_then(|x| x.as_bool()).unwrap_or(false);
-        let message = v
-            .get("message")
-            .and_then(|x| x.as_str())
-            .unwrap_or("")
-            .to_string();
-
-        let styles_str = v
-            .get("chat_container_styles")
-            .and_then(|x| x.as_str())
-            .unwrap_or("")
-            .trim()
-            .to_string();
-        let chat_container_styles = if styles_str.is_empty() {
-            None
-        } else {
-            let sanitized = sanitizer.sanitize_css_string(&styles_str);
-            if sanitized.is_empty() {
-                None
-            } else {
-                Some(sanitized)
-            }
+    #[cfg(not(feature = "ssr"))]
+    {
+        let _ = session_id;
+        unreachable!("Server function should not be called directly on client side")
+    }
+}
+
+/// セッションの会話とUI状態を読み込む（保存先がない、または見つからなければ None）。
+/// 読み込んだセッションを、次にページを開いたときに描画するセッションにする
+#[server]
+pub async fn load_session(session_id: String) -> Result<Option<SessionSnapshot>, ServerFnError> {
+    #[cfg(feature = "ssr")]
+    {
+        let Some((store, owner)) = crate::storage::current_owner(false) else {
+            return Ok(None);
         };
+        if !crate::storage::is_valid_id(&session_id) {
+            return Ok(None);
+        }
+        let snapshot = store
+            .load(&owner, &session_id)
+            .map_err(|e| ServerFnError::new(e.to_string()))?;
+        if snapshot.is_some() {
+            crate::storage::set_active_session(Some(&session_id));
+        }
+        Ok(snapshot)
+    }
 
-        let style_val = v
-            .get("change_style_elements")
-            .cloned()
-            .unwrap_or_else(|| Value::Array(vec![]));
-        let mut styles: Vec<StyleUpdate> =
-            leptos::serde_json::from_value(style_val).unwrap_or_default();
-
-        // スタイル更新をサニタイズ
-        styles.retain_mut(|style| {
-            let sanitized = sanitizer.sanitize_css_string(&style.styles);
-            if !sanitized.is_empty() {
-                style.styles = sanitized;
-                true
-            } else {
-                false
-            }
-        });
+    #[cfg(not(feature = "ssr"))]
+    {
+        let _ = session_id;
+        unreachable!("Server function should not be called directly on client side")
+    }
+}
 
-        let change_style_elements = if styles.is_empty() {
-            None
-        } else {
-            Some(styles)
+/// ページを開いたときのセッションの一覧と、最後に開いていたセッションの会話とUI状態。
+/// 保存先がなければ None（ブラウザが localStorage から読み込む）。
+/// 描画のたびに呼ばれるため書き込まない（Cookieのない閲覧者には空の一覧を返し、
+/// 所有者とセッションはブラウザが最初に作るときに作る）
+#[server]
+pub async fn load_initial_state() -> Result<Option<InitialState>, ServerFnError> {
+    #[cfg(feature = "ssr")]
+    {
+        use crate::storage::{active_session_from_headers, current_owner, SharedSessionStore};
+        if leptos::prelude::use_context::<SharedSessionStore>().is_none() {
+            return Ok(None);
+        }
+        let Some((store, owner)) = current_owner(false) else {
+            return Ok(Some(InitialState {
+                sessions: Vec::new(),
+                active: None,
+                snapshot: SessionSnapshot::default(),
+            }));
+        };
+        let sessions = store
+            .list(&owner)
+            .map_err(|e| ServerFnError::new(e.to_string()))?;
+        let active = leptos::prelude::use_context::<http::request::Parts>()
+            .and_then(|parts| active_session_from_headers(&parts.headers))
+            .filter(|id| sessions.iter().any(|s| &s.id == id))
+            .or_else(|| sessions.first().map(|s| s.id.clone()));
+        let snapshot = match &active {
+            Some(session_id) => store
+                .load(&owner, session_id)
+                .map_err(|e| ServerFnError::new(e.to_string()))?
+                .unwrap_or_default(),
+            None => SessionSnapshot::default(),
         };
+        Ok(Some(InitialState {
+            sessions,
+            active,
+            snapshot,
+        }))
+    }
 
-        let new_val = v
-            .get("new_elements")
-            .cloned()
-            .unwrap_or_else(|| Value::Array(vec![]));
-        let mut news: Vec<DynamicElementData> =
-            leptos::serde_json::from_value(new_val).unwrap_or_default();
-
-        // 新しい要素のスタイルをサニタイズ
-        for element in &mut news {
-            if let Some(ref mut styles) = element.styles {
-                let sanitized = sanitizer.sanitize_css_string(styles);
-                if sanitized.is_empty() {
-                    element.styles = None;
-                } else {
-                    *styles = sanitized;
-                }
+    #[cfg(not(feature = "ssr"))]
+    {
+        unreachable!("Server function should not be called directly on client side")
+    }
+}
+
+/// セッションの会話とUI状態を空にする（名前と一覧の項目は残す）
+#[server]
+pub async fn reset_session(session_id: String) -> Result<(), ServerFnError> {
+    #[cfg(feature = "ssr")]
+    {
+        if let Some((store, owner)) = crate::storage::current_owner(false) {
+            store
+                .save(
+                    &owner,
+                    checked_session_id(&session_id)?,
+                    &SessionSnapshot::default(),
+                )
+                .map_err(|e| ServerFnError::new(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "ssr"))]
+    {
+        let _ = session_id;
+        unreachable!("Server function should not be called directly on client side")
+    }
+}
+
+/// UIドキュメント（JSON）を検証・サニタイズして読み込み、保存先があればセッションに保存する
+/// （読み込んだ内容は版として記録する）
+#[server(input = Json)]
+pub async fn import_ui_document(
+    document: String,
+    session_id: Option<String>,
+) -> Result<SessionSnapshot, ImportError> {
+    #[cfg(feature = "ssr")]
+    {
+        use crate::request_validation::{validate_history, RequestLimits};
+        let mut snapshot = crate::ui_document::parse(&document)?;
+        validate_history(
+            &snapshot.messages,
+            &snapshot.ui_state,
+            &RequestLimits::from_env(),
+        )
+        .map_err(ImportError::Rejected)?;
+        let removed = crate::ui_document::sanitize(&mut snapshot);
+        if removed > 0 {
+            log::info!("UIドキュメントから{}件を除去しました", removed);
+        }
+        crate::branches::record_history(&mut snapshot);
+
+        use crate::storage::{current_owner, is_valid_id};
+        if let (Some(session_id), Some((store, owner))) = (
+            session_id.as_deref().filter(|id| is_valid_id(id)),
+            current_owner(true),
+        ) {
+            if let Err(e) = store.save_version(&owner, session_id, &snapshot, None) {
+                log::error!("セッションを保存できません: {}", e);
             }
         }
+        Ok(snapshot)
+    }
 
-        let new_elements = if news.is_empty() { None } else { Some(news) };
+    #[cfg(not(feature = "ssr"))]
+    {
+        let _ = (document, session_id);
+        unreachable!("Server function should not be called directly on client side")
+    }
+}
 
-        Ok(SendMessageResponse {
-            success,
-            message,
-            chat_container_styles,
-            change_style_elements,
-            new_elements,
-        })
+/// クライアントで変更した会話とUI状態（テーマの適用など）をセッションに保存し、版として記録する
+#[server(input = Json)]
+pub async fn save_session(
+    session_id: String,
+    snapshot: SessionSnapshot,
+) -> Result<(), ServerFnError> {
+    #[cfg(feature = "ssr")]
+    {
+        use crate::request_validation::{validate_history, RequestLimits};
+        let (store, owner) = session_owner()?;
+        // 読み込みと同じく、クライアントから送られた内容は検証・サニタイズしてから保存する
+        let mut snapshot = snapshot;
+        validate_history(
+            &snapshot.messages,
+            &snapshot.ui_state,
+            &RequestLimits::from_env(),
+        )
+        .map_err(|e| ServerFnError::new(e.to_string()))?;
+        checked_ui_history(&snapshot)?;
+        crate::ui_document::sanitize(&mut snapshot);
+        crate::branches::record_history(&mut snapshot);
+        store
+            .save_version(&owner, checked_session_id(&session_id)?, &snapshot, None)
+            .map(|_| ())
+            .map_err(|e| ServerFnError::new(e.to_string()))
     }
 
     #[cfg(not(feature = "ssr"))]
     {
-        // クライアント側では、この関数は自動的にHTTPリクエストに変換されます
+        let _ = (session_id, snapshot);
+        unreachable!("Server function should not be called directly on client side")
+    }
+}
+
+/// セッションの版の一覧（古い順）。保存先がなければ空
+#[server]
+pub async fn list_versions(session_id: String) -> Result<Vec<SessionVersion>, ServerFnError> {
+    #[cfg(feature = "ssr")]
+    {
+        let Some((store, owner)) = crate::storage::current_owner(false) else {
+            return Ok(Vec::new());
+        };
+        store
+            .list_versions(&owner, checked_session_id(&session_id)?)
+            .map_err(|e| ServerFnError::new(e.to_string()))
+    }
+
+    #[cfg(not(feature = "ssr"))]
+    {
+        let _ = session_id;
+        unreachable!("Server function should not be called directly on client side")
+    }
+}
+
+/// 過去の版の会話とUI状態をセッションに戻し、新しい版として記録する（履歴は書き換えない）
+#[server]
+pub async fn restore_version(
+    session_id: String,
+    number: u32,
+) -> Result<SessionVersion, ServerFnError> {
+    #[cfg(feature = "ssr")]
+    {
+        let (store, owner) = session_owner()?;
+        let session_id = checked_session_id(&session_id)?;
+        let version = store
+            .list_versions(&owner, session_id)
+            .map_err(|e| ServerFnError::new(e.to_string()))?
+            .into_iter()
+            .find(|v| v.number == number)
+            .ok_or_else(|| ServerFnError::new("版が見つかりません"))?;
+        store
+            .save_version(&owner, session_id, &version.snapshot, Some(number))
+            .map_err(|e| ServerFnError::new(e.to_string()))
+    }
+
+    #[cfg(not(feature = "ssr"))]
+    {
+        let _ = (session_id, number);
+        unreachable!("Server function should not be called directly on client side")
+    }
+}
+
+/// セッションのメッセージから分岐し、その時点までの会話とUI状態を持つセッションを作る
+#[server]
+pub async fn fork_session(
+    session_id: String,
+    message_id: usize,
+    name: String,
+) -> Result<SessionSummary, ServerFnError> {
+    #[cfg(feature = "ssr")]
+    {
+        use crate::storage::{new_session_id, StoreError};
+        let (store, owner) = session_owner()?;
+        let session_id = checked_session_id(&session_id)?;
+        let name = checked_session_name(&name)?;
+        let snapshot = store
+            .load(&owner, session_id)
+            .map_err(|e| ServerFnError::new(e.to_string()))?
+            .ok_or_else(|| ServerFnError::new(StoreError::NotFound.to_string()))?;
+        let fork = crate::branches::fork_snapshot(&snapshot, message_id)
+            .ok_or_else(|| ServerFnError::new("分岐するメッセージが見つかりません"))?;
+        store
+            .create(
+                &owner,
+                &new_session_id(),
+                &name,
+                Some((session_id, message_id)),
+                &fork,
+            )
+            .map_err(|e| ServerFnError::new(e.to_string()))
+    }
+
+    #[cfg(not(feature = "ssr"))]
+    {
+        let _ = (session_id, message_id, name);
+        unreachable!("Server function should not be called directly on client side")
+    }
+}
+
+/// テーマの一覧（名前順）。保存先がなければ None
+#[server]
+pub async fn list_themes() -> Result<Option<Vec<Theme>>, ServerFnError> {
+    #[cfg(feature = "ssr")]
+    {
+        let Some((store, owner)) = crate::storage::current_owner(true) else {
+            return Ok(None);
+        };
+        store
+            .list_themes(&owner)
+            .map(Some)
+            .map_err(|e| ServerFnError::new(e.to_string()))
+    }
+
+    #[cfg(not(feature = "ssr"))]
+    {
+        unreachable!("Server function should not be called directly on client side")
+    }
+}
+
+/// テーマを保存する（同じ名前のテーマは置き換える）
+#[server(input = Json)]
+pub async fn save_theme(theme: Theme) -> Result<Theme, ServerFnError> {
+    #[cfg(feature = "ssr")]
+    {
+        let (store, owner) = session_owner()?;
+        let mut theme = theme;
+        theme.name = checked_session_name(&theme.name)?;
+        crate::themes::sanitize_theme(&mut theme);
+        store
+            .save_theme(&owner, &theme)
+            .map_err(|e| ServerFnError::new(e.to_string()))
+    }
+
+    #[cfg(not(feature = "ssr"))]
+    {
+        let _ = theme;
+        unreachable!("Server function should not be called directly on client side")
+    }
+}
+
+#[server]
+pub async fn delete_theme(name: String) -> Result<(), ServerFnError> {
+    #[cfg(feature = "ssr")]
+    {
+        let (store, owner) = session_owner()?;
+        store
+            .delete_theme(&owner, &name)
+            .map_err(|e| ServerFnError::new(e.to_string()))
+    }
+
+    #[cfg(not(feature = "ssr"))]
+    {
+        let _ = name;
+        unreachable!("Server function should not be called directly on client side")
+    }
+}
+
+/// 現在の画面を共有用に固定し、読み取り専用のURLのパス（`/s/{id}`）を返す。
+/// 有効期限（日）を省略した場合は SHARE_EXPIRY_DAYS（未設定なら無期限）を使う
+#[server(input = Json)]
+pub async fn share_snapshot(
+    snapshot: SessionSnapshot,
+    expires_in_days: Option<u32>,
+) -> Result<String, ShareError> {
+    #[cfg(feature = "ssr")]
+    {
+        use crate::request_validation::{validate_history, RequestLimits};
+        use crate::storage::{new_share_id, unix_now};
+        // セッションの保存と同じく所有者のものとして保存し、所有者ごとの件数を制限する
+        let (store, owner) = session_owner().map_err(|_| ShareError::Unavailable)?;
+
+        // 共有する内容もクライアントから送られたものなので、読み込みと同じく検証する
+        let mut snapshot = snapshot;
+        validate_history(
+            &snapshot.messages,
+            &snapshot.ui_state,
+            &RequestLimits::from_env(),
+        )
+        .map_err(ShareError::Rejected)?;
+        crate::ui_document::sanitize(&mut snapshot);
+        // 共有ページは最後の状態だけを表示するので、分岐用の履歴は含めない
+        snapshot.ui_history.clear();
+
+        let days = expires_in_days
+            .or_else(|| {
+                std::env::var("SHARE_EXPIRY_DAYS")
+                    .ok()
+                    .and_then(|v| v.trim().parse().ok())
+            })
+            .filter(|&days| days > 0)
+            .map(|days: u32| days.min(MAX_SHARE_EXPIRY_DAYS));
+        let now = unix_now();
+        let expires_at = days.map(|days| now + i64::from(days) * 86_400);
+        let share_id = new_share_id();
+        store
+            .save_share(&owner, &share_id, &snapshot, expires_at, now)
+            .map_err(|e| ShareError::Storage(e.to_string()))?;
+        log::info!(
+            "共有リンクを作成しました: id={} expires_at={:?}",
+            share_id,
+            expires_at
+        );
+        Ok(format!("/s/{}", share_id))
+    }
+
+    #[cfg(not(feature = "ssr"))]
+    {
+        let _ = (snapshot, expires_in_days);
+        unreachable!("Server function should not be called directly on client side")
+    }
+}
+
+/// 共有されたスナップショットを読み込む（存在しない、または期限切れなら None）
+#[server]
+pub async fn load_shared_snapshot(
+    share_id: String,
+) -> Result<Option<SessionSnapshot>, ServerFnError> {
+    #[cfg(feature = "ssr")]
+    {
+        use crate::storage::{is_valid_id, unix_now, SharedSessionStore};
+        let Some(store) = leptos::prelude::use_context::<SharedSessionStore>() else {
+            return Ok(None);
+        };
+        if !is_valid_id(&share_id) {
+            return Ok(None);
+        }
+        store
+            .load_share(&share_id, unix_now())
+            .map_err(|e| ServerFnError::new(e.to_string()))
+    }
+
+    #[cfg(not(feature = "ssr"))]
+    {
+        let _ = share_id;
         unreachable!("Server function should not be called directly on client side")
     }
 }
@@ -2,7 +2,8 @@ use leptos::prelude::WriteSignal;
 use leptos::prelude::*;
 use leptos::task::spawn_local;
 
-use crate::api::send_message;
+use crate::api::{send_message, SendMessageError};
+use crate::i18n::error_message;
 use crate::pages::chat_page::Message;
 use common::*;
 use std::collections::HashMap;
@@ -13,11 +14,22 @@ pub struct ApiCallParams {
     pub user_message: String,
     pub anchor_message_id: usize,
     pub current_messages: Vec<Message>,
+    pub current_ui_state: UiState,
+    pub generation: GenerationOverrides,
+    pub locale: Locale,
+    pub session_id: Option<String>, // 返信後の会話を保存するセッション
     pub set_is_loading: WriteSignal<bool>,
     pub set_messages: WriteSignal<Vec<Message>>,
     pub set_chat_container_styles: WriteSignal<String>,
     pub set_dynamic_elements: WriteSignal<HashMap<usize, Vec<DynamicElementData>>>,
     pub set_element_styles: WriteSignal<HashMap<usize, String>>,
+    pub set_session_usage: WriteSignal<UsageTotals>,
+    pub on_reply: Callback<()>, // 返信を画面に反映した後に呼ぶ
+}
+
+// 最後のメッセージの次のID
+fn next_message_id(messages: &[Message]) -> usize {
+    messages.last().map(|m| m.id + 1).unwrap_or(0)
 }
 
 /// メッセージをサーバーに送信し、応答を処理します。
@@ -31,95 +43,65 @@ pub fn send_message_to_api(params: ApiCallParams) {
         let message_context: Vec<common::MessageInfo> = params
             .current_messages
             .iter()
-            .map(|msg| common::MessageInfo {
-                id: msg.id,
-                is_user: msg.is_user,
-                text: msg.text.clone(),
-            })
+            .map(common::MessageInfo::from)
             .collect();
 
         let req = SendMessageRequest {
             text: params.user_message.clone(),
             messages: message_context,
+            ui_state: params.current_ui_state.clone(),
+            generation: params.generation.clone(),
+            locale: params.locale,
+            session_id: params.session_id.clone(),
         };
         let api_response = send_message(req).await;
 
         match api_response {
             Ok(res) => {
-                // 新しい要素を指定されたメッセージIDの後に挿入
-                if let Some(elements) = res.new_elements {
-                    params.set_dynamic_elements.update(|map| {
-                        map.insert(params.anchor_message_id, elements);
-                    });
-                }
+                // 次回以降のリクエストでモデルに自分の応答として渡すため、適用する操作を保持する
+                let ops = UiOperations {
+                    success: res.success,
+                    chat_container_styles: res.chat_container_styles.clone(),
+                    change_style_elements: res.change_style_elements.clone(),
+                    new_elements: res.new_elements.clone(),
+                };
 
-                // スタイル更新はCSSプロパティ形式で処理
-                let element_updates_clone = res.change_style_elements.clone();
-                if let Some(element_updates) = element_updates_clone {
-                    params.set_element_styles.update(|styles| {
-                        for update in element_updates {
-                            styles
-                                .entry(update.id)
-                                .and_modify(|existing| {
-                                    if !existing.trim().is_empty() {
-                                        // 既存のスタイルの最後にセミコロンがない場合は追加
-                                        if !existing.trim().ends_with(';') {
-                                            existing.push(';');
-                                        }
-                                        existing.push(' ');
-                                    }
-                                    existing.push_str(&update.styles);
-                                    // 新しいスタイルの最後にセミコロンがない場合は追加
-                                    if !update.styles.trim().ends_with(';') {
-                                        existing.push(';');
-                                    }
-                                })
-                                .or_insert_with(|| {
-                                    let mut styles = update.styles.clone();
-                                    // 新しいスタイルの最後にセミコロンがない場合は追加
-                                    if !styles.trim().ends_with(';') {
-                                        styles.push(';');
-                                    }
-                                    styles
-                                });
-                        }
-                    })
+                // トークン数と料金をセッションの合計に加える
+                if let Some(usage) = &res.usage {
+                    params.set_session_usage.update(|totals| totals.add(usage));
                 }
 
-                // APIの応答を処理ハンドラーに渡す
-                if let Some(chat_container_styles) = res.chat_container_styles {
-                    params.set_chat_container_styles.set(chat_container_styles);
-                }
-
-                let api_reply = res.message;
+                // UI操作を適用した状態を各シグナルに反映する
+                let reply_id = next_message_id(&params.current_messages);
+                let mut state = params.current_ui_state.clone();
+                state.apply(params.anchor_message_id, reply_id, &ops);
+                params.set_dynamic_elements.set(state.dynamic_elements);
+                params.set_element_styles.set(state.element_styles);
+                params
+                    .set_chat_container_styles
+                    .set(state.chat_container_styles);
 
                 params.set_messages.update(|msgs| {
-                    let new_id = msgs.last().map(|m| m.id + 1).unwrap_or(0);
                     msgs.push(Message {
-                        id: new_id,
-                        text: api_reply.clone(),
+                        id: reply_id,
+                        text: res.message,
                         is_user: false,
+                        ops: Some(ops),
+                    });
+                });
+                params.on_reply.run(());
+            }
+            Err(e @ (SendMessageError::InvalidRequest(_) | SendMessageError::ModelResponse(_))) => {
+                // 入力やモデルの応答の問題はユーザーが言い直せるよう、理由をチャットに表示する
+                log::warn!("API request rejected: {:?}", e);
+                params.set_messages.update(|msgs| {
+                    msgs.push(Message {
+                        id: next_message_id(msgs),
+                        text: error_message(params.locale, &e),
+                        is_user: false,
+                        ops: None,
                     });
                 });
-
-                // 一般的なスタイル変更要求の場合、新しいメッセージ（AIの返信）にもスタイルを適用
-                if let Some(element_updates) = &res.change_style_elements {
-                    // 全ての要素に同じスタイルが適用されているかチェック（一般的なスタイル変更要求）
-                    let all_elements_same_style = element_updates.len() > 1
-                        && element_updates
-                            .iter()
-                            .all(|update| update.styles == element_updates[0].styles);
-
-                    if all_elements_same_style {
-                        // 新しいメッセージ（AIの返信）にも同じスタイルを適用
-                        let new_message_id = params.current_messages.len(); // ユーザーメッセージ追加後のID
-                        let style_to_apply = element_updates[0].styles.clone();
-
-                        params.set_element_styles.update(|styles| {
-                            styles.insert(new_message_id, style_to_apply);
-                        });
-                    }
-                }
             }
             Err(e) => {
                 log::error!("API rewuest failed: {:?}", e);
@@ -0,0 +1,14 @@
+/// ユーザーのメッセージ吹き出しに常に適用されるベーススタイル
+pub const USER_BUBBLE_STYLES: &str = "padding: 12px 16px; border-radius: 18px; border-bottom-right-radius: 4px; box-shadow: 0 2px 8px rgba(0, 0, 0, 0.1); max-width: 85%; text-align: left; background-color: #dbeafe; color: #1f2937;";
+
+/// AIのメッセージ吹き出しに常に適用されるベーススタイル
+pub const AI_BUBBLE_STYLES: &str = "padding: 12px 16px; border-radius: 18px; border-bottom-left-radius: 4px; box-shadow: 0 2px 8px rgba(0, 0, 0, 0.1); max-width: 85%; text-align: left; background-color: #e5e7eb; color: #1f2937;";
+
+/// 発言者に応じた吹き出しのベーススタイルを返す
+pub fn bubble_styles(is_user: bool) -> &'static str {
+    if is_user {
+        USER_BUBBLE_STYLES
+    } else {
+        AI_BUBBLE_STYLES
+    }
+}
@@ -0,0 +1,285 @@
+//! 会話の分岐
+//!
+//! 任意のメッセージから、その時点までの会話とUI状態を持つ新しいセッションを作る。
+//! その時点のUI状態は、メッセージごとに保存したUI状態の変更（`ui_history`）を先頭から
+//! 重ねて求める。分岐したセッションは分岐元を覚え、セッション一覧に木として表示する。
+
+use crate::api::fork_session;
+use crate::local_store;
+use crate::sessions::Backend;
+use common::{SessionSnapshot, SessionSummary, UiState};
+use leptos::server_fn::ServerFnError;
+use std::collections::{BTreeMap, HashSet};
+
+/// メッセージから分岐したセッションを作る（表示しているセッションは変えない）
+pub async fn fork(
+    backend: Backend,
+    session_id: String,
+    message_id: usize,
+    name: String,
+) -> Result<SessionSummary, ServerFnError> {
+    match backend {
+        Backend::Server => fork_session(session_id, message_id, name).await,
+        Backend::Local => local_store::load(&session_id)
+            .and_then(|snapshot| fork_snapshot(&snapshot, message_id))
+            .map(|snapshot| local_store::create_fork(&name, &session_id, message_id, &snapshot))
+            .ok_or_else(|| ServerFnError::new("分岐するメッセージが見つかりません")),
+    }
+}
+
+/// メッセージごとのUI状態の変更を、現在の会話とUI状態に合わせる。
+/// 最後のメッセージの変更は、その直前の時点から現在のUI状態への変更にする
+pub fn record_history(snapshot: &mut SessionSnapshot) {
+    let ids: HashSet<usize> = snapshot.messages.iter().map(|m| m.id).collect();
+    snapshot.ui_history.retain(|id, _| ids.contains(id));
+    let Some(last) = snapshot.messages.last().map(|m| m.id) else {
+        snapshot.ui_history.clear();
+        return;
+    };
+    if snapshot.ui_history.is_empty() {
+        // 履歴を保存する以前の会話は、返信のUI操作を順に適用し直して求める
+        replay_ops(snapshot);
+    }
+    let base = fold(&snapshot.ui_history, |id| id < last);
+    let delta = snapshot.ui_state.delta_from(&base);
+    if delta.is_empty() {
+        snapshot.ui_history.remove(&last);
+    } else {
+        snapshot.ui_history.insert(last, delta);
+    }
+}
+
+/// メッセージが最後だった時点のUI状態
+pub fn state_at(snapshot: &SessionSnapshot, message_id: usize) -> UiState {
+    fold(&snapshot.ui_history, |id| id <= message_id)
+}
+
+/// メッセージまでの会話と、その時点のUI状態（メッセージが見つからなければ None）
+pub fn fork_snapshot(snapshot: &SessionSnapshot, message_id: usize) -> Option<SessionSnapshot> {
+    if !snapshot.messages.iter().any(|m| m.id == message_id) {
+        return None;
+    }
+    Some(SessionSnapshot {
+        messages: snapshot
+            .messages
+            .iter()
+            .filter(|m| m.id <= message_id)
+            .cloned()
+            .collect(),
+        ui_state: state_at(snapshot, message_id),
+        ui_history: snapshot
+            .ui_history
+            .range(..=message_id)
+            .map(|(id, delta)| (*id, delta.clone()))
+            .collect(),
+    })
+}
+
+/// セッション一覧を木の順に並べ、各セッションの深さを添える。
+/// 分岐元の下に分岐したセッションを並べ、それ以外は一覧の順を保つ（分岐元が削除されたものは最上位に置く）
+pub fn tree(sessions: &[SessionSummary]) -> Vec<(SessionSummary, usize)> {
+    let ids: HashSet<&str> = sessions.iter().map(|s| s.id.as_str()).collect();
+    let parent = |session: &SessionSummary| -> Option<String> {
+        session
+            .parent_id
+            .clone()
+            .filter(|id| ids.contains(id.as_str()) && *id != session.id)
+    };
+    let mut ordered = Vec::with_capacity(sessions.len());
+    let mut visited = HashSet::new();
+    let mut stack: Vec<(&SessionSummary, usize)> = sessions
+        .iter()
+        .filter(|s| parent(s).is_none())
+        .rev()
+        .map(|s| (s, 0))
+        .collect();
+    while let Some((session, depth)) = stack.pop() {
+        if !visited.insert(session.id.as_str()) {
+            continue;
+        }
+        ordered.push((session.clone(), depth));
+        stack.extend(
+            sessions
+                .iter()
+                .filter(|s| parent(s).as_deref() == Some(session.id.as_str()))
+                .rev()
+                .map(|s| (s, depth + 1)),
+        );
+    }
+    // 分岐元をたどると循環しているもの（通常は起きない）も落とさない
+    ordered.extend(
+        sessions
+            .iter()
+            .filter(|s| !visited.contains(s.id.as_str()))
+            .map(|s| (s.clone(), 0)),
+    );
+    ordered
+}
+
+// 条件に合うメッセージのUI状態の変更を、空の状態から順に重ねる
+fn fold(history: &BTreeMap<usize, common::UiDelta>, include: impl Fn(usize) -> bool) -> UiState {
+    let mut state = UiState::default();
+    for (_, delta) in history.iter().filter(|(id, _)| include(**id)) {
+        state.apply_delta(delta);
+    }
+    state
+}
+
+// 返信ごとのUI操作を、直前のメッセージを挿入位置として適用し直した履歴
+fn replay_ops(snapshot: &mut SessionSnapshot) {
+    let mut state = UiState::default();
+    for pair in snapshot.messages.windows(2) {
+        let (anchor, reply) = (&pair[0], &pair[1]);
+        if let Some(ops) = &reply.ops {
+            let before = state.clone();
+            state.apply(anchor.id, reply.id, ops);
+            let delta = state.delta_from(&before);
+            if !delta.is_empty() {
+                snapshot.ui_history.insert(reply.id, delta);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{MessageInfo, StyleUpdate, UiOperations};
+
+    fn message(id: usize, is_user: bool, ops: Option<UiOperations>) -> MessageInfo {
+        MessageInfo {
+            id,
+            is_user,
+            text: format!("メッセージ{}", id),
+            ops,
+        }
+    }
+
+    fn restyle(id: usize, styles: &str) -> UiOperations {
+        UiOperations {
+            success: true,
+            chat_container_styles: None,
+            change_style_elements: Some(vec![StyleUpdate {
+                id,
+                styles: styles.to_string(),
+            }]),
+            new_elements: None,
+        }
+    }
+
+    #[test]
+    fn test_history_and_fork() {
+        // 返信ごとに記録していく
+        let mut snapshot = SessionSnapshot {
+            messages: vec![message(0, false, None), message(1, true, None)],
+            ..Default::default()
+        };
+        record_history(&mut snapshot);
+        assert!(snapshot.ui_history.is_empty());
+
+        snapshot
+            .messages
+            .push(message(2, false, Some(restyle(1, "color: red"))));
+        snapshot
+            .ui_state
+            .element_styles
+            .insert(1, "color: red;".to_string());
+        record_history(&mut snapshot);
+
+        // テーマのようにクライアントで変えたものも、その時点の最後のメッセージに記録される
+        snapshot.messages.push(message(3, false, None));
+        snapshot.ui_state.chat_container_styles = "background: #000".to_string();
+        snapshot.ui_state.element_styles.clear();
+        record_history(&mut snapshot);
+        assert_eq!(
+            snapshot.ui_history.keys().copied().collect::<Vec<_>>(),
+            [2, 3]
+        );
+
+        assert_eq!(state_at(&snapshot, 1), UiState::default());
+        assert_eq!(
+            state_at(&snapshot, 2)
+                .element_styles
+                .get(&1)
+                .map(String::as_str),
+            Some("color: red;")
+        );
+        assert_eq!(state_at(&snapshot, 3), snapshot.ui_state);
+
+        let fork = fork_snapshot(&snapshot, 2).unwrap();
+        assert_eq!(fork.messages.len(), 3);
+        assert_eq!(fork.ui_state, state_at(&snapshot, 2));
+        assert_eq!(fork.ui_history.keys().copied().collect::<Vec<_>>(), [2]);
+        assert!(fork_snapshot(&snapshot, 9).is_none());
+
+        // 会話を短くすると、なくなったメッセージの記録も消える
+        let mut reset = snapshot.clone();
+        reset.messages.truncate(1);
+        reset.ui_state = UiState::default();
+        record_history(&mut reset);
+        assert!(reset.ui_history.is_empty());
+    }
+
+    #[test]
+    fn test_history_is_rebuilt_from_ops() {
+        let mut snapshot = SessionSnapshot {
+            messages: vec![
+                message(0, false, None),
+                message(1, true, None),
+                message(2, false, Some(restyle(1, "color: red"))),
+                message(3, true, None),
+                message(4, false, Some(restyle(3, "color: blue"))),
+            ],
+            ..Default::default()
+        };
+        snapshot
+            .ui_state
+            .element_styles
+            .insert(1, "color: red;".to_string());
+        snapshot
+            .ui_state
+            .element_styles
+            .insert(3, "color: blue;".to_string());
+        record_history(&mut snapshot);
+        assert_eq!(state_at(&snapshot, 4), snapshot.ui_state);
+        assert_eq!(state_at(&snapshot, 3).element_styles.len(), 1);
+    }
+
+    #[test]
+    fn test_tree_orders_branches_under_parents() {
+        let session = |id: &str, parent: Option<&str>| SessionSummary {
+            id: id.to_string(),
+            name: id.to_string(),
+            updated_at: 0,
+            parent_id: parent.map(str::to_string),
+            forked_at: parent.map(|_| 2),
+        };
+        let sessions = [
+            session("b1", Some("b")),
+            session("a", None),
+            session("a1", Some("a")),
+            session("b", None),
+            session("orphan", Some("deleted")),
+            session("a1x", Some("a1")),
+        ];
+        let order: Vec<(String, usize)> = tree(&sessions)
+            .into_iter()
+            .map(|(s, depth)| (s.id, depth))
+            .collect();
+        let expected = [
+            ("a", 0),
+            ("a1", 1),
+            ("a1x", 2),
+            ("b", 0),
+            ("b1", 1),
+            ("orphan", 0),
+        ];
+        assert_eq!(
+            order,
+            expected
+                .iter()
+                .map(|(id, depth)| (id.to_string(), *depth))
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,212 @@
+//! モデルに渡す会話の長さをトークン数の予算内に収める
+//!
+//! 直近のメッセージはそのまま渡し、それより古いやり取りは
+//! 「背景は紺、ボタンは角丸」のような決定事項の要約に畳み込む。
+
+use crate::prompt::{compact_declarations, quote_untrusted};
+use common::*;
+use serde_json::json;
+use std::collections::BTreeMap;
+
+/// 会話の要約で1件の要求を残す最大文字数
+const SUMMARY_REQUEST_CHARS: usize = 80;
+
+/// プロバイダーごとの既定のトークン予算
+fn default_token_budget(provider: &str) -> usize {
+    match provider {
+        "gemini" => 16_000,
+        _ => 8_000,
+    }
+}
+
+/// 1回のリクエストでモデルに渡すトークン数の予算
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ContextBudget {
+    pub max_tokens: usize,           // システム指示を含めた入力全体の上限（推定値）
+    pub keep_recent_messages: usize, // 要約せずにそのまま渡す直近のメッセージ数
+}
+
+impl ContextBudget {
+    /// 環境変数 CONTEXT_TOKEN_BUDGET_<PROVIDER> / CONTEXT_TOKEN_BUDGET / CONTEXT_KEEP_MESSAGES から読み込む
+    pub fn for_provider(provider: &str) -> Self {
+        let var = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+        };
+        let max_tokens = var(&format!(
+            "CONTEXT_TOKEN_BUDGET_{}",
+            provider.to_ascii_uppercase()
+        ))
+        .or_else(|| var("CONTEXT_TOKEN_BUDGET"))
+        .unwrap_or_else(|| default_token_budget(provider));
+        Self {
+            max_tokens,
+            keep_recent_messages: var("CONTEXT_KEEP_MESSAGES").unwrap_or(6),
+        }
+    }
+}
+
+/// トークン数の推定値（ASCIIは約4文字で1トークン、日本語などはおおむね1文字1トークン）
+pub fn estimate_tokens(text: &str) -> usize {
+    let (ascii, other) = text.chars().fold((0usize, 0usize), |(ascii, other), c| {
+        if c.is_ascii() {
+            (ascii + 1, other)
+        } else {
+            (ascii, other + 1)
+        }
+    });
+    ascii.div_ceil(4) + other
+}
+
+/// 要約せずに渡す直近のメッセージの開始位置。
+/// ユーザーの発言から始まるよう、直前のAIの返信は要約側に含める
+pub fn recent_start(messages: &[MessageInfo], keep: usize) -> usize {
+    let mut start = messages.len().saturating_sub(keep);
+    while start < messages.len() && !messages[start].is_user {
+        start += 1;
+    }
+    start
+}
+
+/// 古いやり取りを決定事項の要約にまとめる。
+/// 予算に合わせて削れるよう、1行目に決定事項、以降に古い順の要求を1行ずつ並べる
+pub fn summarize_messages(messages: &[MessageInfo]) -> Vec<String> {
+    let mut container: Vec<String> = Vec::new();
+    let mut styles: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    let mut elements: Vec<String> = Vec::new();
+    let mut requests: Vec<String> = Vec::new();
+
+    for message in messages {
+        if message.is_user {
+            let text: String = message.text.chars().take(SUMMARY_REQUEST_CHARS).collect();
+            requests.push(quote_untrusted(&json!({ "request": text })));
+            continue;
+        }
+        let Some(ops) = message.ops.as_ref().filter(|ops| ops.success) else {
+            continue;
+        };
+        if let Some(css) = ops
+            .chat_container_styles
+            .as_deref()
+            .filter(|s| !s.trim().is_empty())
+        {
+            container.push(css.to_string());
+        }
+        for update in ops.change_style_elements.iter().flatten() {
+            styles
+                .entry(update.id)
+                .or_default()
+                .push(update.styles.clone());
+        }
+        for element in ops.new_elements.iter().flatten() {
+            elements.push(match element.text.as_deref() {
+                Some(text) if !text.is_empty() => format!("{}「{}」", element.tag, text),
+                _ => element.tag.clone(),
+            });
+        }
+    }
+
+    // 同じプロパティへの変更は後勝ちでまとめ、最終的な決定だけを残す
+    let decisions = json!({
+        "chat_container": compact_declarations(&container.join(";")),
+        "messages": styles
+            .iter()
+            .map(|(id, css)| (id.to_string(), compact_declarations(&css.join(";"))))
+            .collect::<BTreeMap<_, _>>(),
+        "added_elements": elements,
+    });
+    let mut lines = vec![quote_untrusted(&json!({ "decisions": decisions }))];
+    lines.extend(requests);
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: usize, is_user: bool, text: &str, ops: Option<UiOperations>) -> MessageInfo {
+        MessageInfo {
+            id,
+            is_user,
+            text: text.to_string(),
+            ops,
+        }
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("background"), 3);
+        assert_eq!(estimate_tokens("背景を青く"), 5);
+        assert_eq!(estimate_tokens("color: 赤"), 3);
+    }
+
+    #[test]
+    fn test_recent_start_begins_with_user_message() {
+        let messages: Vec<_> = (0..7)
+            .map(|id| message(id, id % 2 == 1, "x", None))
+            .collect();
+        // 直近4件は [3:user, 4:ai, 5:user, 6:ai]
+        assert_eq!(recent_start(&messages, 4), 3);
+        // 直近3件は AI から始まるため、1件進める
+        assert_eq!(recent_start(&messages, 3), 5);
+        assert_eq!(recent_start(&messages, 0), 7);
+    }
+
+    #[test]
+    fn test_summary_keeps_final_decisions() {
+        let ops = |container: &str, updates: Vec<StyleUpdate>, elements| UiOperations {
+            success: true,
+            chat_container_styles: Some(container.to_string()),
+            change_style_elements: Some(updates),
+            new_elements: Some(elements),
+        };
+        let messages = vec![
+            message(1, true, "背景を青くして", None),
+            message(
+                2,
+                false,
+                "背景を青にしました",
+                Some(ops("background-color: blue;", vec![], vec![])),
+            ),
+            message(3, true, "背景を紺にしてボタンを角丸に", None),
+            message(
+                4,
+                false,
+                "変更しました",
+                Some(ops(
+                    "background-color: navy;",
+                    vec![StyleUpdate {
+                        id: 1,
+                        styles: "border-radius: 12px;".to_string(),
+                    }],
+                    vec![DynamicElementData {
+                        id: 0,
+                        tag: "button".to_string(),
+                        text: Some("送信".to_string()),
+                        styles: None,
+                        attributes: None,
+                    }],
+                )),
+            ),
+        ];
+
+        let lines = summarize_messages(&messages);
+        assert_eq!(lines.len(), 3);
+        let decisions: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(
+            decisions["decisions"]["chat_container"],
+            "background-color: navy"
+        );
+        assert_eq!(
+            decisions["decisions"]["messages"]["1"],
+            "border-radius: 12px"
+        );
+        assert_eq!(
+            decisions["decisions"]["added_elements"][0],
+            "button「送信」"
+        );
+        assert!(lines[2].contains("背景を紺にしてボタンを角丸に"));
+    }
+}
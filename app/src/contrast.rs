@@ -0,0 +1,865 @@
+use crate::base_styles::bubble_styles;
+use crate::css_sanitizer::declarations;
+use crate::request_validation::next_message_id;
+use common::*;
+
+/// チャットコンテナに背景色が指定されていない場合の見た目上の背景色（bodyの白）
+const DEFAULT_CONTAINER_BACKGROUND: Color = Color::rgb(255, 255, 255);
+
+/// チャットコンテナに文字色が指定されていない場合の文字色
+const DEFAULT_CONTAINER_COLOR: Color = Color::rgb(0, 0, 0);
+
+/// WCAGのコントラスト基準
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContrastPolicy {
+    Off,
+    Aa,
+    Aaa,
+}
+
+impl ContrastPolicy {
+    /// 通常サイズのテキストに必要なコントラスト比
+    pub fn min_ratio(&self) -> Option<f64> {
+        match self {
+            ContrastPolicy::Off => None,
+            ContrastPolicy::Aa => Some(4.5),
+            ContrastPolicy::Aaa => Some(7.0),
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            ContrastPolicy::Off => "OFF",
+            ContrastPolicy::Aa => "AA",
+            ContrastPolicy::Aaa => "AAA",
+        }
+    }
+}
+
+/// 基準を下回った場合の扱い
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContrastMode {
+    Adjust, // 文字色を自動補正する
+    Warn,   // レスポンスで警告するだけ
+}
+
+/// RGBAの色（各チャンネル0-255、アルファは0.0-1.0）
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Color {
+    pub r: f64,
+    pub g: f64,
+    pub b: f64,
+    pub a: f64,
+}
+
+impl Color {
+    const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self {
+            r: r as f64,
+            g: g as f64,
+            b: b as f64,
+            a: 1.0,
+        }
+    }
+
+    /// CSSの色指定を解釈する（hex, rgb(), rgba(), hsl(), hsla(), 一般的な色名）
+    pub fn parse(value: &str) -> Option<Self> {
+        let v = value
+            .trim()
+            .trim_end_matches("!important")
+            .trim()
+            .to_ascii_lowercase();
+        if let Some(hex) = v.strip_prefix('#') {
+            return Self::parse_hex(hex);
+        }
+        if let Some(args) = function_args(&v, &["rgba", "rgb"]) {
+            let parts = split_color_args(args);
+            if parts.len() < 3 {
+                return None;
+            }
+            let channel = |s: &str| -> Option<f64> {
+                match s.strip_suffix('%') {
+                    Some(p) => p.trim().parse::<f64>().ok().map(|p| p * 2.55),
+                    None => s.parse::<f64>().ok(),
+                }
+            };
+            return Some(Self {
+                r: channel(parts[0])?.clamp(0.0, 255.0),
+                g: channel(parts[1])?.clamp(0.0, 255.0),
+                b: channel(parts[2])?.clamp(0.0, 255.0),
+                a: parse_alpha(parts.get(3).copied())?,
+            });
+        }
+        if let Some(args) = function_args(&v, &["hsla", "hsl"]) {
+            let parts = split_color_args(args);
+            if parts.len() < 3 {
+                return None;
+            }
+            let h = parts[0].trim_end_matches("deg").parse::<f64>().ok()?;
+            let s = parts[1].trim_end_matches('%').parse::<f64>().ok()? / 100.0;
+            let l = parts[2].trim_end_matches('%').parse::<f64>().ok()? / 100.0;
+            let (r, g, b) = hsl_to_rgb(h, s.clamp(0.0, 1.0), l.clamp(0.0, 1.0));
+            return Some(Self {
+                r,
+                g,
+                b,
+                a: parse_alpha(parts.get(3).copied())?,
+            });
+        }
+        named_color(&v)
+    }
+
+    fn parse_hex(hex: &str) -> Option<Self> {
+        let digit = |i: usize, len: usize| -> Option<f64> {
+            let s = hex.get(i * len..(i + 1) * len)?;
+            let n = u8::from_str_radix(s, 16).ok()?;
+            Some(if len == 1 { (n * 17) as f64 } else { n as f64 })
+        };
+        let (len, has_alpha) = match hex.len() {
+            3 => (1, false),
+            4 => (1, true),
+            6 => (2, false),
+            8 => (2, true),
+            _ => return None,
+        };
+        Some(Self {
+            r: digit(0, len)?,
+            g: digit(1, len)?,
+            b: digit(2, len)?,
+            a: if has_alpha {
+                digit(3, len)? / 255.0
+            } else {
+                1.0
+            },
+        })
+    }
+
+    /// 半透明の色を背景色の上に合成した不透明色を返す
+    pub fn over(&self, backdrop: &Color) -> Color {
+        let a = self.a.clamp(0.0, 1.0);
+        Color {
+            r: self.r * a + backdrop.r * (1.0 - a),
+            g: self.g * a + backdrop.g * (1.0 - a),
+            b: self.b * a + backdrop.b * (1.0 - a),
+            a: 1.0,
+        }
+    }
+
+    /// WCAG 2.x の相対輝度
+    pub fn relative_luminance(&self) -> f64 {
+        let linear = |c: f64| {
+            let c = c / 255.0;
+            if c <= 0.03928 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        };
+        0.2126 * linear(self.r) + 0.7152 * linear(self.g) + 0.0722 * linear(self.b)
+    }
+
+    /// 2色間のコントラスト比（1.0〜21.0）
+    pub fn contrast_ratio(&self, other: &Color) -> f64 {
+        let l1 = self.relative_luminance();
+        let l2 = other.relative_luminance();
+        let (hi, lo) = if l1 > l2 { (l1, l2) } else { (l2, l1) };
+        (hi + 0.05) / (lo + 0.05)
+    }
+
+    fn mix(&self, other: &Color, t: f64) -> Color {
+        Color {
+            r: self.r + (other.r - self.r) * t,
+            g: self.g + (other.g - self.g) * t,
+            b: self.b + (other.b - self.b) * t,
+            a: 1.0,
+        }
+    }
+
+    pub fn to_hex(self) -> String {
+        format!(
+            "#{:02x}{:02x}{:02x}",
+            self.r.round().clamp(0.0, 255.0) as u8,
+            self.g.round().clamp(0.0, 255.0) as u8,
+            self.b.round().clamp(0.0, 255.0) as u8
+        )
+    }
+}
+
+fn function_args<'a>(value: &'a str, names: &[&str]) -> Option<&'a str> {
+    names.iter().find_map(|name| {
+        value
+            .strip_prefix(name)
+            .and_then(|rest| rest.trim_start().strip_prefix('('))
+            .and_then(|rest| rest.strip_suffix(')'))
+    })
+}
+
+// "255, 0, 0" と "255 0 0 / 0.5" の両方の書式に対応する
+fn split_color_args(args: &str) -> Vec<&str> {
+    args.split([',', ' ', '/'])
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn parse_alpha(value: Option<&str>) -> Option<f64> {
+    match value {
+        None => Some(1.0),
+        Some(v) => match v.strip_suffix('%') {
+            Some(p) => p.parse::<f64>().ok().map(|p| (p / 100.0).clamp(0.0, 1.0)),
+            None => v.parse::<f64>().ok().map(|a| a.clamp(0.0, 1.0)),
+        },
+    }
+}
+
+fn hsl_to_rgb(h: f64, s: f64, l: f64) -> (f64, f64, f64) {
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let hp = h.rem_euclid(360.0) / 60.0;
+    let x = c * (1.0 - (hp % 2.0 - 1.0).abs());
+    let (r, g, b) = match hp as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = l - c / 2.0;
+    ((r + m) * 255.0, (g + m) * 255.0, (b + m) * 255.0)
+}
+
+fn named_color(name: &str) -> Option<Color> {
+    let color = match name {
+        "transparent" => Color {
+            r: 0.0,
+            g: 0.0,
+            b: 0.0,
+            a: 0.0,
+        },
+        "white" => Color::rgb(255, 255, 255),
+        "black" => Color::rgb(0, 0, 0),
+        "red" => Color::rgb(255, 0, 0),
+        "green" => Color::rgb(0, 128, 0),
+        "blue" => Color::rgb(0, 0, 255),
+        "yellow" => Color::rgb(255, 255, 0),
+        "orange" => Color::rgb(255, 165, 0),
+        "purple" => Color::rgb(128, 0, 128),
+        "pink" => Color::rgb(255, 192, 203),
+        "brown" => Color::rgb(165, 42, 42),
+        "gray" | "grey" => Color::rgb(128, 128, 128),
+        "lightgray" | "lightgrey" => Color::rgb(211, 211, 211),
+        "darkgray" | "darkgrey" => Color::rgb(169, 169, 169),
+        "silver" => Color::rgb(192, 192, 192),
+        "whitesmoke" => Color::rgb(245, 245, 245),
+        "navy" => Color::rgb(0, 0, 128),
+        "teal" => Color::rgb(0, 128, 128),
+        "maroon" => Color::rgb(128, 0, 0),
+        "olive" => Color::rgb(128, 128, 0),
+        "lime" => Color::rgb(0, 255, 0),
+        "aqua" | "cyan" => Color::rgb(0, 255, 255),
+        "fuchsia" | "magenta" => Color::rgb(255, 0, 255),
+        "gold" => Color::rgb(255, 215, 0),
+        "beige" => Color::rgb(245, 245, 220),
+        "ivory" => Color::rgb(255, 255, 240),
+        "skyblue" => Color::rgb(135, 206, 235),
+        "lightblue" => Color::rgb(173, 216, 230),
+        "darkblue" => Color::rgb(0, 0, 139),
+        "darkgreen" => Color::rgb(0, 100, 0),
+        "darkred" => Color::rgb(139, 0, 0),
+        "lightyellow" => Color::rgb(255, 255, 224),
+        "lightgreen" => Color::rgb(144, 238, 144),
+        "lightpink" => Color::rgb(255, 182, 193),
+        "coral" => Color::rgb(255, 127, 80),
+        "crimson" => Color::rgb(220, 20, 60),
+        "indigo" => Color::rgb(75, 0, 130),
+        "violet" => Color::rgb(238, 130, 238),
+        "salmon" => Color::rgb(250, 128, 114),
+        "tomato" => Color::rgb(255, 99, 71),
+        "khaki" => Color::rgb(240, 230, 140),
+        _ => return None,
+    };
+    Some(color)
+}
+
+/// 背景の指定から色を取り出す。グラデーションや画像など判定できない場合は Err
+fn background_color(prop: &str, value: &str) -> Result<Option<Color>, ()> {
+    match prop {
+        "background-color" => Ok(Color::parse(value)),
+        "background" => {
+            let lower = value.to_ascii_lowercase();
+            if lower.contains("gradient(") || lower.contains("url(") {
+                return Err(());
+            }
+            // 括弧の外側の空白で区切り、色として解釈できる最初のトークンを使う
+            let mut depth = 0;
+            let mut start = 0;
+            let mut tokens = Vec::new();
+            for (i, ch) in lower.char_indices() {
+                match ch {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    ' ' if depth == 0 => {
+                        tokens.push(&lower[start..i]);
+                        start = i + 1;
+                    }
+                    _ => {}
+                }
+            }
+            tokens.push(&lower[start..]);
+            Ok(tokens.into_iter().find_map(Color::parse))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn is_important(value: &str) -> bool {
+    value.to_ascii_lowercase().ends_with("!important")
+}
+
+/// いずれかの層が文字色を !important で指定しているか
+fn has_important_color(layers: &[&str]) -> bool {
+    layers.iter().any(|layer| {
+        declarations(layer).any(|(prop, value)| prop == "color" && is_important(value))
+    })
+}
+
+/// 実際に描画される文字色と背景色
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EffectiveColors {
+    pub foreground: Color,
+    pub background: Color,
+}
+
+/// スタイルを後勝ちで重ねた結果の文字色と背景色を求める。
+/// 同じstyle属性に連結されるため、!important の宣言は後の通常の宣言より優先する。
+/// 背景が判定不能（グラデーション等）な場合は None
+pub fn effective_colors(layers: &[&str], inherited: EffectiveColors) -> Option<EffectiveColors> {
+    let mut foreground: Option<(Color, bool)> = None;
+    let mut background: Option<(Option<Color>, bool)> = None;
+    for layer in layers {
+        for (prop, value) in declarations(layer) {
+            let important = is_important(value);
+            if prop == "color" {
+                if let Some(c) = Color::parse(value) {
+                    if important || !foreground.is_some_and(|(_, i)| i) {
+                        foreground = Some((c, important));
+                    }
+                }
+            } else if important || !background.is_some_and(|(_, i)| i) {
+                match background_color(&prop, value) {
+                    Ok(Some(c)) => background = Some((Some(c), important)),
+                    Ok(None) => {}
+                    Err(()) => background = Some((None, important)),
+                }
+            }
+        }
+    }
+    let background = match background {
+        Some((Some(c), _)) => c.over(&inherited.background),
+        Some((None, _)) => return None,
+        None => inherited.background,
+    };
+    let foreground = foreground
+        .map(|(c, _)| c.over(&background))
+        .unwrap_or(inherited.foreground);
+    Some(EffectiveColors {
+        foreground,
+        background,
+    })
+}
+
+/// 判定結果
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ContrastEvaluation {
+    pub colors: EffectiveColors,
+    pub ratio: f64,
+    pub required: f64,
+    pub suggested: Option<Color>, // Adjustモードでの補正後の文字色
+}
+
+/// 設定された基準でコントラストを判定・補正する
+#[derive(Clone, Copy, Debug)]
+pub struct ContrastChecker {
+    policy: ContrastPolicy,
+    mode: ContrastMode,
+}
+
+impl ContrastChecker {
+    pub fn new(policy: ContrastPolicy, mode: ContrastMode) -> Self {
+        Self { policy, mode }
+    }
+
+    /// 環境変数 CONTRAST_POLICY (aa/aaa/off) と CONTRAST_MODE (adjust/warn) から設定を読み込む
+    pub fn from_env() -> Self {
+        let policy = match std::env::var("CONTRAST_POLICY")
+            .unwrap_or_default()
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "off" => ContrastPolicy::Off,
+            "aaa" => ContrastPolicy::Aaa,
+            _ => ContrastPolicy::Aa,
+        };
+        let mode = match std::env::var("CONTRAST_MODE")
+            .unwrap_or_default()
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "warn" => ContrastMode::Warn,
+            _ => ContrastMode::Adjust,
+        };
+        Self::new(policy, mode)
+    }
+
+    /// 基準を満たしていれば None、満たしていなければ判定結果を返す
+    pub fn evaluate(&self, colors: EffectiveColors) -> Option<ContrastEvaluation> {
+        let required = self.policy.min_ratio()?;
+        let ratio = colors.foreground.contrast_ratio(&colors.background);
+        if ratio >= required {
+            return None;
+        }
+        let suggested = match self.mode {
+            ContrastMode::Adjust => Some(adjust_foreground(&colors, required)),
+            ContrastMode::Warn => None,
+        };
+        Some(ContrastEvaluation {
+            colors,
+            ratio,
+            required,
+            suggested,
+        })
+    }
+
+    /// AIの出力したスタイル変更を検査し、基準を下回ったものを補正または報告する。
+    /// コンテナのスタイルが変わった場合は、変更のないメッセージも新しい背景で検査し、
+    /// 補正が必要なものは style_updates に追加する
+    pub fn enforce(
+        &self,
+        messages: &[MessageInfo],
+        ui_state: &UiState,
+        chat_container_styles: Option<&str>,
+        style_updates: &mut Vec<StyleUpdate>,
+        new_elements: &mut [DynamicElementData],
+    ) -> Vec<ContrastReport> {
+        if self.policy == ContrastPolicy::Off {
+            return Vec::new();
+        }
+
+        let defaults = EffectiveColors {
+            foreground: DEFAULT_CONTAINER_COLOR,
+            background: DEFAULT_CONTAINER_BACKGROUND,
+        };
+        let container_layers = [
+            ui_state.chat_container_styles.as_str(),
+            chat_container_styles.unwrap_or(""),
+        ];
+        let container = effective_colors(&container_layers, defaults).unwrap_or(defaults);
+
+        let mut reports = Vec::new();
+
+        for update in style_updates.iter_mut() {
+            // 次のAI返信はまだ履歴にないため、履歴外のIDはAIのメッセージとして扱う
+            let is_user = match messages.iter().find(|m| m.id == update.id) {
+                Some(m) => m.is_user,
+                None if update.id == next_message_id(messages) => false,
+                None => continue,
+            };
+            reports.extend(self.check_message(
+                ui_state,
+                container,
+                update.id,
+                is_user,
+                &mut update.styles,
+            ));
+        }
+
+        if chat_container_styles.is_some() {
+            let unchanged: Vec<(usize, bool)> = messages
+                .iter()
+                .map(|m| (m.id, m.is_user))
+                .chain(std::iter::once((next_message_id(messages), false)))
+                .filter(|(id, _)| !style_updates.iter().any(|u| u.id == *id))
+                .collect();
+            for (id, is_user) in unchanged {
+                let mut styles = String::new();
+                if let Some(report) =
+                    self.check_message(ui_state, container, id, is_user, &mut styles)
+                {
+                    reports.push(report);
+                    if !styles.is_empty() {
+                        style_updates.push(StyleUpdate { id, styles });
+                    }
+                }
+            }
+        }
+
+        for (index, element) in new_elements.iter_mut().enumerate() {
+            // テキストを持たない要素は判定しない
+            if matches!(element.tag.as_str(), "img" | "hr" | "br" | "input") {
+                continue;
+            }
+            let styles = element.styles.clone().unwrap_or_default();
+            let Some(colors) = effective_colors(&[styles.as_str()], container) else {
+                continue;
+            };
+            if let Some(eval) = self.evaluate(colors) {
+                if let Some(color) = eval.suggested {
+                    element.styles = Some(replace_color(&styles, &color, false));
+                }
+                reports.push(report(ContrastTarget::NewElement(index), &eval));
+            }
+        }
+
+        reports
+    }
+
+    // メッセージの吹き出しに update_styles を重ねた結果を判定し、Adjustモードなら文字色を補正する
+    fn check_message(
+        &self,
+        ui_state: &UiState,
+        container: EffectiveColors,
+        id: usize,
+        is_user: bool,
+        update_styles: &mut String,
+    ) -> Option<ContrastReport> {
+        let accumulated = ui_state
+            .element_styles
+            .get(&id)
+            .map(String::as_str)
+            .unwrap_or("");
+        let role = if is_user {
+            &ui_state.role_styles.user_message
+        } else {
+            &ui_state.role_styles.ai_message
+        };
+        let earlier = [bubble_styles(is_user), role.as_str(), accumulated];
+        let layers = [earlier[0], earlier[1], earlier[2], update_styles.as_str()];
+        let eval = self.evaluate(effective_colors(&layers, container)?)?;
+        if let Some(color) = eval.suggested {
+            *update_styles = replace_color(update_styles, &color, has_important_color(&earlier));
+        }
+        Some(report(ContrastTarget::Message(id), &eval))
+    }
+
+    /// レスポンスのメッセージに添える注記
+    pub fn summarize(&self, reports: &[ContrastReport], locale: Locale) -> Option<String> {
+        if reports.is_empty() {
+            return None;
+        }
+        let adjusted = reports.iter().any(|r| r.adjusted_foreground.is_some());
+        let worst = reports
+            .iter()
+            .map(|r| r.ratio)
+            .fold(f64::INFINITY, f64::min);
+        Some(crate::i18n::contrast_note(
+            locale,
+            reports.len(),
+            adjusted,
+            worst,
+            self.policy.label(),
+        ))
+    }
+}
+
+fn report(target: ContrastTarget, eval: &ContrastEvaluation) -> ContrastReport {
+    ContrastReport {
+        target,
+        foreground: eval.colors.foreground.to_hex(),
+        background: eval.colors.background.to_hex(),
+        ratio: (eval.ratio * 100.0).round() / 100.0,
+        required: eval.required,
+        adjusted_foreground: eval.suggested.map(|c| c.to_hex()),
+    }
+}
+
+// 既存の color 宣言を取り除いて補正後の文字色を指定し直す。
+// 前の層が !important で文字色を指定している場合は、同じく !important を付けないと描画に反映されない
+fn replace_color(styles: &str, color: &Color, important: bool) -> String {
+    let mut parts: Vec<String> = declarations(styles)
+        .filter(|(prop, _)| prop != "color")
+        .map(|(prop, value)| format!("{}: {}", prop, value))
+        .collect();
+    let suffix = if important { " !important" } else { "" };
+    parts.push(format!("color: {}{}", color.to_hex(), suffix));
+    parts.join("; ")
+}
+
+/// 元の色味をなるべく残したまま、白または黒に寄せて基準を満たす文字色を求める
+fn adjust_foreground(colors: &EffectiveColors, required: f64) -> Color {
+    let white = Color::rgb(255, 255, 255);
+    let black = Color::rgb(0, 0, 0);
+    let target =
+        if white.contrast_ratio(&colors.background) >= black.contrast_ratio(&colors.background) {
+            white
+        } else {
+            black
+        };
+    (1..=20)
+        .map(|step| colors.foreground.mix(&target, step as f64 / 20.0))
+        .find(|c| c.contrast_ratio(&colors.background) >= required)
+        .unwrap_or(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base_styles::AI_BUBBLE_STYLES;
+
+    fn colors(fg: &str, bg: &str) -> EffectiveColors {
+        EffectiveColors {
+            foreground: Color::parse(fg).unwrap(),
+            background: Color::parse(bg).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_parse_colors() {
+        assert_eq!(Color::parse("#fff"), Some(Color::rgb(255, 255, 255)));
+        assert_eq!(Color::parse("#3b82f6"), Some(Color::rgb(59, 130, 246)));
+        assert_eq!(Color::parse("rgb(255, 0, 0)"), Some(Color::rgb(255, 0, 0)));
+        assert_eq!(
+            Color::parse("White !important"),
+            Some(Color::rgb(255, 255, 255))
+        );
+        assert_eq!(
+            Color::parse("hsl(0, 100%, 50%)").map(|c| c.to_hex()),
+            Some("#ff0000".to_string())
+        );
+        assert_eq!(Color::parse("rgba(0, 0, 0, 0.5)").map(|c| c.a), Some(0.5));
+        assert_eq!(Color::parse("inherit"), None);
+    }
+
+    #[test]
+    fn test_contrast_ratio() {
+        let white = Color::rgb(255, 255, 255);
+        let black = Color::rgb(0, 0, 0);
+        assert!((white.contrast_ratio(&black) - 21.0).abs() < 0.01);
+        assert!((white.contrast_ratio(&white) - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_effective_colors_last_declaration_wins() {
+        let inherited = colors("#000000", "#ffffff");
+        let result = effective_colors(
+            &[AI_BUBBLE_STYLES, "color: white", "background: #1e3a8a none"],
+            inherited,
+        )
+        .unwrap();
+        assert_eq!(result.foreground.to_hex(), "#ffffff");
+        assert_eq!(result.background.to_hex(), "#1e3a8a");
+
+        // グラデーション背景は判定しない
+        assert!(effective_colors(&["background: linear-gradient(red, blue)"], inherited).is_none());
+    }
+
+    #[test]
+    fn test_white_text_on_ai_bubble_is_adjusted() {
+        let checker = ContrastChecker::new(ContrastPolicy::Aa, ContrastMode::Adjust);
+        let messages = vec![MessageInfo {
+            id: 0,
+            is_user: false,
+            text: "こんにちは".to_string(),
+            ops: None,
+        }];
+        let mut updates = vec![StyleUpdate {
+            id: 0,
+            styles: "color: white".to_string(),
+        }];
+        let reports = checker.enforce(&messages, &UiState::default(), None, &mut updates, &mut []);
+
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].target, ContrastTarget::Message(0));
+        assert!(reports[0].ratio < 4.5);
+        let adjusted = reports[0].adjusted_foreground.clone().unwrap();
+        assert!(updates[0].styles.ends_with(&format!("color: {}", adjusted)));
+
+        let bg = Color::parse("#e5e7eb").unwrap();
+        assert!(Color::parse(&adjusted).unwrap().contrast_ratio(&bg) >= 4.5);
+    }
+
+    #[test]
+    fn test_accumulated_background_is_considered() {
+        let checker = ContrastChecker::new(ContrastPolicy::Aa, ContrastMode::Warn);
+        let messages = vec![MessageInfo {
+            id: 0,
+            is_user: false,
+            text: "こんにちは".to_string(),
+            ops: None,
+        }];
+        let mut ui_state = UiState::default();
+        ui_state
+            .element_styles
+            .insert(0, "background-color: #1e3a8a;".to_string());
+        let mut updates = vec![StyleUpdate {
+            id: 0,
+            styles: "color: white".to_string(),
+        }];
+        let reports = checker.enforce(&messages, &ui_state, None, &mut updates, &mut []);
+        assert!(reports.is_empty());
+    }
+
+    #[test]
+    fn test_important_color_is_replaced() {
+        let checker = ContrastChecker::new(ContrastPolicy::Aa, ContrastMode::Adjust);
+        let messages = vec![MessageInfo {
+            id: 0,
+            is_user: false,
+            text: "こんにちは".to_string(),
+            ops: None,
+        }];
+
+        // 通常の宣言は !important に負けるため、値から !important を外して読んではいけない
+        let inherited = colors("#000000", "#ffffff");
+        let result = effective_colors(&["color: white !important", "color: black"], inherited);
+        assert_eq!(result.unwrap().foreground.to_hex(), "#ffffff");
+
+        let mut updates = vec![StyleUpdate {
+            id: 0,
+            styles: "font-weight: bold; color: white !important".to_string(),
+        }];
+        let reports = checker.enforce(&messages, &UiState::default(), None, &mut updates, &mut []);
+        assert_eq!(reports.len(), 1);
+        let adjusted = reports[0].adjusted_foreground.clone().unwrap();
+        assert_eq!(
+            updates[0].styles,
+            format!("font-weight: bold; color: {}", adjusted)
+        );
+
+        // 前の層の !important に勝つには補正後の色にも !important が必要
+        let mut ui_state = UiState::default();
+        ui_state.role_styles.ai_message = "color: white !important;".to_string();
+        let mut updates = vec![StyleUpdate {
+            id: 0,
+            styles: "background-color: #f9fafb".to_string(),
+        }];
+        let reports = checker.enforce(&messages, &ui_state, None, &mut updates, &mut []);
+        assert_eq!(reports.len(), 1);
+        assert!(updates[0].styles.ends_with(" !important"));
+        let layers = [
+            AI_BUBBLE_STYLES,
+            ui_state.role_styles.ai_message.as_str(),
+            updates[0].styles.as_str(),
+        ];
+        let rendered = effective_colors(&layers, inherited).unwrap();
+        assert!(rendered.foreground.contrast_ratio(&rendered.background) >= 4.5);
+    }
+
+    #[test]
+    fn test_reply_id_follows_last_message_id() {
+        let checker = ContrastChecker::new(ContrastPolicy::Aa, ContrastMode::Warn);
+        // 削除やフォークでIDは添字と一致しなくなる
+        let messages = vec![
+            MessageInfo {
+                id: 3,
+                is_user: true,
+                text: "白くして".to_string(),
+                ops: None,
+            },
+            MessageInfo {
+                id: 5,
+                is_user: false,
+                text: "はい".to_string(),
+                ops: None,
+            },
+        ];
+        let mut updates = vec![
+            StyleUpdate {
+                id: 2,
+                styles: "color: white".to_string(),
+            },
+            StyleUpdate {
+                id: 6,
+                styles: "color: white".to_string(),
+            },
+        ];
+        let reports = checker.enforce(&messages, &UiState::default(), None, &mut updates, &mut []);
+        let targets: Vec<_> = reports.iter().map(|r| r.target.clone()).collect();
+        assert_eq!(targets, vec![ContrastTarget::Message(6)]);
+    }
+
+    #[test]
+    fn test_container_change_rechecks_existing_messages() {
+        let checker = ContrastChecker::new(ContrastPolicy::Aa, ContrastMode::Adjust);
+        let messages = vec![
+            MessageInfo {
+                id: 0,
+                is_user: true,
+                text: "暗くして".to_string(),
+                ops: None,
+            },
+            MessageInfo {
+                id: 1,
+                is_user: false,
+                text: "はい".to_string(),
+                ops: None,
+            },
+        ];
+        let mut ui_state = UiState::default();
+        ui_state.role_styles.ai_message = "background-color: transparent;".to_string();
+        let mut updates = Vec::new();
+        let reports = checker.enforce(
+            &messages,
+            &ui_state,
+            Some("background-color: #111827"),
+            &mut updates,
+            &mut [],
+        );
+
+        // ユーザーの吹き出しは自前の背景があるので読める。AIのメッセージと次の返信は補正する
+        let targets: Vec<_> = reports.iter().map(|r| r.target.clone()).collect();
+        assert_eq!(
+            targets,
+            vec![ContrastTarget::Message(1), ContrastTarget::Message(2)]
+        );
+        assert_eq!(updates.iter().map(|u| u.id).collect::<Vec<_>>(), vec![1, 2]);
+        let bg = Color::parse("#111827").unwrap();
+        for update in &updates {
+            let color = update.styles.trim_start_matches("color: ");
+            assert!(Color::parse(color).unwrap().contrast_ratio(&bg) >= 4.5);
+        }
+
+        // コンテナが変わらなければ既存のメッセージは再検査しない
+        let mut updates = Vec::new();
+        assert!(checker
+            .enforce(&messages, &ui_state, None, &mut updates, &mut [])
+            .is_empty());
+    }
+
+    #[test]
+    fn test_warn_mode_and_off_policy() {
+        let mut element = DynamicElementData {
+            id: 0,
+            tag: "p".to_string(),
+            text: Some("テキスト".to_string()),
+            styles: Some("color: #eeeeee".to_string()),
+            attributes: None,
+        };
+
+        let warn = ContrastChecker::new(ContrastPolicy::Aaa, ContrastMode::Warn);
+        let reports = warn.enforce(
+            &[],
+            &UiState::default(),
+            None,
+            &mut Vec::new(),
+            std::slice::from_mut(&mut element),
+        );
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].adjusted_foreground, None);
+        assert_eq!(element.styles.as_deref(), Some("color: #eeeeee"));
+        assert!(warn
+            .summarize(&reports, Locale::Ja)
+            .unwrap()
+            .contains("AAA"));
+
+        let off = ContrastChecker::new(ContrastPolicy::Off, ContrastMode::Adjust);
+        assert!(off
+            .enforce(
+                &[],
+                &UiState::default(),
+                None,
+                &mut Vec::new(),
+                std::slice::from_mut(&mut element)
+            )
+            .is_empty());
+    }
+}
@@ -67,6 +67,14 @@ impl Default for CssSanitizer {
     }
 }
 
+/// CSSプロパティ文字列を (プロパティ名, 値) の列に分解する
+pub fn declarations(css: &str) -> impl Iterator<Item = (String, &str)> {
+    css.split(';').filter_map(|decl| {
+        let (prop, value) = decl.split_once(':')?;
+        Some((prop.trim().to_ascii_lowercase(), value.trim()))
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
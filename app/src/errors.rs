@@ -0,0 +1,116 @@
+use leptos::server_fn::codec::JsonEncoding;
+use leptos::server_fn::error::{FromServerFnError, ServerFnErrorErr};
+use serde::{Deserialize, Serialize};
+
+/// send_message が返すエラー
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, thiserror::Error)]
+pub enum SendMessageError {
+    /// クライアントから送られたリクエストが制限や整合性に違反している
+    #[error("{0}")]
+    InvalidRequest(RequestRejection),
+    /// モデルが使える応答を返さなかった（ブロック、途中で打ち切り、空など）
+    #[error("{0}")]
+    ModelResponse(ResponseFailure),
+    /// APIキーやモデル呼び出しなど、サーバー側の処理に失敗した
+    #[error("{0}")]
+    Internal(String),
+    /// サーバー関数の呼び出し自体に失敗した（通信エラーなど）
+    #[error("{0}")]
+    ServerFn(ServerFnErrorErr),
+}
+
+impl FromServerFnError for SendMessageError {
+    type Encoder = JsonEncoding;
+
+    fn from_server_fn_error(value: ServerFnErrorErr) -> Self {
+        SendMessageError::ServerFn(value)
+    }
+}
+
+/// リクエスト検証で拒否した理由
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+pub enum RequestRejection {
+    #[error("メッセージが空です")]
+    EmptyText,
+    #[error("メッセージが長すぎます（{chars}文字、上限{max}文字）")]
+    TextTooLong { chars: usize, max: usize },
+    #[error("会話履歴が多すぎます（{count}件、上限{max}件）")]
+    TooManyMessages { count: usize, max: usize },
+    #[error("会話履歴が大きすぎます（{bytes}バイト、上限{max}バイト）")]
+    HistoryTooLarge { bytes: usize, max: usize },
+    #[error("UIの状態が大きすぎます（{bytes}バイト、上限{max}バイト）")]
+    UiStateTooLarge { bytes: usize, max: usize },
+    #[error("メッセージIDが昇順になっていません（ID {id} が ID {previous} の後にあります）")]
+    NonIncreasingId { id: usize, previous: usize },
+    #[error("ID {id} のAIメッセージの直前にユーザーのメッセージがありません")]
+    UnexpectedAiMessage { id: usize },
+    #[error("会話履歴の最後が送信したユーザーのメッセージと一致しません")]
+    LastMessageMismatch,
+}
+
+/// モデルの応答を使えなかった理由
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+pub enum ResponseFailure {
+    #[error(
+        "リクエストが安全性フィルタによりブロックされました。表現を変えてもう一度お試しください。"
+    )]
+    PromptBlocked { reason: String },
+    #[error("AIの応答が安全性フィルタによりブロックされたため、UIは変更していません。表現を変えてもう一度お試しください。")]
+    SafetyBlocked { reason: String },
+    #[error("AIの応答が長すぎて途中で切れたため、UIは変更していません。変更を小さく分けて指示してください。")]
+    Truncated,
+    #[error("AIの応答が既存の文章の引用と判定されたため、UIは変更していません。別の表現で指示してください。")]
+    Recitation,
+    #[error("AIから空の応答が返されたため、UIは変更していません。もう一度お試しください。")]
+    Empty,
+    #[error(
+        "AIの応答が途中で終了したため（{reason}）、UIは変更していません。もう一度お試しください。"
+    )]
+    Other { reason: String },
+}
+
+/// import_ui_document が返すエラー
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, thiserror::Error)]
+pub enum ImportError {
+    #[error("UIドキュメントを読み込めません: {0}")]
+    InvalidDocument(String),
+    #[error("self-changer のUIドキュメントではありません")]
+    UnknownFormat,
+    #[error("UIドキュメントのバージョン{version}には対応していません（対応: {supported}まで）")]
+    UnsupportedVersion { version: u32, supported: u32 },
+    /// 会話履歴やUI状態が上限や整合性に違反している
+    #[error("{0}")]
+    Rejected(RequestRejection),
+    #[error("{0}")]
+    ServerFn(ServerFnErrorErr),
+}
+
+impl FromServerFnError for ImportError {
+    type Encoder = JsonEncoding;
+
+    fn from_server_fn_error(value: ServerFnErrorErr) -> Self {
+        ImportError::ServerFn(value)
+    }
+}
+
+/// share_snapshot が返すエラー
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, thiserror::Error)]
+pub enum ShareError {
+    #[error("共有リンクを作るには、サーバーに保存先（DATABASE_PATH）が必要です")]
+    Unavailable,
+    /// 会話履歴やUI状態が上限や整合性に違反している
+    #[error("{0}")]
+    Rejected(RequestRejection),
+    #[error("共有リンクを保存できません: {0}")]
+    Storage(String),
+    #[error("{0}")]
+    ServerFn(ServerFnErrorErr),
+}
+
+impl FromServerFnError for ShareError {
+    type Encoder = JsonEncoding;
+
+    fn from_server_fn_error(value: ServerFnErrorErr) -> Self {
+        ShareError::ServerFn(value)
+    }
+}
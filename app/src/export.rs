@@ -0,0 +1,288 @@
+//! カスタマイズしたUIを単体で開けるHTMLファイルとして書き出す
+//!
+//! 画面と同じ構造のHTMLを作り、インラインのスタイルはIDごとのルールとして
+//! `<style>` にまとめる。ブラウザからのダウンロードと、サーバーの `/export` の両方で使う。
+
+use crate::base_styles::bubble_styles;
+use crate::css_sanitizer::CssSanitizer;
+use common::{DynamicElementData, Locale, SessionSnapshot};
+use std::fmt::Write;
+
+/// 書き出したファイルの名前
+pub const EXPORT_FILE_NAME: &str = "self-changer.html";
+
+/// 画面のレイアウトに必要な最小限のスタイル（style/main.css から抜粋）
+const BASE_CSS: &str = "\
+body { font-family: sans-serif; margin: 0; padding: 0; }
+.main-container { display: flex; width: 100%; min-height: 100vh; align-items: center; justify-content: center; box-sizing: border-box; }
+.chat-container { display: flex; flex-direction: column; border-radius: 20px; box-shadow: 0 10px 30px rgba(0, 0, 0, 0.1); overflow: hidden; width: 100%; max-width: 500px; }
+.messages-area { padding: 1rem; display: flex; flex-direction: column; gap: 16px; }
+.message-item { display: flex; align-items: flex-end; }
+.message-item.justify-end { justify-content: flex-end; }
+.message-item.justify-start { justify-content: flex-start; }
+.message-icon { flex-shrink: 0; width: 32px; height: 32px; border-radius: 50%; display: flex; align-items: center; justify-content: center; font-size: 16px; background: #6b7280; margin: 0 8px; }
+.message-text { margin: 0; text-align: left; font-size: 14px; line-height: 1.4; white-space: pre-wrap; }
+.dynamic-element { margin-top: 8px; }
+.dynamic-element button { padding: 8px 16px; border: none; border-radius: 8px; font-size: 14px; }
+.dynamic-element img { max-width: 100%; height: auto; border-radius: 8px; }
+.dynamic-element div, .dynamic-element p { margin: 0; padding: 8px; border-radius: 8px; }
+.dynamic-element span { padding: 4px 8px; border-radius: 4px; }
+.dynamic-element input { padding: 8px 12px; border: 1px solid #ddd; border-radius: 4px; font-size: 14px; }
+";
+
+/// 会話とUI状態を、スタイルシートを含む1つのHTMLにする。
+/// `/` で始まるURL（画像プロキシなど）は `base_url` を付けて絶対URLにする
+pub fn export_html(snapshot: &SessionSnapshot, locale: Locale, base_url: &str) -> String {
+    let state = &snapshot.ui_state;
+    let mut css = String::from(BASE_CSS);
+    let mut body = String::new();
+
+    push_rule(&mut css, ".chat-container", &state.chat_container_styles);
+    for message in &snapshot.messages {
+        let mut bubble = bubble_styles(message.is_user).to_string();
+        let role = if message.is_user {
+            &state.role_styles.user_message
+        } else {
+            &state.role_styles.ai_message
+        };
+        for extra in [Some(role), state.element_styles.get(&message.id)]
+            .into_iter()
+            .flatten()
+        {
+            bubble.push(' ');
+            bubble.push_str(extra);
+        }
+        push_rule(&mut css, &format!("#m{}", message.id), &bubble);
+
+        let _ = write!(
+            body,
+            "<div class=\"message-item {}\">",
+            if message.is_user {
+                "justify-end"
+            } else {
+                "justify-start"
+            }
+        );
+        if !message.is_user {
+            body.push_str("<div class=\"message-icon\">🤖</div>");
+        }
+        let _ = writeln!(
+            body,
+            "<div id=\"m{}\"><p class=\"message-text\">{}</p></div></div>",
+            message.id,
+            escape_html(&message.text)
+        );
+
+        // このメッセージ直後に紐づいた動的要素
+        for (index, element) in state
+            .dynamic_elements
+            .get(&message.id)
+            .into_iter()
+            .flatten()
+            .enumerate()
+        {
+            let id = format!("e{}-{}", message.id, index);
+            push_rule(
+                &mut css,
+                &format!("#{}", id),
+                element.styles.as_deref().unwrap_or_default(),
+            );
+            let _ = writeln!(
+                body,
+                "<div class=\"dynamic-element\" id=\"{}\">{}</div>",
+                id,
+                render_element(element, base_url)
+            );
+        }
+    }
+
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"{}\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>self changer</title>\n<style>\n{}</style>\n</head>\n<body>\n\
+         <div class=\"main-container\"><div class=\"chat-container\"><div class=\"messages-area\">\n\
+         {}</div></div></div>\n</body>\n</html>\n",
+        locale.code(),
+        css,
+        body
+    )
+}
+
+/// 保存されたセッションを書き出す（サーバーの `/export` 用）。
+/// 保存されたUI状態はクライアントから送られたものなので、要素の許可リストを適用し直す
+#[cfg(feature = "ssr")]
+pub fn export_stored_session(snapshot: &SessionSnapshot, headers: &http::HeaderMap) -> String {
+    let mut snapshot = snapshot.clone();
+    for elements in snapshot.ui_state.dynamic_elements.values_mut() {
+        crate::ai_output::sanitize_elements(elements);
+    }
+    let locale = headers
+        .get(http::header::ACCEPT_LANGUAGE)
+        .and_then(|v| v.to_str().ok())
+        .map(crate::i18n::negotiate)
+        .unwrap_or_default();
+    // 画像プロキシのURLを絶対URLにするため、リクエストされたホストを使う
+    let host = headers
+        .get(http::header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("localhost");
+    let scheme = headers
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("http");
+    export_html(&snapshot, locale, &format!("{}://{}", scheme, host))
+}
+
+/// 現在の画面をHTMLファイルとしてダウンロードさせる
+pub fn download_html(snapshot: &SessionSnapshot, locale: Locale) {
+    #[cfg(not(feature = "ssr"))]
+    let base_url = leptos::prelude::window()
+        .location()
+        .origin()
+        .unwrap_or_default();
+    #[cfg(feature = "ssr")]
+    let base_url = String::new();
+    download_file(
+        &export_html(snapshot, locale, &base_url),
+        "text/html;charset=utf-8",
+        EXPORT_FILE_NAME,
+    );
+}
+
+/// 文字列をファイルとしてダウンロードさせる（サーバーでは何もしない）
+pub fn download_file(contents: &str, mime: &str, file_name: &str) {
+    #[cfg(not(feature = "ssr"))]
+    if let Err(e) = download(contents, mime, file_name) {
+        log::error!("{} を書き出せません: {:?}", file_name, e);
+    }
+    #[cfg(feature = "ssr")]
+    let _ = (contents, mime, file_name);
+}
+
+#[cfg(not(feature = "ssr"))]
+fn download(contents: &str, mime: &str, file_name: &str) -> Result<(), wasm_bindgen::JsValue> {
+    use wasm_bindgen::JsCast;
+    use web_sys::{Blob, BlobPropertyBag, HtmlAnchorElement, Url};
+
+    let options = BlobPropertyBag::new();
+    options.set_type(mime);
+    let parts = js_sys::Array::of1(&contents.into());
+    let blob = Blob::new_with_str_sequence_and_options(&parts, &options)?;
+    let url = Url::create_object_url_with_blob(&blob)?;
+    let anchor: HtmlAnchorElement = leptos::prelude::document()
+        .create_element("a")?
+        .unchecked_into();
+    anchor.set_href(&url);
+    anchor.set_download(file_name);
+    anchor.click();
+    Url::revoke_object_url(&url)
+}
+
+// 画面の MessageView と同じ対応でタグを描画する（それ以外のタグは div として描画する）
+fn render_element(element: &DynamicElementData, base_url: &str) -> String {
+    let text = escape_html(element.text.as_deref().unwrap_or_default());
+    let attr = |name: &str| {
+        element
+            .attributes
+            .as_ref()
+            .and_then(|a| a.get(name))
+            .map(String::as_str)
+            .unwrap_or_default()
+    };
+    let url = |name: &str| escape_html(&absolute_url(attr(name), base_url));
+    match element.tag.as_str() {
+        "p" | "span" | "button" => format!("<{0}>{1}</{0}>", element.tag, text),
+        "a" => format!("<a href=\"{}\">{}</a>", url("href"), text),
+        "img" => format!(
+            "<img src=\"{}\" alt=\"{}\">",
+            url("src"),
+            escape_html(attr("alt"))
+        ),
+        "input" => format!(
+            "<input type=\"{}\" placeholder=\"{}\" value=\"{}\">",
+            escape_html(match attr("type") {
+                "" => "text",
+                t => t,
+            }),
+            escape_html(attr("placeholder")),
+            escape_html(attr("value"))
+        ),
+        _ => format!("<div>{}</div>", text),
+    }
+}
+
+fn absolute_url(url: &str, base_url: &str) -> String {
+    if url.starts_with('/') && !url.starts_with("//") {
+        format!("{}{}", base_url.trim_end_matches('/'), url)
+    } else {
+        url.to_string()
+    }
+}
+
+// 宣言をサニタイズし、ルールや <style> の外に出られる文字を除いて追加する
+fn push_rule(css: &mut String, selector: &str, declarations: &str) {
+    let declarations = CssSanitizer::new()
+        .sanitize_css_string(declarations)
+        .replace(['{', '}', '<', '>'], "");
+    if !declarations.trim().is_empty() {
+        let _ = writeln!(css, "{} {{ {}; }}", selector, declarations);
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::MessageInfo;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_export_extracts_styles_and_escapes() {
+        let mut snapshot = SessionSnapshot::default();
+        snapshot.messages.push(MessageInfo {
+            id: 1,
+            is_user: true,
+            text: "<b>太字</b>".to_string(),
+            ops: None,
+        });
+        snapshot.ui_state.chat_container_styles =
+            "background: #123456;} body { display: none".to_string();
+        snapshot
+            .ui_state
+            .element_styles
+            .insert(1, "color: red;".to_string());
+        snapshot.ui_state.dynamic_elements.insert(
+            1,
+            vec![DynamicElementData {
+                id: 7,
+                tag: "img".to_string(),
+                text: None,
+                styles: Some("width: 50%</style><script>".to_string()),
+                attributes: Some(HashMap::from([(
+                    "src".to_string(),
+                    "/img-proxy?url=https%3A%2F%2Fexample.com%2Fa.png".to_string(),
+                )])),
+            }],
+        );
+
+        let html = export_html(&snapshot, Locale::En, "https://self-changer.example/");
+        assert!(html.starts_with("<!DOCTYPE html>\n<html lang=\"en\">"));
+        assert!(html.contains("<p class=\"message-text\">&lt;b&gt;太字&lt;/b&gt;</p>"));
+        assert!(html.contains("#m1 { padding: 12px 16px;"));
+        assert!(html.contains("color: red; }"));
+        assert!(html.contains(
+            "<img src=\"https://self-changer.example/img-proxy?url=https%3A%2F%2Fexample.com%2Fa.png\""
+        ));
+        // スタイルの値からルールや <style> の外には出られない
+        assert!(html.contains(".chat-container { background: #123456; body  display: none; }"));
+        assert_eq!(html.matches("</style>").count(), 1);
+        assert!(!html.contains("<script"));
+    }
+}
@@ -0,0 +1,192 @@
+//! 出力例ライブラリと、要求に近い例を選ぶセレクター
+//!
+//! 例は `prompts/examples.jsonl` に (要求, 期待する出力) の組として1行ずつ書く。
+//! 外部の埋め込みサービスは使わず、文字n-gramの重なりで近さを測る。
+
+use crate::prompt_templates::TemplateError;
+use common::{DynamicElementData, StyleUpdate};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// 出力例のファイル
+pub const EXAMPLES_FILE: &str = "examples.jsonl";
+
+/// 既定でプロンプトに含める出力例の数
+const DEFAULT_EXAMPLE_COUNT: usize = 4;
+
+/// 出力例の期待する出力（AIの出力形式と同じ）
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExampleOutput {
+    pub success: bool,
+    pub message: String,
+    pub chat_container_styles: String,
+    pub change_style_elements: Vec<StyleUpdate>,
+    pub new_elements: Vec<DynamicElementData>,
+}
+
+/// 1件の出力例
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Example {
+    pub category: String,
+    pub request: String,
+    pub output: ExampleOutput,
+}
+
+/// 読み込み済みの出力例と、選択用のn-gram
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExampleLibrary {
+    pub version: String, // 内容のハッシュ（プロンプトのバージョンに含める）
+    examples: Vec<Example>,
+    grams: Vec<HashSet<String>>,
+}
+
+impl ExampleLibrary {
+    /// JSON Lines を解釈する。空行と `#` で始まる行は無視する
+    pub fn parse(file: &str, source: &str) -> Result<Self, TemplateError> {
+        let mut examples = Vec::new();
+        for (index, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let example: Example =
+                serde_json::from_str(line).map_err(|e| TemplateError::InvalidExample {
+                    file: file.to_string(),
+                    line: index + 1,
+                    message: e.to_string(),
+                })?;
+            examples.push(example);
+        }
+        if examples.is_empty() {
+            return Err(TemplateError::InvalidExample {
+                file: file.to_string(),
+                line: 0,
+                message: "出力例が1件もありません".to_string(),
+            });
+        }
+        let grams = examples.iter().map(|e| ngrams(&e.request)).collect();
+        Ok(Self {
+            version: format!("{:08x}", fnv1a(source) as u32),
+            examples,
+            grams,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.examples.len()
+    }
+
+    /// 要求に近い順に最大 `k` 件を選ぶ（同点ならファイルの順）
+    pub fn select(&self, request: &str, k: usize) -> Vec<&Example> {
+        let query = ngrams(request);
+        let mut scored: Vec<(f64, usize)> = self
+            .grams
+            .iter()
+            .enumerate()
+            .map(|(i, grams)| (similarity(&query, grams), i))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+        scored
+            .into_iter()
+            .take(k)
+            .map(|(_, i)| &self.examples[i])
+            .collect()
+    }
+
+    /// 選んだ出力例をシステム指示に埋め込む形式にする
+    pub fn render(examples: &[&Example]) -> String {
+        examples
+            .iter()
+            .map(|e| {
+                format!(
+                    "- {}: {}",
+                    e.request,
+                    serde_json::to_string(&e.output).unwrap_or_default()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// 環境変数 FEW_SHOT_EXAMPLES で指定された、プロンプトに含める出力例の数
+pub fn example_count_from_env() -> usize {
+    std::env::var("FEW_SHOT_EXAMPLES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_EXAMPLE_COUNT)
+}
+
+// 空白と記号を除いた文字の2-gramと3-gram（日本語は分かち書きしなくても比較できる）
+fn ngrams(text: &str) -> HashSet<String> {
+    let chars: Vec<char> = text
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .collect();
+    let mut grams = HashSet::new();
+    for n in [2, 3] {
+        for window in chars.windows(n) {
+            grams.insert(window.iter().collect());
+        }
+    }
+    if grams.is_empty() && !chars.is_empty() {
+        grams.insert(chars.iter().collect());
+    }
+    grams
+}
+
+// 集合の重なり（Ochiai係数）
+fn similarity(a: &HashSet<String>, b: &HashSet<String>) -> f64 {
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    let shared = a.intersection(b).count() as f64;
+    shared / ((a.len() * b.len()) as f64).sqrt()
+}
+
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library() -> ExampleLibrary {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../prompts")
+            .join(EXAMPLES_FILE);
+        ExampleLibrary::parse(EXAMPLES_FILE, &std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_selects_relevant_examples() {
+        let library = library();
+        assert!(library.len() > 10);
+
+        let picked = library.select("猫の写真を表示してほしい", 2);
+        assert_eq!(picked[0].request, "猫の画像を表示して");
+
+        let picked = library.select("背景色を赤くして", 3);
+        assert!(picked.iter().any(|e| e.request == "全体背景を青くして"));
+
+        let picked = library.select("Googleへのリンクボタン", 2);
+        assert!(picked.iter().all(|e| e.output.new_elements[0].tag == "a"));
+    }
+
+    #[test]
+    fn test_render_and_validation() {
+        let library = library();
+        let rendered = ExampleLibrary::render(&library.select("区切り線", 1));
+        assert!(rendered.starts_with("- 区切り線を追加して: {\"success\":true"));
+        assert_eq!(rendered.lines().count(), 1);
+
+        let err =
+            ExampleLibrary::parse("x.jsonl", "# comment\n{\"request\": \"a\"}\n").unwrap_err();
+        assert!(matches!(err, TemplateError::InvalidExample { line: 2, .. }));
+        assert!(ExampleLibrary::parse("x.jsonl", "\n").is_err());
+    }
+}
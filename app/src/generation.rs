@@ -0,0 +1,274 @@
+//! モデルの生成パラメータ（温度、top-p、最大出力トークン数、候補数、安全性設定）
+//!
+//! 既定値はデプロイごとに環境変数で決め、リクエストごとの上書きは
+//! サーバー側の上限に収めてから適用する。
+
+use common::{GenerationOverrides, GenerationPreset};
+use google_ai_rs::genai::GenerativeModel;
+use google_ai_rs::proto::safety_setting::HarmBlockThreshold;
+use google_ai_rs::proto::{HarmCategory, SafetySetting};
+use std::str::FromStr;
+
+/// 安全性設定を適用するカテゴリ
+const SAFETY_CATEGORIES: [HarmCategory; 4] = [
+    HarmCategory::Harassment,
+    HarmCategory::HateSpeech,
+    HarmCategory::SexuallyExplicit,
+    HarmCategory::DangerousContent,
+];
+
+/// プロバイダーが一度に返せる候補数の上限
+const MAX_CANDIDATE_COUNT: i32 = 8;
+
+/// モデルに渡す生成パラメータ（None はプロバイダーの既定値を使う）
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GenerationSettings {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_output_tokens: Option<i32>,
+    pub candidate_count: Option<i32>,
+    pub safety_threshold: Option<HarmBlockThreshold>,
+}
+
+/// リクエストごとの上書きに対するサーバー側の上限
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GenerationLimits {
+    pub max_temperature: f32,
+    pub max_output_tokens: i32,
+}
+
+impl Default for GenerationLimits {
+    fn default() -> Self {
+        Self {
+            max_temperature: 1.5,
+            max_output_tokens: 8192,
+        }
+    }
+}
+
+impl GenerationLimits {
+    /// 環境変数 GEN_MAX_TEMPERATURE / GEN_MAX_OUTPUT_TOKENS_LIMIT で既定値を上書きする
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            max_temperature: env_var("GEN_MAX_TEMPERATURE").unwrap_or(defaults.max_temperature),
+            max_output_tokens: env_var("GEN_MAX_OUTPUT_TOKENS_LIMIT")
+                .unwrap_or(defaults.max_output_tokens),
+        }
+    }
+}
+
+impl GenerationSettings {
+    /// 環境変数 GEN_TEMPERATURE / GEN_TOP_P / GEN_MAX_OUTPUT_TOKENS /
+    /// GEN_CANDIDATE_COUNT / GEN_SAFETY_THRESHOLD から読み込む
+    pub fn from_env() -> Self {
+        let safety_threshold = std::env::var("GEN_SAFETY_THRESHOLD")
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .and_then(|v| {
+                let threshold = HarmBlockThreshold::from_str_name(v.trim());
+                if threshold.is_none() {
+                    log::warn!("GEN_SAFETY_THRESHOLD を解釈できません: {}", v);
+                }
+                threshold
+            });
+        Self {
+            temperature: env_var("GEN_TEMPERATURE"),
+            top_p: env_var("GEN_TOP_P"),
+            max_output_tokens: env_var("GEN_MAX_OUTPUT_TOKENS"),
+            candidate_count: env_var("GEN_CANDIDATE_COUNT"),
+            safety_threshold,
+        }
+    }
+
+    /// プリセット → 個別の上書きの順に適用し、上限に収める。
+    /// 候補数と安全性設定はリクエストからは変更できない
+    pub fn resolve(&self, overrides: &GenerationOverrides, limits: &GenerationLimits) -> Self {
+        let mut settings = *self;
+        if let Some(preset) = overrides.preset {
+            let (temperature, top_p) = preset_values(preset);
+            settings.temperature = Some(temperature);
+            settings.top_p = Some(top_p);
+        }
+        settings.temperature = overrides.temperature.or(settings.temperature);
+        settings.top_p = overrides.top_p.or(settings.top_p);
+        settings.max_output_tokens = overrides.max_output_tokens.or(settings.max_output_tokens);
+
+        // NaN などの不正な値はプロバイダーの既定値に戻す
+        settings.temperature = settings
+            .temperature
+            .filter(|t| t.is_finite())
+            .map(|t| clamp_logged("temperature", t, 0.0, limits.max_temperature));
+        settings.top_p = settings
+            .top_p
+            .filter(|p| p.is_finite())
+            .map(|p| clamp_logged("top_p", p, 0.0, 1.0));
+        settings.max_output_tokens = settings
+            .max_output_tokens
+            .map(|n| clamp_logged("max_output_tokens", n, 1, limits.max_output_tokens));
+        settings.candidate_count = settings
+            .candidate_count
+            .map(|n| n.clamp(1, MAX_CANDIDATE_COUNT));
+        settings
+    }
+
+    /// 出力が途中で切れたときの再試行用に、最大出力トークン数を上限まで広げた設定を返す。
+    /// すでに上限に達していれば None
+    pub fn with_larger_output_budget(&self, limits: &GenerationLimits) -> Option<Self> {
+        let larger = self
+            .max_output_tokens
+            .map_or(limits.max_output_tokens, |n| n.saturating_mul(2))
+            .min(limits.max_output_tokens);
+        if self.max_output_tokens.is_some_and(|n| n >= larger) {
+            return None;
+        }
+        Some(Self {
+            max_output_tokens: Some(larger),
+            ..*self
+        })
+    }
+
+    /// 設定された項目だけをモデルに適用する
+    pub fn apply<'c>(&self, mut model: GenerativeModel<'c>) -> GenerativeModel<'c> {
+        if let Some(temperature) = self.temperature {
+            model = model.temperature(temperature);
+        }
+        if let Some(top_p) = self.top_p {
+            model = model.top_p(top_p);
+        }
+        if let Some(max_output_tokens) = self.max_output_tokens {
+            model = model.max_output_tokens(max_output_tokens);
+        }
+        if let Some(candidate_count) = self.candidate_count {
+            model = model.candidate_count(candidate_count);
+        }
+        if let Some(threshold) = self.safety_threshold {
+            model = model.safety_settings(safety_settings(threshold));
+        }
+        model
+    }
+}
+
+/// プリセットの (温度, top-p)
+fn preset_values(preset: GenerationPreset) -> (f32, f32) {
+    match preset {
+        GenerationPreset::Creative => (1.2, 0.95),
+        GenerationPreset::Precise => (0.2, 0.8),
+    }
+}
+
+fn safety_settings(threshold: HarmBlockThreshold) -> Vec<SafetySetting> {
+    SAFETY_CATEGORIES
+        .iter()
+        .map(|&category| {
+            SafetySetting::new()
+                .harm_category(category)
+                .harm_threshold(threshold)
+        })
+        .collect()
+}
+
+// 上限を超えた値は切り詰め、ログに残す
+fn clamp_logged<T: PartialOrd + Copy + std::fmt::Display>(
+    name: &str,
+    value: T,
+    min: T,
+    max: T,
+) -> T {
+    let clamped = if value < min {
+        min
+    } else if value > max {
+        max
+    } else {
+        value
+    };
+    if clamped != value {
+        log::info!("{} を {} から {} に制限しました", name, value, clamped);
+    }
+    clamped
+}
+
+// 解釈できない値はログに残して無視する
+fn env_var<T: FromStr>(name: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    let parsed = value.trim().parse().ok();
+    if parsed.is_none() {
+        log::warn!("{} を解釈できません: {}", name, value);
+    }
+    parsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preset_then_overrides_within_limits() {
+        let base = GenerationSettings {
+            temperature: Some(0.7),
+            max_output_tokens: Some(2048),
+            candidate_count: Some(0),
+            ..Default::default()
+        };
+        let limits = GenerationLimits::default();
+
+        // 上書きなしならサーバーの設定のまま（候補数は1以上）
+        let resolved = base.resolve(&GenerationOverrides::default(), &limits);
+        assert_eq!(resolved.temperature, Some(0.7));
+        assert_eq!(resolved.top_p, None);
+        assert_eq!(resolved.candidate_count, Some(1));
+
+        // プリセットより個別の上書きを優先する
+        let overrides = GenerationOverrides {
+            preset: Some(GenerationPreset::Precise),
+            top_p: Some(0.5),
+            ..Default::default()
+        };
+        let resolved = base.resolve(&overrides, &limits);
+        assert_eq!(resolved.temperature, Some(0.2));
+        assert_eq!(resolved.top_p, Some(0.5));
+        assert_eq!(resolved.max_output_tokens, Some(2048));
+
+        // 上限を超える値と不正な値
+        let overrides = GenerationOverrides {
+            temperature: Some(5.0),
+            top_p: Some(f32::NAN),
+            max_output_tokens: Some(1_000_000),
+            ..Default::default()
+        };
+        let resolved = base.resolve(&overrides, &limits);
+        assert_eq!(resolved.temperature, Some(1.5));
+        assert_eq!(resolved.top_p, None);
+        assert_eq!(resolved.max_output_tokens, Some(8192));
+    }
+
+    #[test]
+    fn test_larger_output_budget_for_retry() {
+        let limits = GenerationLimits::default();
+        let settings = GenerationSettings {
+            max_output_tokens: Some(3000),
+            ..Default::default()
+        };
+        let larger = settings.with_larger_output_budget(&limits).unwrap();
+        assert_eq!(larger.max_output_tokens, Some(6000));
+        let larger = larger.with_larger_output_budget(&limits).unwrap();
+        assert_eq!(larger.max_output_tokens, Some(8192));
+        assert_eq!(larger.with_larger_output_budget(&limits), None);
+
+        // 未指定ならサーバーの上限で再試行する
+        let larger = GenerationSettings::default()
+            .with_larger_output_budget(&limits)
+            .unwrap();
+        assert_eq!(larger.max_output_tokens, Some(8192));
+    }
+
+    #[test]
+    fn test_safety_settings_cover_categories() {
+        let settings = safety_settings(HarmBlockThreshold::BlockOnlyHigh);
+        assert_eq!(settings.len(), SAFETY_CATEGORIES.len());
+        assert!(settings
+            .iter()
+            .all(|s| s.threshold == HarmBlockThreshold::BlockOnlyHigh as i32));
+        assert_eq!(settings[0].category, HarmCategory::Harassment as i32);
+    }
+}
@@ -0,0 +1,583 @@
+//! UIの文言とロケールの判定
+//!
+//! ロケールはサーバーが Accept-Language から決めて `<html lang>` に書き、
+//! ハイドレーション時はその値を読んで同じ文言で描画する。
+
+use crate::errors::{ImportError, RequestRejection, ResponseFailure, SendMessageError, ShareError};
+use crate::versions::{Change, StyleTarget};
+use common::{Locale, SessionVersion, UsageTotals};
+
+/// 画面に表示する文言
+pub struct UiText {
+    pub welcome: &'static str,
+    pub loading: &'static str,
+    pub placeholder: &'static str,
+    pub not_found: &'static str,
+    pub preset_title: &'static str,
+    pub preset_standard: &'static str,
+    pub preset_creative: &'static str,
+    pub preset_precise: &'static str,
+    pub export_title: &'static str,
+    pub export_json_title: &'static str,
+    pub import_title: &'static str,
+    pub share_title: &'static str,
+    pub share_prompt: &'static str,
+    pub shared_notice: &'static str,
+    pub shared_not_found: &'static str,
+    pub sessions_title: &'static str,
+    pub new_session: &'static str,
+    pub untitled_session: &'static str,
+    pub session_name_prompt: &'static str,
+    pub rename_session_title: &'static str,
+    pub duplicate_session_title: &'static str,
+    pub fork_title: &'static str,
+    pub delete_session_title: &'static str,
+    pub delete_session_confirm: &'static str,
+    pub session_error: &'static str,
+    pub themes_title: &'static str,
+    pub themes_hint: &'static str,
+    pub save_theme_title: &'static str,
+    pub theme_name_prompt: &'static str,
+    pub default_theme_name: &'static str,
+    pub delete_theme_title: &'static str,
+    pub delete_theme_confirm: &'static str,
+    pub theme_error: &'static str,
+    pub timeline_title: &'static str,
+    pub timeline_empty: &'static str,
+    pub preview_version_title: &'static str,
+    pub restore_version_title: &'static str,
+    pub close_preview: &'static str,
+    pub version_error: &'static str,
+    // 以下はサーバーがAIの出力の代わりに返すメッセージ
+    #[cfg(feature = "ssr")]
+    pub invalid_json: &'static str,
+    #[cfg(feature = "ssr")]
+    pub unrecoverable: &'static str,
+}
+
+static JA: UiText = UiText {
+    welcome: "こんにちは！self changerチャットへようこそ。",
+    loading: "読み込み中...",
+    placeholder: "メッセージを入力...",
+    not_found: "ページが見つかりません",
+    preset_title: "生成の傾向",
+    preset_standard: "標準",
+    preset_creative: "創造的",
+    preset_precise: "正確",
+    export_title: "HTMLとして書き出す",
+    export_json_title: "JSONとして書き出す",
+    import_title: "JSONを読み込む",
+    share_title: "共有リンクを作成",
+    share_prompt: "共有リンク（読み取り専用）:",
+    shared_notice: "共有されたデザインです（読み取り専用）",
+    shared_not_found: "共有リンクが見つからないか、有効期限が切れています",
+    sessions_title: "セッション",
+    new_session: "新しいセッション",
+    untitled_session: "無題のセッション",
+    session_name_prompt: "セッション名:",
+    rename_session_title: "名前を変更",
+    duplicate_session_title: "複製",
+    fork_title: "ここから分岐",
+    delete_session_title: "削除",
+    delete_session_confirm: "このセッションを削除しますか？会話とUI状態は元に戻せません。",
+    session_error: "セッションを操作できませんでした。もう一度お試しください。",
+    themes_title: "テーマ",
+    themes_hint: "クリックすると現在のスタイルに重ねて適用します",
+    save_theme_title: "現在のスタイルをテーマとして保存",
+    theme_name_prompt: "テーマ名（同じ名前のテーマは上書きします）:",
+    default_theme_name: "マイテーマ",
+    delete_theme_title: "削除",
+    delete_theme_confirm: "このテーマを削除しますか？",
+    theme_error: "テーマを操作できませんでした。もう一度お試しください。",
+    timeline_title: "履歴",
+    timeline_empty: "まだ版がありません。返信のたびに記録されます。",
+    preview_version_title: "プレビュー",
+    restore_version_title: "この版に戻す",
+    close_preview: "閉じる",
+    version_error: "履歴を読み込めませんでした。もう一度お試しください。",
+    #[cfg(feature = "ssr")]
+    invalid_json: "AIの出力がJSONとして不正だったため、UIは変更していません。もう一度具体的に指示してください。",
+    #[cfg(feature = "ssr")]
+    unrecoverable: "チャットやメッセージが見えなくなる変更だったため、UIは変更していません。別の指示をお試しください。",
+};
+
+static EN: UiText = UiText {
+    welcome: "Hello! Welcome to the self changer chat.",
+    loading: "Loading...",
+    placeholder: "Type a message...",
+    not_found: "Page not found",
+    preset_title: "Generation style",
+    preset_standard: "Standard",
+    preset_creative: "Creative",
+    preset_precise: "Precise",
+    export_title: "Export as HTML",
+    export_json_title: "Export as JSON",
+    import_title: "Import JSON",
+    share_title: "Create share link",
+    share_prompt: "Share link (read-only):",
+    shared_notice: "A shared design (read-only)",
+    shared_not_found: "This share link does not exist or has expired",
+    sessions_title: "Sessions",
+    new_session: "New session",
+    untitled_session: "Untitled session",
+    session_name_prompt: "Session name:",
+    rename_session_title: "Rename",
+    duplicate_session_title: "Duplicate",
+    fork_title: "Branch from here",
+    delete_session_title: "Delete",
+    delete_session_confirm: "Delete this session? Its conversation and UI state cannot be restored.",
+    session_error: "The session could not be updated. Please try again.",
+    themes_title: "Themes",
+    themes_hint: "Click a theme to layer it onto the current styles",
+    save_theme_title: "Save the current styles as a theme",
+    theme_name_prompt: "Theme name (a theme with the same name is replaced):",
+    default_theme_name: "My theme",
+    delete_theme_title: "Delete",
+    delete_theme_confirm: "Delete this theme?",
+    theme_error: "The theme could not be updated. Please try again.",
+    timeline_title: "History",
+    timeline_empty: "No versions yet. One is recorded after every reply.",
+    preview_version_title: "Preview",
+    restore_version_title: "Restore this version",
+    close_preview: "Close",
+    version_error: "The history could not be loaded. Please try again.",
+    #[cfg(feature = "ssr")]
+    invalid_json: "The AI output was not valid JSON, so the UI was not changed. Please try again with a more specific request.",
+    #[cfg(feature = "ssr")]
+    unrecoverable: "That change would have hidden the chat or its messages, so the UI was not changed. Please try a different request.",
+};
+
+pub fn text(locale: Locale) -> &'static UiText {
+    match locale {
+        Locale::Ja => &JA,
+        Locale::En => &EN,
+    }
+}
+
+/// Accept-Language ヘッダーから、対応している言語のうち最も優先度の高いものを選ぶ
+#[cfg(feature = "ssr")]
+pub fn negotiate(accept_language: &str) -> Locale {
+    let mut ranges: Vec<(f32, &str)> = accept_language
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (!tag.is_empty() && quality > 0.0).then_some((quality, tag))
+        })
+        .collect();
+    // 同じ優先度ならヘッダーの順
+    ranges.sort_by(|a, b| b.0.total_cmp(&a.0));
+    ranges
+        .into_iter()
+        .find_map(|(_, tag)| Locale::from_tag(tag))
+        .unwrap_or_default()
+}
+
+/// 現在のリクエスト（サーバー）または `<html lang>`（ブラウザ）からロケールを決める
+pub fn detect_locale() -> Locale {
+    #[cfg(feature = "ssr")]
+    {
+        use leptos::prelude::use_context;
+        use_context::<http::request::Parts>()
+            .and_then(|parts| {
+                parts
+                    .headers
+                    .get(http::header::ACCEPT_LANGUAGE)?
+                    .to_str()
+                    .ok()
+                    .map(negotiate)
+            })
+            .unwrap_or_default()
+    }
+
+    #[cfg(not(feature = "ssr"))]
+    {
+        leptos::prelude::document()
+            .document_element()
+            .and_then(|e| e.get_attribute("lang"))
+            .and_then(|lang| Locale::from_tag(&lang))
+            .unwrap_or_default()
+    }
+}
+
+/// 複製したセッションの名前
+pub fn duplicate_name(locale: Locale, name: &str) -> String {
+    match locale {
+        Locale::Ja => format!("{}のコピー", name),
+        Locale::En => format!("Copy of {}", name),
+    }
+}
+
+/// メッセージから分岐したセッションの名前（何件目のメッセージからか）
+pub fn fork_name(locale: Locale, name: &str, message_no: usize) -> String {
+    match locale {
+        Locale::Ja => format!("{}（{}件目から分岐）", name, message_no),
+        Locale::En => format!("{} (branch at #{})", name, message_no),
+    }
+}
+
+/// テーマの適用の指示への返信
+pub fn theme_applied_message(locale: Locale, name: &str) -> String {
+    match locale {
+        Locale::Ja => format!("テーマ『{}』を適用しました。", name),
+        Locale::En => format!("Applied the theme \"{}\".", name),
+    }
+}
+
+/// タイムラインに表示する版の名前（復元した版には元の版の番号を添える）
+pub fn version_label(locale: Locale, version: &SessionVersion) -> String {
+    match (locale, version.restored_from) {
+        (Locale::Ja, None) => format!("バージョン{}", version.number),
+        (Locale::Ja, Some(from)) => {
+            format!("バージョン{}（バージョン{}を復元）", version.number, from)
+        }
+        (Locale::En, None) => format!("Version {}", version.number),
+        (Locale::En, Some(from)) => {
+            format!(
+                "Version {} (restored from version {})",
+                version.number, from
+            )
+        }
+    }
+}
+
+/// プレビュー中の案内
+pub fn preview_notice(locale: Locale, version: &SessionVersion) -> String {
+    match locale {
+        Locale::Ja => format!("{}をプレビュー中", version_label(locale, version)),
+        Locale::En => format!("Previewing {}", version_label(locale, version)),
+    }
+}
+
+/// 前の版からの変更を1行にまとめる。
+/// 例: "message 2: background-color #e5e7eb → #3b82f6; +1 button after message 3"
+pub fn describe_changes(locale: Locale, changes: &[Change]) -> String {
+    if changes.is_empty() {
+        return match locale {
+            Locale::Ja => "見た目の変更なし".to_string(),
+            Locale::En => "No visual changes".to_string(),
+        };
+    }
+    // 同じ対象へのスタイルの変更は1つにまとめる
+    let mut parts: Vec<(Option<&StyleTarget>, Vec<String>)> = Vec::new();
+    for change in changes {
+        match change {
+            Change::Style {
+                target,
+                property,
+                before,
+                after,
+            } => {
+                let value = match (before, after) {
+                    (Some(before), Some(after)) => format!("{} {} → {}", property, before, after),
+                    (None, Some(after)) => format!("+{} {}", property, after),
+                    (Some(before), None) => format!("−{} {}", property, before),
+                    (None, None) => continue,
+                };
+                match parts.last_mut() {
+                    Some((Some(last), values)) if *last == target => values.push(value),
+                    _ => parts.push((Some(target), vec![value])),
+                }
+            }
+            Change::Elements { anchor, tag, delta } => {
+                // メッセージの番号は画面上の1から数える
+                let text = match (locale, *delta > 0) {
+                    (Locale::Ja, true) => {
+                        format!("メッセージ{}の後に{}を{}つ追加", anchor + 1, tag, delta)
+                    }
+                    (Locale::Ja, false) => {
+                        format!("メッセージ{}の後の{}を{}つ削除", anchor + 1, tag, -delta)
+                    }
+                    (Locale::En, true) => {
+                        format!("+{} {} after message {}", delta, tag, anchor + 1)
+                    }
+                    (Locale::En, false) => {
+                        format!("−{} {} after message {}", -delta, tag, anchor + 1)
+                    }
+                };
+                parts.push((None, vec![text]));
+            }
+        }
+    }
+    let separator = match locale {
+        Locale::Ja => "、",
+        Locale::En => ", ",
+    };
+    parts
+        .into_iter()
+        .map(|(target, values)| match target {
+            Some(target) => format!(
+                "{}: {}",
+                style_target_name(locale, target),
+                values.join(separator)
+            ),
+            None => values.join(separator),
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+fn style_target_name(locale: Locale, target: &StyleTarget) -> String {
+    match (locale, target) {
+        (Locale::Ja, StyleTarget::Container) => "チャット全体".to_string(),
+        (Locale::Ja, StyleTarget::UserMessages) => "自分のメッセージ".to_string(),
+        (Locale::Ja, StyleTarget::AiMessages) => "AIのメッセージ".to_string(),
+        (Locale::Ja, StyleTarget::Chrome) => "入力欄".to_string(),
+        (Locale::Ja, StyleTarget::Message(id)) => format!("メッセージ{}", id + 1),
+        (Locale::En, StyleTarget::Container) => "chat".to_string(),
+        (Locale::En, StyleTarget::UserMessages) => "your messages".to_string(),
+        (Locale::En, StyleTarget::AiMessages) => "AI messages".to_string(),
+        (Locale::En, StyleTarget::Chrome) => "input bar".to_string(),
+        (Locale::En, StyleTarget::Message(id)) => format!("message {}", id + 1),
+    }
+}
+
+/// フッターに表示する使用量（直前のターンとセッション合計）
+pub fn usage_summary(locale: Locale, totals: &UsageTotals) -> String {
+    let last = totals.last.as_ref().map(|u| {
+        let cost = u.cost_usd.unwrap_or_default();
+        match locale {
+            Locale::Ja => format!(
+                "今回: 入力 {} / 出力 {} トークン（${:.5}） ・ ",
+                u.prompt_tokens, u.completion_tokens, cost
+            ),
+            Locale::En => format!(
+                "This turn: {} in / {} out tokens (${:.5}) · ",
+                u.prompt_tokens, u.completion_tokens, cost
+            ),
+        }
+    });
+    let estimated = match (totals.estimated, locale) {
+        (false, _) => "",
+        (true, Locale::Ja) => " ※推定を含む",
+        (true, Locale::En) => " *includes estimates",
+    };
+    match locale {
+        Locale::Ja => format!(
+            "{}合計 {}回: 入力 {} / 出力 {} トークン（${:.5}）{}",
+            last.unwrap_or_default(),
+            totals.turns,
+            totals.prompt_tokens,
+            totals.completion_tokens,
+            totals.cost_usd,
+            estimated
+        ),
+        Locale::En => format!(
+            "{}Total over {} turns: {} in / {} out tokens (${:.5}){}",
+            last.unwrap_or_default(),
+            totals.turns,
+            totals.prompt_tokens,
+            totals.completion_tokens,
+            totals.cost_usd,
+            estimated
+        ),
+    }
+}
+
+/// コントラストの調整・警告をAIのメッセージに添える注記
+#[cfg(feature = "ssr")]
+pub fn contrast_note(
+    locale: Locale,
+    count: usize,
+    adjusted: bool,
+    worst_ratio: f64,
+    policy: &str,
+) -> String {
+    match (locale, adjusted) {
+        (Locale::Ja, true) => format!(
+            "（読みやすさのため、{}件の要素の文字色をWCAG {}基準に合わせて調整しました）",
+            count, policy
+        ),
+        (Locale::Ja, false) => format!(
+            "（注意: {}件の要素で文字色と背景色のコントラスト比が{:.1}:1まで下がり、WCAG {}基準を満たしていません）",
+            count, worst_ratio, policy
+        ),
+        (Locale::En, true) => format!(
+            " (For readability, the text color of {} element(s) was adjusted to meet WCAG {}.)",
+            count, policy
+        ),
+        (Locale::En, false) => format!(
+            " (Warning: the text/background contrast of {} element(s) drops to {:.1}:1, which does not meet WCAG {}.)",
+            count, worst_ratio, policy
+        ),
+    }
+}
+
+/// ユーザーに表示するエラーの文言
+pub fn error_message(locale: Locale, error: &SendMessageError) -> String {
+    if locale == Locale::Ja {
+        return error.to_string();
+    }
+    match error {
+        SendMessageError::InvalidRequest(rejection) => rejection_message(rejection),
+        SendMessageError::ModelResponse(failure) => match failure {
+            ResponseFailure::PromptBlocked { .. } => {
+                "The request was blocked by the safety filter. Please rephrase it and try again."
+            }
+            ResponseFailure::SafetyBlocked { .. } => {
+                "The AI response was blocked by the safety filter, so the UI was not changed. Please rephrase your request and try again."
+            }
+            ResponseFailure::Truncated => {
+                "The AI response was too long and got cut off, so the UI was not changed. Please split the change into smaller requests."
+            }
+            ResponseFailure::Recitation => {
+                "The AI response was flagged as reciting existing text, so the UI was not changed. Please phrase your request differently."
+            }
+            ResponseFailure::Empty => {
+                "The AI returned an empty response, so the UI was not changed. Please try again."
+            }
+            ResponseFailure::Other { .. } => {
+                "The AI response ended unexpectedly, so the UI was not changed. Please try again."
+            }
+        }
+        .to_string(),
+        SendMessageError::Internal(_) | SendMessageError::ServerFn(_) => {
+            "Something went wrong on the server. Please try again.".to_string()
+        }
+    }
+}
+
+/// 上限や整合性の違反の英語の文言
+fn rejection_message(rejection: &RequestRejection) -> String {
+    match rejection {
+        RequestRejection::EmptyText => "The message is empty.".to_string(),
+        RequestRejection::TextTooLong { chars, max } => format!(
+            "The message is too long ({} characters, limit {}).",
+            chars, max
+        ),
+        RequestRejection::TooManyMessages { count, max } => format!(
+            "The conversation has too many messages ({}, limit {}).",
+            count, max
+        ),
+        RequestRejection::HistoryTooLarge { bytes, max } => format!(
+            "The conversation is too large ({} bytes, limit {}).",
+            bytes, max
+        ),
+        RequestRejection::UiStateTooLarge { bytes, max } => format!(
+            "The UI state is too large ({} bytes, limit {}).",
+            bytes, max
+        ),
+        RequestRejection::NonIncreasingId { id, previous } => format!(
+            "Message IDs are not in ascending order (ID {} comes after ID {}).",
+            id, previous
+        ),
+        RequestRejection::UnexpectedAiMessage { id } => format!(
+            "The AI message with ID {} is not preceded by a user message.",
+            id
+        ),
+        RequestRejection::LastMessageMismatch => {
+            "The last message in the conversation does not match the message sent.".to_string()
+        }
+    }
+}
+
+/// UIドキュメントを読み込めなかった理由の文言
+pub fn import_error_message(locale: Locale, error: &ImportError) -> String {
+    if locale == Locale::Ja {
+        return error.to_string();
+    }
+    match error {
+        ImportError::InvalidDocument(reason) => {
+            format!("The UI document could not be read: {}", reason)
+        }
+        ImportError::UnknownFormat => "This is not a self-changer UI document.".to_string(),
+        ImportError::UnsupportedVersion { version, supported } => format!(
+            "UI document version {} is not supported (up to {}).",
+            version, supported
+        ),
+        ImportError::Rejected(rejection) => rejection_message(rejection),
+        ImportError::ServerFn(_) => {
+            "Something went wrong on the server. Please try again.".to_string()
+        }
+    }
+}
+
+/// 共有リンクを作れなかった理由の文言
+pub fn share_error_message(locale: Locale, error: &ShareError) -> String {
+    if locale == Locale::Ja {
+        return error.to_string();
+    }
+    match error {
+        ShareError::Unavailable => {
+            "Share links require server-side storage (DATABASE_PATH).".to_string()
+        }
+        ShareError::Rejected(rejection) => rejection_message(rejection),
+        ShareError::Storage(_) | ShareError::ServerFn(_) => {
+            "Something went wrong on the server. Please try again.".to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_accept_language() {
+        assert_eq!(negotiate("en-US,en;q=0.9,ja;q=0.8"), Locale::En);
+        assert_eq!(negotiate("fr-FR, ja;q=0.5, en;q=0.7"), Locale::En);
+        assert_eq!(negotiate("ja-JP"), Locale::Ja);
+        assert_eq!(negotiate("en;q=0, ja;q=0.1"), Locale::Ja);
+        assert_eq!(negotiate("de, fr"), Locale::Ja);
+        assert_eq!(negotiate(""), Locale::Ja);
+        assert_eq!(Locale::from_tag("EN_gb"), Some(Locale::En));
+    }
+
+    #[test]
+    fn test_error_messages_follow_locale() {
+        let error = SendMessageError::InvalidRequest(RequestRejection::TextTooLong {
+            chars: 3000,
+            max: 2000,
+        });
+        assert_eq!(error_message(Locale::Ja, &error), error.to_string());
+        assert_eq!(
+            error_message(Locale::En, &error),
+            "The message is too long (3000 characters, limit 2000)."
+        );
+
+        let totals = UsageTotals {
+            turns: 2,
+            prompt_tokens: 10,
+            completion_tokens: 5,
+            ..Default::default()
+        };
+        assert!(usage_summary(Locale::En, &totals).starts_with("Total over 2 turns"));
+        assert!(usage_summary(Locale::Ja, &totals).starts_with("合計 2回"));
+    }
+
+    #[test]
+    fn test_describe_changes() {
+        let changes = [
+            Change::Style {
+                target: StyleTarget::Message(1),
+                property: "background-color".to_string(),
+                before: Some("#e5e7eb".to_string()),
+                after: Some("#3b82f6".to_string()),
+            },
+            Change::Style {
+                target: StyleTarget::Message(1),
+                property: "border".to_string(),
+                before: None,
+                after: Some("1px solid".to_string()),
+            },
+            Change::Elements {
+                anchor: 2,
+                tag: "button".to_string(),
+                delta: 1,
+            },
+        ];
+        assert_eq!(
+            describe_changes(Locale::En, &changes),
+            "message 2: background-color #e5e7eb → #3b82f6, +border 1px solid; +1 button after message 3"
+        );
+        assert_eq!(
+            describe_changes(Locale::Ja, &changes),
+            "メッセージ2: background-color #e5e7eb → #3b82f6、+border 1px solid; メッセージ3の後にbuttonを1つ追加"
+        );
+        assert_eq!(describe_changes(Locale::En, &[]), "No visual changes");
+    }
+}
@@ -0,0 +1,115 @@
+use common::DynamicElementData;
+
+/// サーバーの画像プロキシのパス（server クレートの img_proxy::ROUTE と対応）
+pub const IMG_PROXY_PATH: &str = "/img-proxy";
+
+/// 外部画像のURLを画像プロキシ経由のパスに変換する。
+/// プロキシは https のみ取得するため、http は https に置き換える。
+/// 変換済みのsrc（`/img-proxy?url=` で始まるもの）はそのまま返し、
+/// http(s) 以外（data:, javascript:, 他のパス等）は None を返す
+pub fn proxied_src(src: &str) -> Option<String> {
+    let src = src.trim();
+    if src
+        .strip_prefix(IMG_PROXY_PATH)
+        .is_some_and(|rest| rest.starts_with("?url="))
+    {
+        return Some(src.to_string());
+    }
+    let lower = src.to_ascii_lowercase();
+    let url = if lower.starts_with("https://") {
+        src.to_string()
+    } else if lower.starts_with("http://") {
+        format!("https://{}", &src["http://".len()..])
+    } else {
+        return None;
+    };
+    Some(format!("{}?url={}", IMG_PROXY_PATH, percent_encode(&url)))
+}
+
+/// img要素のsrcを画像プロキシ経由に書き換える。変換できないsrcは削除する
+pub fn rewrite_image_sources(elements: &mut [DynamicElementData]) {
+    for element in elements.iter_mut().filter(|e| e.tag == "img") {
+        if let Some(attrs) = element.attributes.as_mut() {
+            match attrs.get("src").and_then(|src| proxied_src(src)) {
+                Some(src) => {
+                    attrs.insert("src".to_string(), src);
+                }
+                None => {
+                    attrs.remove("src");
+                }
+            }
+        }
+    }
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_proxied_src() {
+        assert_eq!(
+            proxied_src("https://picsum.photos/300/200?random=1").as_deref(),
+            Some("/img-proxy?url=https%3A%2F%2Fpicsum.photos%2F300%2F200%3Frandom%3D1")
+        );
+        // プロキシは https のみ取得するため、http は https にする
+        assert_eq!(
+            proxied_src("HTTP://cataas.com/cat").as_deref(),
+            Some("/img-proxy?url=https%3A%2F%2Fcataas.com%2Fcat")
+        );
+        assert_eq!(proxied_src("javascript:alert(1)"), None);
+        assert_eq!(proxied_src("data:image/png;base64,AAAA"), None);
+        // 変換済みのsrcは二重に変換しない
+        let once = proxied_src("https://cataas.com/cat").unwrap();
+        assert_eq!(proxied_src(&once), Some(once));
+        // プロキシのパスに似ているだけのsrcは通さない
+        assert_eq!(proxied_src("/img-proxy/../api/delete_session"), None);
+        assert_eq!(proxied_src("/img-proxyfoo?url=x"), None);
+        assert_eq!(proxied_src("/img-proxy?other=x"), None);
+    }
+
+    #[test]
+    fn test_rewrite_image_sources() {
+        let mut elements = vec![
+            DynamicElementData {
+                id: 0,
+                tag: "img".to_string(),
+                text: None,
+                styles: None,
+                attributes: Some(HashMap::from([
+                    ("src".to_string(), "https://cataas.com/cat".to_string()),
+                    ("alt".to_string(), "猫".to_string()),
+                ])),
+            },
+            DynamicElementData {
+                id: 1,
+                tag: "a".to_string(),
+                text: Some("リンク".to_string()),
+                styles: None,
+                attributes: Some(HashMap::from([(
+                    "href".to_string(),
+                    "https://example.com".to_string(),
+                )])),
+            },
+        ];
+        rewrite_image_sources(&mut elements);
+
+        let img = elements[0].attributes.as_ref().unwrap();
+        assert_eq!(img["src"], "/img-proxy?url=https%3A%2F%2Fcataas.com%2Fcat");
+        assert_eq!(img["alt"], "猫");
+        let link = elements[1].attributes.as_ref().unwrap();
+        assert_eq!(link["href"], "https://example.com");
+    }
+}
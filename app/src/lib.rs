@@ -1,20 +1,62 @@
+#[cfg(feature = "ssr")]
+mod ai_output;
 pub mod api;
 mod api_client;
+mod base_styles;
+mod branches;
+#[cfg(feature = "ssr")]
+mod context_window;
+#[cfg(feature = "ssr")]
+mod contrast;
 mod css_sanitizer;
+mod errors;
+pub mod export;
+#[cfg(feature = "ssr")]
+mod few_shot;
+#[cfg(feature = "ssr")]
+mod generation;
+mod i18n;
+#[cfg(feature = "ssr")]
+pub mod image_rewrite;
+mod local_store;
+#[cfg(feature = "ssr")]
+mod model_response;
 mod pages;
+#[cfg(feature = "ssr")]
+mod prompt;
+#[cfg(feature = "ssr")]
+pub mod prompt_templates;
+#[cfg(feature = "ssr")]
+mod request_validation;
+mod sessions;
+#[cfg(feature = "ssr")]
+pub mod storage;
+mod themes;
+pub mod ui_document;
+#[cfg(feature = "ssr")]
+mod usage;
+pub mod versions;
 use crate::pages::chat_page::ChatPage;
+use crate::pages::shared_page::SharedPage;
 
 use leptos::prelude::*;
 use leptos_meta::{provide_meta_context, MetaTags, Stylesheet, Title};
 use leptos_router::{
     components::{Route, Router, Routes},
-    StaticSegment,
+    ParamSegment, StaticSegment,
 };
 
+/// シェルで使われたCSP nonceをサーバーのセキュリティヘッダー層へ伝える内部ヘッダー
+pub const CSP_NONCE_HEADER: &str = "x-csp-nonce";
+
 pub fn shell(options: LeptosOptions) -> impl IntoView {
+    #[cfg(feature = "ssr")]
+    publish_csp_nonce();
+    let locale = i18n::detect_locale();
+
     view! {
         <!DOCTYPE html>
-        <html lang="ja">
+        <html lang=locale.code()>
             <head>
                 <meta charset="utf-8"/>
                 <meta name="viewport" content="width=device-width, initial-scale=1, maximum-scale=1, user-scalable=no, viewport-fit=cover"/>
@@ -29,10 +71,29 @@ pub fn shell(options: LeptosOptions) -> impl IntoView {
     }
 }
 
+/// HydrationScripts が使うリクエストごとのnonceをレスポンスヘッダーに載せる
+/// （ヘッダー層がCSPを組み立てた後に取り除く）
+#[cfg(feature = "ssr")]
+fn publish_csp_nonce() {
+    use http::{HeaderName, HeaderValue};
+    use leptos::nonce::use_nonce;
+    use leptos_axum::ResponseOptions;
+
+    if let (Some(nonce), Some(res)) = (use_nonce(), use_context::<ResponseOptions>()) {
+        if let Ok(value) = HeaderValue::from_str(&nonce) {
+            res.insert_header(HeaderName::from_static(CSP_NONCE_HEADER), value);
+        }
+    }
+}
+
 #[component]
 pub fn App() -> impl IntoView {
     // Provides context that manages stylesheets, titles, meta tags, etc.
     provide_meta_context();
+    // UIの言語（サーバーでは Accept-Language、ブラウザでは <html lang> から判定）
+    let locale = i18n::detect_locale();
+    provide_context(locale);
+    let not_found = i18n::text(locale).not_found;
 
     view! {
         <Stylesheet id="leptos" href="/pkg/self-changer.css"/>
@@ -43,8 +104,9 @@ pub fn App() -> impl IntoView {
         // content for this welcome page
         <Router>
             <main class="w-full h-screen flex flex-col items-center justify-center bg-gray-200 py-2">
-                <Routes fallback=|| "ページが見つかりません".into_view()>
+                <Routes fallback=move || not_found.into_view()>
                     <Route path=StaticSegment("") view=ChatPage/>
+                    <Route path=(StaticSegment("s"), ParamSegment("id")) view=SharedPage/>
                 </Routes>
             </main>
         </Router>
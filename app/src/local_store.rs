@@ -0,0 +1,403 @@
+//! ブラウザの localStorage への会話とUI状態の保存
+//!
+//! データベースのないデプロイでも再読み込みで会話が消えないよう、名前付きセッションの
+//! 一覧と、セッションごとの会話とUI状態と版の履歴、テーマのライブラリをこちらに保存する。
+//! 形式を変えたときはバージョンを上げ、古い保存内容を `migrate` で変換する
+//! （変換できないものは破棄する）。
+
+use common::{SessionSnapshot, SessionSummary, SessionVersion, Theme};
+use serde::{Deserialize, Serialize};
+
+/// 名前付きセッション以前に、1つだけの会話を保存していたキー
+const LEGACY_KEY: &str = "self-changer.session";
+
+/// セッションの一覧を保存するキー
+const INDEX_KEY: &str = "self-changer.sessions";
+
+/// 最後に開いていたセッションのIDを保存するキー
+const ACTIVE_KEY: &str = "self-changer.active-session";
+
+/// テーマのライブラリを保存するキー
+const THEMES_KEY: &str = "self-changer.themes";
+
+/// セッションの版の履歴を保存するキーの接頭辞（後ろにセッションIDを付ける）
+const VERSIONS_KEY: &str = "self-changer.versions";
+
+/// 保存形式のバージョン
+const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct StoredSnapshot {
+    version: u32,
+    snapshot: SessionSnapshot,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredIndex {
+    version: u32,
+    sessions: Vec<SessionSummary>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredThemes {
+    version: u32,
+    themes: Vec<Theme>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredVersions {
+    version: u32,
+    versions: Vec<SessionVersion>,
+}
+
+// セッションの会話とUI状態を保存するキー
+fn session_key(session_id: &str) -> String {
+    format!("{}.{}", LEGACY_KEY, session_id)
+}
+
+fn versions_key(session_id: &str) -> String {
+    format!("{}.{}", VERSIONS_KEY, session_id)
+}
+
+/// 保存する文字列
+fn encode(snapshot: &SessionSnapshot) -> Option<String> {
+    serde_json::to_string(&StoredSnapshot {
+        version: SCHEMA_VERSION,
+        snapshot: snapshot.clone(),
+    })
+    .ok()
+}
+
+/// 保存された文字列を現在の形式で読む。不正なものや未知のバージョンは None
+fn decode(raw: &str) -> Option<SessionSnapshot> {
+    let value: serde_json::Value = serde_json::from_str(raw).ok()?;
+    let version = value.get("version")?.as_u64()?;
+    migrate(version, value)
+}
+
+// バージョンごとの変換（形式を変えたら、古いバージョンの分岐をここに追加する）
+fn migrate(version: u64, value: serde_json::Value) -> Option<SessionSnapshot> {
+    match version {
+        v if v == SCHEMA_VERSION as u64 => serde_json::from_value::<StoredSnapshot>(value)
+            .ok()
+            .map(|stored| stored.snapshot),
+        _ => None,
+    }
+}
+
+fn encode_index(sessions: &[SessionSummary]) -> Option<String> {
+    serde_json::to_string(&StoredIndex {
+        version: SCHEMA_VERSION,
+        sessions: sessions.to_vec(),
+    })
+    .ok()
+}
+
+fn decode_index(raw: &str) -> Option<Vec<SessionSummary>> {
+    serde_json::from_str::<StoredIndex>(raw)
+        .ok()
+        .filter(|index| index.version == SCHEMA_VERSION)
+        .map(|index| index.sessions)
+}
+
+// 保存したセッションを一覧の先頭に移し、保存時刻を更新する
+fn touch(sessions: &mut Vec<SessionSummary>, session_id: &str, now: i64) {
+    if let Some(position) = sessions.iter().position(|s| s.id == session_id) {
+        let mut session = sessions.remove(position);
+        session.updated_at = now;
+        sessions.insert(0, session);
+    }
+}
+
+/// 保存されているセッションの一覧（新しく保存した順）。
+/// 以前の形式で保存された会話があれば、名前なしのセッションに移す
+pub fn list() -> Vec<SessionSummary> {
+    if let Some(raw) = get_item(INDEX_KEY) {
+        if let Some(sessions) = decode_index(&raw) {
+            return sessions;
+        }
+        log::warn!("localStorage のセッション一覧を読めないため作り直します");
+    }
+    let mut sessions = Vec::new();
+    if let Some(snapshot) = get_item(LEGACY_KEY).and_then(|raw| decode(&raw)) {
+        sessions.push(write_new("", None, &snapshot));
+        save_index(&sessions);
+    }
+    remove_item(LEGACY_KEY);
+    sessions
+}
+
+/// 名前を付けて新しいセッションを保存する
+pub fn create(name: &str, snapshot: &SessionSnapshot) -> SessionSummary {
+    insert_new(write_new(name, None, snapshot))
+}
+
+/// セッションのメッセージから分岐した会話とUI状態を、名前を付けて保存する
+pub fn create_fork(
+    name: &str,
+    parent_id: &str,
+    message_id: usize,
+    snapshot: &SessionSnapshot,
+) -> SessionSummary {
+    insert_new(write_new(name, Some((parent_id, message_id)), snapshot))
+}
+
+fn insert_new(session: SessionSummary) -> SessionSummary {
+    let mut sessions = list();
+    sessions.insert(0, session.clone());
+    save_index(&sessions);
+    session
+}
+
+/// セッションの会話とUI状態を読み込む。読めない保存内容は削除する
+pub fn load(session_id: &str) -> Option<SessionSnapshot> {
+    let key = session_key(session_id);
+    let raw = get_item(&key)?;
+    let snapshot = decode(&raw);
+    if snapshot.is_none() {
+        log::warn!("localStorage の保存内容を読めないため破棄します");
+        remove_item(&key);
+    }
+    snapshot
+}
+
+/// セッションの会話とUI状態を保存する（変わっていなければ何もしない）
+pub fn save(session_id: &str, snapshot: &SessionSnapshot) {
+    if load(session_id).as_ref() == Some(snapshot) {
+        return;
+    }
+    let Some(raw) = encode(snapshot) else {
+        return;
+    };
+    set_item(&session_key(session_id), &raw);
+    let mut sessions = list();
+    touch(&mut sessions, session_id, now());
+    save_index(&sessions);
+}
+
+pub fn rename(session_id: &str, name: &str) {
+    let mut sessions = list();
+    if let Some(session) = sessions.iter_mut().find(|s| s.id == session_id) {
+        session.name = name.to_string();
+        save_index(&sessions);
+    }
+}
+
+pub fn delete(session_id: &str) {
+    let mut sessions = list();
+    sessions.retain(|s| s.id != session_id);
+    save_index(&sessions);
+    remove_item(&session_key(session_id));
+    remove_item(&versions_key(session_id));
+}
+
+/// 最後に開いていたセッションのID
+pub fn active() -> Option<String> {
+    get_item(ACTIVE_KEY)
+}
+
+pub fn set_active(session_id: &str) {
+    set_item(ACTIVE_KEY, session_id);
+}
+
+/// セッションの版の一覧（古い順）。読めない保存内容は空として扱う
+pub fn list_versions(session_id: &str) -> Vec<SessionVersion> {
+    get_item(&versions_key(session_id))
+        .and_then(|raw| serde_json::from_str::<StoredVersions>(&raw).ok())
+        .filter(|stored| stored.version == SCHEMA_VERSION)
+        .map(|stored| stored.versions)
+        .unwrap_or_default()
+}
+
+/// 会話とUI状態を新しい版として記録する
+pub fn save_version(
+    session_id: &str,
+    snapshot: &SessionSnapshot,
+    restored_from: Option<u32>,
+) -> SessionVersion {
+    let mut versions = list_versions(session_id);
+    let saved = crate::versions::push_version(&mut versions, snapshot, restored_from, now());
+    if let Ok(raw) = serde_json::to_string(&StoredVersions {
+        version: SCHEMA_VERSION,
+        versions,
+    }) {
+        set_item(&versions_key(session_id), &raw);
+    }
+    saved
+}
+
+/// 保存されているテーマ（名前順）。読めない保存内容は空として扱う
+pub fn list_themes() -> Vec<Theme> {
+    get_item(THEMES_KEY)
+        .and_then(|raw| serde_json::from_str::<StoredThemes>(&raw).ok())
+        .filter(|stored| stored.version == SCHEMA_VERSION)
+        .map(|stored| stored.themes)
+        .unwrap_or_default()
+}
+
+/// テーマを保存する（同じ名前のテーマは置き換える）
+pub fn save_theme(theme: &Theme) -> Theme {
+    let theme = Theme {
+        updated_at: now(),
+        ..theme.clone()
+    };
+    let mut themes = list_themes();
+    crate::themes::upsert(&mut themes, theme.clone());
+    save_themes(themes);
+    theme
+}
+
+pub fn delete_theme(name: &str) {
+    let mut themes = list_themes();
+    themes.retain(|t| t.name != name);
+    save_themes(themes);
+}
+
+fn save_themes(themes: Vec<Theme>) {
+    if let Ok(raw) = serde_json::to_string(&StoredThemes {
+        version: SCHEMA_VERSION,
+        themes,
+    }) {
+        set_item(THEMES_KEY, &raw);
+    }
+}
+
+// 新しいIDで会話とUI状態を保存する（一覧には加えない）
+fn write_new(
+    name: &str,
+    parent: Option<(&str, usize)>,
+    snapshot: &SessionSnapshot,
+) -> SessionSummary {
+    let session = SessionSummary {
+        id: new_local_id(),
+        name: name.to_string(),
+        updated_at: now(),
+        parent_id: parent.map(|(id, _)| id.to_string()),
+        forked_at: parent.map(|(_, message_id)| message_id),
+    };
+    if let Some(raw) = encode(snapshot) {
+        set_item(&session_key(&session.id), &raw);
+    }
+    session
+}
+
+fn save_index(sessions: &[SessionSummary]) {
+    if let Some(raw) = encode_index(sessions) {
+        set_item(INDEX_KEY, &raw);
+    }
+}
+
+// ブラウザ内だけで使うID（推測されても困らないため、時刻と乱数で作る）
+fn new_local_id() -> String {
+    #[cfg(not(feature = "ssr"))]
+    let random = (js_sys::Math::random() * f64::from(u32::MAX)) as u32;
+    #[cfg(feature = "ssr")]
+    let random = 0u32;
+    format!("{:x}{:08x}", now(), random)
+}
+
+// 現在のUNIX時刻（秒）
+fn now() -> i64 {
+    #[cfg(not(feature = "ssr"))]
+    {
+        (js_sys::Date::now() / 1000.0) as i64
+    }
+    #[cfg(feature = "ssr")]
+    {
+        crate::storage::unix_now()
+    }
+}
+
+// 以下は localStorage の読み書き（サーバーでは何もしない）
+
+fn get_item(key: &str) -> Option<String> {
+    #[cfg(not(feature = "ssr"))]
+    {
+        storage().and_then(|s| s.get_item(key).ok().flatten())
+    }
+    #[cfg(feature = "ssr")]
+    {
+        let _ = key;
+        None
+    }
+}
+
+fn set_item(key: &str, value: &str) {
+    #[cfg(not(feature = "ssr"))]
+    if let Some(storage) = storage() {
+        if let Err(e) = storage.set_item(key, value) {
+            // 容量超過などで保存できなくても、画面の操作は続けられる
+            log::warn!("localStorage に保存できません: {:?}", e);
+        }
+    }
+    #[cfg(feature = "ssr")]
+    let _ = (key, value);
+}
+
+fn remove_item(key: &str) {
+    #[cfg(not(feature = "ssr"))]
+    if let Some(storage) = storage() {
+        let _ = storage.remove_item(key);
+    }
+    #[cfg(feature = "ssr")]
+    let _ = key;
+}
+
+#[cfg(not(feature = "ssr"))]
+fn storage() -> Option<web_sys::Storage> {
+    // プライベートブラウズなどでは使えないことがある
+    leptos::prelude::window().local_storage().ok().flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::MessageInfo;
+
+    #[test]
+    fn test_round_trip_and_unknown_versions() {
+        let mut snapshot = SessionSnapshot::default();
+        snapshot.messages.push(MessageInfo {
+            id: 0,
+            is_user: true,
+            text: "背景を青くして".to_string(),
+            ops: None,
+        });
+        snapshot.ui_state.chat_container_styles = "background: blue;".to_string();
+
+        let raw = encode(&snapshot).unwrap();
+        assert_eq!(decode(&raw), Some(snapshot));
+
+        // 未知のバージョン、バージョンのないもの、壊れたものは破棄する
+        let future = raw.replace("\"version\":1", "\"version\":99");
+        assert_eq!(decode(&future), None);
+        assert_eq!(decode("{\"snapshot\":{}}"), None);
+        assert_eq!(decode("{\"version\":1,\"snapshot\":"), None);
+    }
+
+    #[test]
+    fn test_index_round_trip_and_touch() {
+        let session = |id: &str, updated_at| SessionSummary {
+            id: id.to_string(),
+            name: format!("{}の実験", id),
+            updated_at,
+            parent_id: None,
+            forked_at: None,
+        };
+        let mut sessions = vec![session("a", 2), session("b", 1)];
+        let raw = encode_index(&sessions).unwrap();
+        assert_eq!(decode_index(&raw), Some(sessions.clone()));
+        assert_eq!(
+            decode_index(&raw.replace("\"version\":1", "\"version\":99")),
+            None
+        );
+
+        // 保存したセッションが先頭に来る
+        touch(&mut sessions, "b", 3);
+        assert_eq!(sessions, vec![session("b", 3), session("a", 2)]);
+        touch(&mut sessions, "missing", 4);
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(session_key("b"), "self-changer.session.b");
+        assert_eq!(versions_key("b"), "self-changer.versions.b");
+    }
+}
@@ -0,0 +1,150 @@
+//! モデルの応答からテキストを取り出し、使えない応答をその理由ごとに分類する
+
+use crate::errors::ResponseFailure;
+use google_ai_rs::proto::candidate::FinishReason;
+use google_ai_rs::proto::generate_content_response::prompt_feedback::BlockReason;
+use google_ai_rs::proto::{Candidate, GenerateContentResponse};
+
+/// 応答のすべての候補のうち、使えるもののテキストを返す。
+/// 1件も使えない場合は先頭の候補の理由を返す
+pub fn response_texts(response: &GenerateContentResponse) -> Result<Vec<String>, ResponseFailure> {
+    // リクエスト自体がブロックされた場合は候補が返らない
+    if let Some(feedback) = &response.prompt_feedback {
+        let reason = BlockReason::try_from(feedback.block_reason).unwrap_or(BlockReason::Other);
+        if reason != BlockReason::Unspecified {
+            return Err(ResponseFailure::PromptBlocked {
+                reason: reason.as_str_name().to_string(),
+            });
+        }
+    }
+    let mut first_failure = None;
+    let mut texts = Vec::new();
+    for candidate in &response.candidates {
+        match candidate_text(candidate) {
+            Ok(text) => texts.push(text),
+            Err(failure) => {
+                first_failure.get_or_insert(failure);
+            }
+        }
+    }
+    if texts.is_empty() {
+        return Err(first_failure.unwrap_or(ResponseFailure::Empty));
+    }
+    Ok(texts)
+}
+
+/// 1件の候補のテキストを終了理由に応じて取り出す
+pub fn candidate_text(candidate: &Candidate) -> Result<String, ResponseFailure> {
+    let reason = FinishReason::try_from(candidate.finish_reason).unwrap_or(FinishReason::Other);
+    match reason {
+        FinishReason::Unspecified | FinishReason::Stop => {}
+        // 出力トークンの上限で止まった場合、JSONは途中で切れている
+        FinishReason::MaxTokens => return Err(ResponseFailure::Truncated),
+        FinishReason::Safety
+        | FinishReason::Blocklist
+        | FinishReason::ProhibitedContent
+        | FinishReason::Spii
+        | FinishReason::ImageSafety => {
+            return Err(ResponseFailure::SafetyBlocked {
+                reason: reason.as_str_name().to_string(),
+            })
+        }
+        FinishReason::Recitation => return Err(ResponseFailure::Recitation),
+        _ => {
+            return Err(ResponseFailure::Other {
+                reason: reason.as_str_name().to_string(),
+            })
+        }
+    }
+    // テキストが複数のパートに分かれて返ることがあるため連結する
+    let text: String = candidate
+        .content
+        .iter()
+        .flat_map(|c| c.parts.iter())
+        .map(|p| p.to_text())
+        .collect();
+    if text.trim().is_empty() {
+        return Err(ResponseFailure::Empty);
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use google_ai_rs::proto::generate_content_response::PromptFeedback;
+    use google_ai_rs::{Content, Part};
+
+    fn candidate(reason: FinishReason, parts: &[&str]) -> Candidate {
+        Candidate {
+            finish_reason: reason as i32,
+            content: Some(Content::model(
+                parts.iter().map(|p| Part::text(*p)).collect::<Vec<_>>(),
+            )),
+            ..Default::default()
+        }
+    }
+
+    fn response(candidates: Vec<Candidate>) -> GenerateContentResponse {
+        GenerateContentResponse {
+            candidates,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_texts_from_finished_candidates() {
+        let ok = response(vec![candidate(FinishReason::Stop, &["{\"a\":", " 1}"])]);
+        assert_eq!(response_texts(&ok).unwrap(), vec!["{\"a\": 1}"]);
+
+        // 使えない候補は除き、使える候補だけを返す
+        let mixed = response(vec![
+            candidate(FinishReason::MaxTokens, &["{\"a\""]),
+            candidate(FinishReason::Stop, &["{}"]),
+        ]);
+        assert_eq!(response_texts(&mixed).unwrap(), vec!["{}"]);
+    }
+
+    #[test]
+    fn test_failures_are_classified() {
+        let cases = [
+            (FinishReason::MaxTokens, ResponseFailure::Truncated),
+            (
+                FinishReason::Safety,
+                ResponseFailure::SafetyBlocked {
+                    reason: "SAFETY".to_string(),
+                },
+            ),
+            (FinishReason::Recitation, ResponseFailure::Recitation),
+            (
+                FinishReason::Language,
+                ResponseFailure::Other {
+                    reason: "LANGUAGE".to_string(),
+                },
+            ),
+        ];
+        for (reason, expected) in cases {
+            let res = response(vec![candidate(reason, &["{\"success\": tr"])]);
+            assert_eq!(response_texts(&res), Err(expected));
+        }
+
+        assert_eq!(
+            response_texts(&response(vec![])),
+            Err(ResponseFailure::Empty)
+        );
+        let blank = response(vec![candidate(FinishReason::Stop, &["  "])]);
+        assert_eq!(response_texts(&blank), Err(ResponseFailure::Empty));
+
+        let blocked = GenerateContentResponse {
+            prompt_feedback: Some(PromptFeedback {
+                block_reason: BlockReason::Safety as i32,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(matches!(
+            response_texts(&blocked),
+            Err(ResponseFailure::PromptBlocked { .. })
+        ));
+    }
+}
@@ -1,26 +1,97 @@
+use crate::api::{import_ui_document, load_initial_state, share_snapshot};
 use crate::api_client::{send_message_to_api, ApiCallParams};
-use common::DynamicElementData;
+use crate::branches;
+use crate::export::download_html;
+use crate::i18n::{
+    duplicate_name, fork_name, import_error_message, preview_notice, share_error_message, text,
+    theme_applied_message, usage_summary,
+};
+use crate::local_store;
+use crate::pages::message_view::MessageView;
+use crate::pages::session_sidebar::SessionSidebar;
+use crate::pages::theme_panel::ThemePanel;
+use crate::pages::version_timeline::VersionTimeline;
+use crate::sessions::{self, normalize_name, Backend};
+use crate::themes::{self, parse_theme_command};
+use crate::ui_document::{download_json, read_selected_file};
+use crate::versions;
+use common::{
+    DynamicElementData, GenerationOverrides, GenerationPreset, InitialState, Locale, MessageInfo,
+    RoleStyles, SessionSnapshot, SessionSummary, SessionVersion, Theme, UiDelta, UiOperations,
+    UiState, UsageTotals,
+};
 use leptos::ev::SubmitEvent;
 use leptos::prelude::signal as leptos_signal;
 use leptos::prelude::*;
-use std::collections::HashMap;
+use leptos::server_fn::ServerFnError;
+use leptos::task::spawn_local;
+use std::collections::{BTreeMap, HashMap};
 
 // メッセージのデータを保持する構造体
 #[derive(Clone, Debug, PartialEq)]
 pub struct Message {
     pub id: usize,
     pub text: String,
-    pub is_user: bool, // true: ユーザー, false: AI/システム
+    pub is_user: bool,             // true: ユーザー, false: AI/システム
+    pub ops: Option<UiOperations>, // AIの返信で適用したUI操作
 }
 
-/// チャットUIのホームページをレンダリングします
+impl From<MessageInfo> for Message {
+    fn from(m: MessageInfo) -> Self {
+        Self {
+            id: m.id,
+            text: m.text,
+            is_user: m.is_user,
+            ops: m.ops,
+        }
+    }
+}
+
+impl From<&Message> for MessageInfo {
+    fn from(m: &Message) -> Self {
+        Self {
+            id: m.id,
+            is_user: m.is_user,
+            text: m.text.clone(),
+            ops: m.ops.clone(),
+        }
+    }
+}
+
+/// チャットUIのホームページをレンダリングします。
+/// 保存されたセッションはサーバーで読み込んで描画し、最初の表示からカスタマイズしたUIにする
 #[component]
 pub fn ChatPage() -> impl IntoView {
+    let t = text(use_context::<Locale>().unwrap_or_default());
+    // 読み込むまでレスポンスを送らず、ハイドレーション時はサーバーで読み込んだ結果を使う
+    let initial = Resource::new_blocking(|| (), |_| load_initial_state());
+    view! {
+        <Suspense fallback=move || view! { <div class="loading-text">{t.loading}</div> }>
+            {move || Suspend::new(async move {
+                let initial = initial.await.unwrap_or_else(|e| {
+                    log::error!("初期状態を読み込めません: {:?}", e);
+                    None
+                });
+                view! { <ChatView initial/> }
+            })}
+        </Suspense>
+    }
+}
+
+/// チャットUI。`initial` はサーバーで読み込んだ初期状態（保存先がなければ None で、
+/// ブラウザで保存先を調べて読み込む）
+#[component]
+fn ChatView(initial: Option<InitialState>) -> impl IntoView {
+    // 表示する言語（App が判定して提供する）
+    let locale = use_context::<Locale>().unwrap_or_default();
+    let t = text(locale);
+
     // メッセージリストの状態を管理
     let (messages, set_messages) = leptos_signal(vec![Message {
         id: 0,
-        text: "こんにちは！self changerチャットへようこそ。".to_string(),
+        text: t.welcome.to_string(),
         is_user: false,
+        ops: None,
     }]);
 
     // 新しいメッセージ入力フォームの状態を管理
@@ -39,10 +110,563 @@ pub fn ChatPage() -> impl IntoView {
     // 動的要素のスタイルを集中管理するHashMap（CSSプロパティ文字列を保持）
     let (element_styles, set_element_styles) = leptos_signal(HashMap::<usize, String>::new());
 
+    // テーマで設定した役割ごとのメッセージと入力欄のスタイル
+    let (role_styles, set_role_styles) = leptos_signal(RoleStyles::default());
+
+    // メッセージごとのUI状態の変更（分岐したときに、その時点のUI状態を求めるのに使う）
+    let (ui_history, set_ui_history) = leptos_signal(BTreeMap::<usize, UiDelta>::new());
+
+    // セッション中のトークン数と料金の合計
+    let (session_usage, set_session_usage) = leptos_signal(UsageTotals::default());
+
+    // 生成の傾向（None はサーバーの設定どおり）
+    let (preset, set_preset) = leptos_signal(None::<GenerationPreset>);
+
     // 初期の追記クラスは空（ベースクラスは描画時に付与）
     let initial_styles: HashMap<usize, String> = HashMap::new();
     set_element_styles.set(initial_styles);
 
+    // 保存・読み込みした会話とUI状態を画面に反映する（メッセージがなければ挨拶だけにする）
+    let apply_snapshot = move |snapshot: SessionSnapshot| {
+        let messages: Vec<Message> = snapshot.messages.into_iter().map(Message::from).collect();
+        set_messages.set(if messages.is_empty() {
+            vec![Message {
+                id: 0,
+                text: t.welcome.to_string(),
+                is_user: false,
+                ops: None,
+            }]
+        } else {
+            messages
+        });
+        set_chat_container_styles.set(snapshot.ui_state.chat_container_styles);
+        set_element_styles.set(snapshot.ui_state.element_styles);
+        set_dynamic_elements.set(snapshot.ui_state.dynamic_elements);
+        set_role_styles.set(snapshot.ui_state.role_styles);
+        set_ui_history.set(snapshot.ui_history);
+    };
+
+    // 現在の会話とUI状態（書き出し用）
+    let current_snapshot = move || {
+        let mut snapshot = SessionSnapshot {
+            messages: messages.with_untracked(|msgs| msgs.iter().map(MessageInfo::from).collect()),
+            ui_state: UiState {
+                chat_container_styles: chat_container_styles.get_untracked(),
+                element_styles: element_styles.get_untracked(),
+                dynamic_elements: dynamic_elements.get_untracked(),
+                role_styles: role_styles.get_untracked(),
+            },
+            ui_history: ui_history.get_untracked(),
+        };
+        branches::record_history(&mut snapshot);
+        snapshot
+    };
+
+    // テーマを現在のスタイルに重ねて画面に反映する
+    let apply_theme = move |theme: &Theme| {
+        let mut state = current_snapshot().ui_state;
+        state.apply_theme(theme);
+        set_chat_container_styles.set(state.chat_container_styles);
+        set_element_styles.set(state.element_styles);
+        set_role_styles.set(state.role_styles);
+    };
+
+    // セッションの保存先（一覧を読み込むまでは None で、その間は保存しない）
+    let (backend, set_backend) = leptos_signal(None::<Backend>);
+
+    // 名前付きセッションの一覧と、表示しているセッションのID
+    let (session_list, set_session_list) = leptos_signal(Vec::<SessionSummary>::new());
+    let (active_session, set_active_session) = leptos_signal(None::<String>);
+
+    // セッション一覧を開いているか
+    let (sidebar_open, set_sidebar_open) = leptos_signal(false);
+
+    // 保存したテーマの一覧と、テーマの一覧を開いているか
+    let (theme_list, set_theme_list) = leptos_signal(Vec::<Theme>::new());
+    let (themes_open, set_themes_open) = leptos_signal(false);
+
+    // 表示しているセッションの版の一覧と、履歴を開いているか
+    let (version_list, set_version_list) = leptos_signal(Vec::<SessionVersion>::new());
+    let (timeline_open, set_timeline_open) = leptos_signal(false);
+
+    // プレビュー中の版の番号と、プレビュー前の会話とUI状態（プレビュー中は保存も送信もしない）
+    let (previewing, set_previewing) = leptos_signal(None::<u32>);
+    let (before_preview, set_before_preview) = leptos_signal(None::<SessionSnapshot>);
+
+    // プレビューをやめる（画面は呼び出し側で置き換える）
+    let leave_preview = move || {
+        set_previewing.set(None);
+        set_before_preview.set(None);
+    };
+
+    // セッションを画面に表示し、次回開くセッションとして覚える
+    let show_session = move |session_id: String, snapshot: Option<SessionSnapshot>| {
+        leave_preview();
+        apply_snapshot(snapshot.unwrap_or_default());
+        set_session_usage.set(UsageTotals::default());
+        local_store::set_active(&session_id);
+        set_active_session.set(Some(session_id));
+    };
+
+    // セッションの操作に失敗したことを知らせる
+    let session_failed = move |e: ServerFnError| {
+        log::error!("セッションを操作できません: {:?}", e);
+        let _ = window().alert_with_message(t.session_error);
+    };
+
+    // テーマの操作に失敗したことを知らせる
+    let theme_failed = move |e: ServerFnError| {
+        log::error!("テーマを操作できません: {:?}", e);
+        let _ = window().alert_with_message(t.theme_error);
+    };
+
+    // 現在の会話とUI状態を版として記録する（サーバーは保存のたびに記録する）
+    let record_version = move || {
+        if let (Some(found), Some(session_id)) =
+            (backend.get_untracked(), active_session.get_untracked())
+        {
+            versions::record(found, &session_id, &current_snapshot());
+        }
+    };
+
+    // クライアントで変えた会話とUI状態を保存し、版として記録する
+    // （localStorage にはエフェクトが保存する）
+    let save_current = move || {
+        if let (Some(found), Some(session_id)) =
+            (backend.get_untracked(), active_session.get_untracked())
+        {
+            let snapshot = current_snapshot();
+            versions::record(found, &session_id, &snapshot);
+            spawn_local(async move {
+                if let Err(e) = sessions::save(found, session_id, snapshot).await {
+                    log::error!("セッションを保存できません: {:?}", e);
+                }
+            });
+        }
+    };
+
+    // セッションを読み込んで表示する（読み込めなければ空のセッションとして表示する）
+    let open_session = move |found: Backend, session_id: String| async move {
+        let snapshot = sessions::load(found, session_id.clone())
+            .await
+            .unwrap_or_else(|e| {
+                log::error!("セッションを読み込めません: {:?}", e);
+                None
+            });
+        show_session(session_id, snapshot);
+    };
+
+    // サーバーで読み込んだ初期状態があれば、そのまま表示する（サーバーとブラウザで同じ描画になる）
+    if let Some(initial) = initial {
+        apply_snapshot(initial.snapshot);
+        set_session_list.set(initial.sessions);
+        set_active_session.set(initial.active);
+        set_backend.set(Some(Backend::Server));
+    }
+
+    // 保存先とセッションの一覧を読み込み、最後に開いていたセッションを復元する
+    // （エフェクトはブラウザでのみ実行される。サーバーで読み込んだ場合はテーマの一覧だけ）
+    Effect::new(move |_| {
+        spawn_local(async move {
+            let found = match backend.get_untracked() {
+                // サーバーで読み込んだ初期状態を表示している（セッションがなければここで作る）
+                Some(found) => {
+                    if active_session.get_untracked().is_none() {
+                        match sessions::create(found, t.new_session.to_string()).await {
+                            Ok(session) => {
+                                let session_id = session.id.clone();
+                                set_session_list.set(vec![session]);
+                                show_session(session_id, None);
+                            }
+                            Err(e) => log::error!("セッションを作成できません: {:?}", e),
+                        }
+                    }
+                    found
+                }
+                None => {
+                    let (found, mut list) = sessions::detect().await;
+                    if list.is_empty() {
+                        match sessions::create(found, t.new_session.to_string()).await {
+                            Ok(session) => list.push(session),
+                            Err(e) => log::error!("セッションを作成できません: {:?}", e),
+                        }
+                    }
+                    let active = local_store::active()
+                        .filter(|id| list.iter().any(|s| &s.id == id))
+                        .or_else(|| list.first().map(|s| s.id.clone()));
+                    if let Some(session_id) = active {
+                        open_session(found, session_id).await;
+                    }
+                    set_session_list.set(list);
+                    set_backend.set(Some(found));
+                    found
+                }
+            };
+            match themes::list(found).await {
+                Ok(list) => set_theme_list.set(list),
+                Err(e) => log::warn!("テーマの一覧を読み込めません: {:?}", e),
+            }
+        });
+    });
+
+    // 変更のたびに、最後のメッセージの時点のUI状態の変更を記録する。
+    // データベースのないデプロイでは、あわせて localStorage に保存する
+    // （データベースがあれば、送信と読み込みのたびにサーバーが保存する）
+    Effect::new(move |_| {
+        if previewing.get().is_some() {
+            return;
+        }
+        let mut snapshot = SessionSnapshot {
+            messages: messages.with(|msgs| msgs.iter().map(MessageInfo::from).collect()),
+            ui_state: UiState {
+                chat_container_styles: chat_container_styles.get(),
+                element_styles: element_styles.get(),
+                dynamic_elements: dynamic_elements.get(),
+                role_styles: role_styles.get(),
+            },
+            ui_history: ui_history.get_untracked(),
+        };
+        branches::record_history(&mut snapshot);
+        if ui_history.with_untracked(|history| history != &snapshot.ui_history) {
+            set_ui_history.set(snapshot.ui_history.clone());
+        }
+        if backend.get() != Some(Backend::Local) {
+            return;
+        }
+        if let Some(session_id) = active_session.get() {
+            local_store::save(&session_id, &snapshot);
+        }
+    });
+
+    // セッション一覧の開閉（開くたびに一覧を読み込み直し、保存順や他のタブでの変更を反映する）
+    let on_toggle_sessions = move |_| {
+        let open = !sidebar_open.get_untracked();
+        set_sidebar_open.set(open);
+        if let (true, Some(found)) = (open, backend.get_untracked()) {
+            spawn_local(async move {
+                match sessions::list(found).await {
+                    Ok(list) => set_session_list.set(list),
+                    Err(e) => log::warn!("セッションの一覧を読み込めません: {:?}", e),
+                }
+            });
+        }
+    };
+
+    // 別のセッションに切り替える（返信を待っている間は切り替えない）
+    let on_select_session = Callback::new(move |session_id: String| {
+        set_sidebar_open.set(false);
+        let Some(found) = backend.get_untracked() else {
+            return;
+        };
+        if is_loading.get_untracked() || active_session.get_untracked() == Some(session_id.clone())
+        {
+            return;
+        }
+        spawn_local(open_session(found, session_id));
+    });
+
+    // 名前を付けて空のセッションを作り、切り替える
+    let on_create_session = Callback::new(move |_: ()| {
+        let Some(found) = backend.get_untracked() else {
+            return;
+        };
+        if is_loading.get_untracked() {
+            return;
+        }
+        let default = format!(
+            "{} {}",
+            t.new_session,
+            session_list.with_untracked(Vec::len) + 1
+        );
+        let Some(name) = ask_name(t.session_name_prompt, &default) else {
+            return;
+        };
+        spawn_local(async move {
+            match sessions::create(found, name).await {
+                Ok(session) => {
+                    let session_id = session.id.clone();
+                    set_session_list.update(|list| list.insert(0, session));
+                    show_session(session_id, None);
+                    set_sidebar_open.set(false);
+                }
+                Err(e) => session_failed(e),
+            }
+        });
+    });
+
+    let on_rename_session = Callback::new(move |session_id: String| {
+        let Some(found) = backend.get_untracked() else {
+            return;
+        };
+        let current = session_list
+            .with_untracked(|list| {
+                list.iter()
+                    .find(|s| s.id == session_id)
+                    .map(|s| s.name.clone())
+            })
+            .unwrap_or_default();
+        let Some(name) = ask_name(t.session_name_prompt, &current) else {
+            return;
+        };
+        spawn_local(async move {
+            match sessions::rename(found, session_id.clone(), name.clone()).await {
+                Ok(()) => set_session_list.update(|list| {
+                    if let Some(session) = list.iter_mut().find(|s| s.id == session_id) {
+                        session.name = name;
+                    }
+                }),
+                Err(e) => session_failed(e),
+            }
+        });
+    });
+
+    // 会話とUI状態をそのまま複製する（表示しているセッションは変えない）
+    let on_duplicate_session = Callback::new(move |session_id: String| {
+        let Some(found) = backend.get_untracked() else {
+            return;
+        };
+        let name = session_list
+            .with_untracked(|list| {
+                list.iter()
+                    .find(|s| s.id == session_id)
+                    .map(|s| s.name.clone())
+            })
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| t.untitled_session.to_string());
+        let Some(name) = normalize_name(&duplicate_name(locale, &name)) else {
+            return;
+        };
+        spawn_local(async move {
+            match sessions::duplicate(found, session_id, name).await {
+                Ok(session) => set_session_list.update(|list| list.insert(0, session)),
+                Err(e) => session_failed(e),
+            }
+        });
+    });
+
+    // セッションを削除する。表示中のセッションなら、残りの先頭（なければ新しいセッション）に切り替える
+    let on_delete_session = Callback::new(move |session_id: String| {
+        let Some(found) = backend.get_untracked() else {
+            return;
+        };
+        let is_active = active_session.get_untracked() == Some(session_id.clone());
+        if is_active && is_loading.get_untracked() {
+            return;
+        }
+        if !window()
+            .confirm_with_message(t.delete_session_confirm)
+            .unwrap_or(false)
+        {
+            return;
+        }
+        spawn_local(async move {
+            if let Err(e) = sessions::delete(found, session_id.clone()).await {
+                session_failed(e);
+                return;
+            }
+            set_session_list.update(|list| list.retain(|s| s.id != session_id));
+            if !is_active {
+                return;
+            }
+            let next = match session_list.with_untracked(|list| list.first().map(|s| s.id.clone()))
+            {
+                Some(next) => next,
+                None => match sessions::create(found, t.new_session.to_string()).await {
+                    Ok(session) => {
+                        let next = session.id.clone();
+                        set_session_list.update(|list| list.push(session));
+                        next
+                    }
+                    Err(e) => {
+                        session_failed(e);
+                        return;
+                    }
+                },
+            };
+            open_session(found, next).await;
+        });
+    });
+
+    // メッセージから分岐したセッションを作り、切り替える
+    let on_fork = Callback::new(move |message_id: usize| {
+        let (Some(found), Some(session_id)) =
+            (backend.get_untracked(), active_session.get_untracked())
+        else {
+            return;
+        };
+        if is_loading.get_untracked() || previewing.get_untracked().is_some() {
+            return;
+        }
+        let name = session_list
+            .with_untracked(|list| {
+                list.iter()
+                    .find(|s| s.id == session_id)
+                    .map(|s| s.name.clone())
+            })
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| t.untitled_session.to_string());
+        let message_no = messages.with_untracked(|msgs| {
+            msgs.iter()
+                .position(|m| m.id == message_id)
+                .map_or(0, |i| i + 1)
+        });
+        let Some(name) = ask_name(t.session_name_prompt, &fork_name(locale, &name, message_no))
+        else {
+            return;
+        };
+        spawn_local(async move {
+            match branches::fork(found, session_id, message_id, name).await {
+                Ok(session) => {
+                    let fork_id = session.id.clone();
+                    set_session_list.update(|list| list.insert(0, session));
+                    open_session(found, fork_id).await;
+                }
+                Err(e) => session_failed(e),
+            }
+        });
+    });
+
+    let on_close_sessions = Callback::new(move |_: ()| set_sidebar_open.set(false));
+
+    // テーマの一覧の開閉（開くたびに一覧を読み込み直す）
+    let on_toggle_themes = move |_| {
+        let open = !themes_open.get_untracked();
+        set_themes_open.set(open);
+        if let (true, Some(found)) = (open, backend.get_untracked()) {
+            spawn_local(async move {
+                match themes::list(found).await {
+                    Ok(list) => set_theme_list.set(list),
+                    Err(e) => log::warn!("テーマの一覧を読み込めません: {:?}", e),
+                }
+            });
+        }
+    };
+
+    // 現在のスタイルの層に名前を付けて保存する（同じ名前のテーマは上書きする）
+    let on_save_theme = Callback::new(move |_: ()| {
+        let Some(found) = backend.get_untracked() else {
+            return;
+        };
+        let Some(name) = ask_name(t.theme_name_prompt, t.default_theme_name) else {
+            return;
+        };
+        let snapshot = current_snapshot();
+        let theme = snapshot.ui_state.capture_theme(&name, &snapshot.messages);
+        spawn_local(async move {
+            match themes::save(found, theme).await {
+                Ok(saved) => set_theme_list.update(|list| themes::upsert(list, saved)),
+                Err(e) => theme_failed(e),
+            }
+        });
+    });
+
+    // テーマを現在のセッションのスタイルに重ねる（プレビュー中は重ねない）
+    let on_apply_theme = Callback::new(move |name: String| {
+        // 返信を待つ間に適用すると、送信時のUI状態から作った返信で上書きされる
+        if is_loading.get_untracked() || previewing.get_untracked().is_some() {
+            return;
+        }
+        if let Some(theme) = theme_list.with_untracked(|list| themes::find(list, &name).cloned()) {
+            apply_theme(&theme);
+            save_current();
+        }
+    });
+
+    let on_delete_theme = Callback::new(move |name: String| {
+        let Some(found) = backend.get_untracked() else {
+            return;
+        };
+        if !window()
+            .confirm_with_message(t.delete_theme_confirm)
+            .unwrap_or(false)
+        {
+            return;
+        }
+        spawn_local(async move {
+            match themes::delete(found, name.clone()).await {
+                Ok(()) => set_theme_list.update(|list| list.retain(|t| t.name != name)),
+                Err(e) => theme_failed(e),
+            }
+        });
+    });
+
+    let on_close_themes = Callback::new(move |_: ()| set_themes_open.set(false));
+
+    // 履歴の開閉（開くたびに表示しているセッションの版を読み込む）
+    let on_toggle_timeline = move |_| {
+        let open = !timeline_open.get_untracked();
+        set_timeline_open.set(open);
+        if let (true, Some(found), Some(session_id)) = (
+            open,
+            backend.get_untracked(),
+            active_session.get_untracked(),
+        ) {
+            spawn_local(async move {
+                match versions::list(found, session_id).await {
+                    Ok(list) => set_version_list.set(list),
+                    Err(e) => {
+                        log::warn!("版の一覧を読み込めません: {:?}", e);
+                        let _ = window().alert_with_message(t.version_error);
+                    }
+                }
+            });
+        }
+    };
+
+    // 過去の版を画面に表示する（保存はしない）
+    let on_preview_version = Callback::new(move |number: u32| {
+        if is_loading.get_untracked() {
+            return;
+        }
+        let Some(version) =
+            version_list.with_untracked(|list| list.iter().find(|v| v.number == number).cloned())
+        else {
+            return;
+        };
+        if before_preview.with_untracked(Option::is_none) {
+            set_before_preview.set(Some(current_snapshot()));
+        }
+        set_previewing.set(Some(number));
+        apply_snapshot(version.snapshot);
+        set_timeline_open.set(false);
+    });
+
+    // プレビューをやめて、プレビュー前の画面に戻す
+    let on_close_preview = move |_| {
+        if let Some(snapshot) = before_preview.get_untracked() {
+            apply_snapshot(snapshot);
+        }
+        leave_preview();
+    };
+
+    // 過去の版を新しい版として記録し、その内容を表示する（履歴は書き換えない）
+    let on_restore_version = Callback::new(move |number: u32| {
+        let (Some(found), Some(session_id)) =
+            (backend.get_untracked(), active_session.get_untracked())
+        else {
+            return;
+        };
+        if is_loading.get_untracked() {
+            return;
+        }
+        spawn_local(async move {
+            match versions::restore(found, session_id, number).await {
+                Ok(version) => {
+                    leave_preview();
+                    apply_snapshot(version.snapshot.clone());
+                    set_version_list.update(|list| list.push(version));
+                    set_timeline_open.set(false);
+                }
+                Err(e) => {
+                    log::error!("版を復元できません: {:?}", e);
+                    let _ = window().alert_with_message(t.version_error);
+                }
+            }
+        });
+    });
+
+    let on_close_timeline = Callback::new(move |_: ()| set_timeline_open.set(false));
+
     // フォームの送信時に実行される関数
     let on_submit = move |ev: SubmitEvent| {
         // デフォルトのフォーム動作（ページの再読み込み）を止める
@@ -50,7 +674,8 @@ pub fn ChatPage() -> impl IntoView {
         let message = new_message_text.get_untracked();
         let trimmed_message = message.trim().to_string();
 
-        if !trimmed_message.is_empty() {
+        // 過去の版のプレビュー中は送信しない（戻すか閉じてから続ける）
+        if !trimmed_message.is_empty() && previewing.get_untracked().is_none() {
             let next_id = messages
                 .get_untracked()
                 .last()
@@ -61,32 +686,64 @@ pub fn ChatPage() -> impl IntoView {
                     id: next_id,
                     text: message.clone(),
                     is_user: true,
+                    ops: None,
                 });
             });
 
             // 送信後、入力フィールドを空にする
             set_new_message_text.set("".to_string());
 
+            // 保存済みのテーマの適用の指示はモデルを呼ばずに処理する
+            // （見つからなければ、ふつうの指示としてモデルに渡す）
+            let theme = parse_theme_command(&trimmed_message).and_then(|name| {
+                theme_list.with_untracked(|list| themes::find(list, &name).cloned())
+            });
+            if let Some(theme) = theme {
+                apply_theme(&theme);
+                set_messages.update(|msgs| {
+                    msgs.push(Message {
+                        id: next_id + 1,
+                        text: theme_applied_message(locale, &theme.name),
+                        is_user: false,
+                        ops: None,
+                    });
+                });
+                save_current();
+                return;
+            }
+
             // API処理
             send_message_to_api(ApiCallParams {
                 user_message: trimmed_message,
                 anchor_message_id: next_id,
                 current_messages: messages.get(),
+                current_ui_state: current_snapshot().ui_state,
+                generation: GenerationOverrides {
+                    preset: preset.get_untracked(),
+                    ..Default::default()
+                },
+                locale,
+                session_id: active_session.get_untracked(),
                 set_is_loading,
                 set_messages,
                 set_chat_container_styles,
                 set_dynamic_elements,
                 set_element_styles,
+                set_session_usage,
+                on_reply: Callback::new(move |_| record_version()),
             });
         }
     };
 
     let on_refresh = move |_| {
+        // プレビュー中なら、プレビュー前の画面には戻さずにやめる
+        leave_preview();
         // メッセージリストを初期状態に戻す
         set_messages.set(vec![Message {
             id: 0,
-            text: "こんにちは！チャットへようこそ。".to_string(),
+            text: t.welcome.to_string(),
             is_user: false,
+            ops: None,
         }]);
         // 動的要素もクリア（HashMap に変更したため）
         set_dynamic_elements.set(HashMap::new());
@@ -94,6 +751,78 @@ pub fn ChatPage() -> impl IntoView {
         set_element_styles.set(HashMap::new());
         // コンテナのスタイルを初期状態に戻す
         set_chat_container_styles.set("".to_string());
+        // テーマで設定したスタイルも外す（保存したテーマはそのまま）
+        set_role_styles.set(RoleStyles::default());
+        // 使用量の合計もリセット
+        set_session_usage.set(UsageTotals::default());
+        // メッセージごとのUI状態の変更も消す（IDは振り直されるため、残すと分岐で古い会話の状態に戻る）
+        set_ui_history.set(BTreeMap::new());
+        // 読み込み済みの版の一覧は捨て、履歴を開いていれば空にした後で読み直す
+        set_version_list.set(Vec::new());
+        // 表示しているセッションの保存内容も空にする（他のセッションはそのまま）
+        if let (Some(found), Some(session_id)) =
+            (backend.get_untracked(), active_session.get_untracked())
+        {
+            spawn_local(async move {
+                if let Err(e) = sessions::reset(found, session_id.clone()).await {
+                    log::error!("セッションを空にできません: {:?}", e);
+                    return;
+                }
+                if timeline_open.get_untracked() {
+                    match versions::list(found, session_id).await {
+                        Ok(list) => set_version_list.set(list),
+                        Err(e) => log::warn!("版の一覧を読み込めません: {:?}", e),
+                    }
+                }
+            });
+        }
+    };
+
+    // 現在の画面をHTMLファイルとして書き出す
+    let on_export = move |_| download_html(&current_snapshot(), locale);
+
+    // 会話とUI状態をJSONドキュメントとして書き出す
+    let on_export_json = move |_| download_json(&current_snapshot());
+
+    // 現在の画面を共有用に固定し、読み取り専用のリンクを表示する
+    let on_share = move |_| {
+        let snapshot = current_snapshot();
+        spawn_local(async move {
+            let window = window();
+            match share_snapshot(snapshot, None).await {
+                Ok(path) => {
+                    let url = format!("{}{}", window.location().origin().unwrap_or_default(), path);
+                    // コピーしやすいよう、選択済みの入力欄として表示する
+                    let _ = window.prompt_with_message_and_default(t.share_prompt, &url);
+                }
+                Err(e) => {
+                    log::warn!("共有リンクを作成できません: {:?}", e);
+                    let _ = window.alert_with_message(&share_error_message(locale, &e));
+                }
+            }
+        });
+    };
+
+    // JSONドキュメントを読み込む（検証とサニタイズはサーバーで行う）
+    let on_import = move |ev: leptos::ev::Event| {
+        spawn_local(async move {
+            let Some(document) = read_selected_file(&ev).await else {
+                return;
+            };
+            match import_ui_document(document, active_session.get_untracked()).await {
+                Ok(snapshot) => {
+                    leave_preview();
+                    apply_snapshot(snapshot);
+                    set_session_usage.set(UsageTotals::default());
+                    record_version();
+                }
+                Err(e) => {
+                    // 会話に挟むとAIのメッセージが続き、以降の送信や保存が検証で拒否される
+                    log::warn!("UIドキュメントを読み込めません: {:?}", e);
+                    let _ = window().alert_with_message(&import_error_message(locale, &e));
+                }
+            }
+        });
     };
 
     view! {
@@ -102,10 +831,70 @@ pub fn ChatPage() -> impl IntoView {
             <Show when=move || is_loading.get()>
                 <div class="loading-overlay">
                     <div class="loading-text">
-                        "読み込み中..."
+                        {t.loading}
                     </div>
                 </div>
             </Show>
+            // セッション一覧のボタン
+            <button
+                on:click=on_toggle_sessions
+                class="refresh-button sessions-button"
+                title=t.sessions_title
+            >
+                <svg xmlns="http://www.w3.org/2000/svg" class="refresh-icon" viewBox="0 0 24 24" fill="currentColor">
+                    <path d="M3 18h18v-2H3v2zm0-5h18v-2H3v2zm0-7v2h18V6H3z" />
+                </svg>
+            </button>
+            <Show when=move || sidebar_open.get()>
+                <SessionSidebar
+                    sessions=session_list
+                    active=active_session
+                    on_select=on_select_session
+                    on_create=on_create_session
+                    on_rename=on_rename_session
+                    on_duplicate=on_duplicate_session
+                    on_delete=on_delete_session
+                    on_close=on_close_sessions
+                />
+            </Show>
+            // テーマの一覧のボタン
+            <button
+                on:click=on_toggle_themes
+                class="refresh-button themes-button"
+                title=t.themes_title
+            >
+                <svg xmlns="http://www.w3.org/2000/svg" class="refresh-icon" viewBox="0 0 24 24" fill="currentColor">
+                    <path d="M12 3a9 9 0 0 0 0 18c.83 0 1.5-.67 1.5-1.5 0-.39-.15-.74-.39-1.01-.23-.26-.38-.61-.38-.99 0-.83.67-1.5 1.5-1.5H16c2.76 0 5-2.24 5-5 0-4.42-4.03-8-9-8zm-5.5 9c-.83 0-1.5-.67-1.5-1.5S5.67 9 6.5 9 8 9.67 8 10.5 7.33 12 6.5 12zm3-4C8.67 8 8 7.33 8 6.5S8.67 5 9.5 5s1.5.67 1.5 1.5S10.33 8 9.5 8zm5 0c-.83 0-1.5-.67-1.5-1.5S13.67 5 14.5 5s1.5.67 1.5 1.5S15.33 8 14.5 8zm3 4c-.83 0-1.5-.67-1.5-1.5S16.67 9 17.5 9s1.5.67 1.5 1.5-.67 1.5-1.5 1.5z" />
+                </svg>
+            </button>
+            <Show when=move || themes_open.get()>
+                <ThemePanel
+                    themes=theme_list
+                    on_save=on_save_theme
+                    on_apply=on_apply_theme
+                    on_delete=on_delete_theme
+                    on_close=on_close_themes
+                />
+            </Show>
+            // 履歴のボタン
+            <button
+                on:click=on_toggle_timeline
+                class="refresh-button timeline-button"
+                title=t.timeline_title
+            >
+                <svg xmlns="http://www.w3.org/2000/svg" class="refresh-icon" viewBox="0 0 24 24" fill="currentColor">
+                    <path d="M13 3a9 9 0 0 0-9 9H1l3.89 3.89.07.14L9 12H6c0-3.87 3.13-7 7-7s7 3.13 7 7-3.13 7-7 7c-1.93 0-3.68-.79-4.94-2.06l-1.42 1.42A8.954 8.954 0 0 0 13 21a9 9 0 0 0 0-18zm-1 5v5l4.28 2.54.72-1.21-3.5-2.08V8H12z" />
+                </svg>
+            </button>
+            <Show when=move || timeline_open.get()>
+                <VersionTimeline
+                    versions=version_list
+                    previewing=previewing
+                    on_preview=on_preview_version
+                    on_restore=on_restore_version
+                    on_close=on_close_timeline
+                />
+            </Show>
             // リフレッシュボタン
             <button
                 on:click=on_refresh
@@ -115,7 +904,64 @@ pub fn ChatPage() -> impl IntoView {
                     <path d="M17.65 6.35C16.2 4.9 14.21 4 12 4c-4.42 0-7.99 3.58-7.99 8s3.57 8 7.99 8c3.73 0 6.84-2.55 7.73-6h-2.08c-.82 2.33-3.04 4-5.65 4-3.31 0-6-2.69-6-6s2.69-6 6-6c1.76 0 3.32.74 4.46 1.96L13 11h7V4z" />
                 </svg>
             </button>
+            // 書き出しボタン
+            <button
+                on:click=on_export
+                class="refresh-button export-button"
+                title=t.export_title
+            >
+                <svg xmlns="http://www.w3.org/2000/svg" class="refresh-icon" viewBox="0 0 24 24" fill="currentColor">
+                    <path d="M19 9h-4V3H9v6H5l7 7 7-7zM5 18v2h14v-2H5z" />
+                </svg>
+            </button>
+            // JSONの書き出しボタン
+            <button
+                on:click=on_export_json
+                class="refresh-button export-json-button"
+                title=t.export_json_title
+            >
+                <svg xmlns="http://www.w3.org/2000/svg" class="refresh-icon" viewBox="0 0 24 24" fill="currentColor">
+                    <path d="M9.4 16.6 4.8 12l4.6-4.6L8 6l-6 6 6 6 1.4-1.4zm5.2 0 4.6-4.6-4.6-4.6L16 6l6 6-6 6-1.4-1.4z" />
+                </svg>
+            </button>
+            // 共有ボタン
+            <button
+                on:click=on_share
+                class="refresh-button share-button"
+                title=t.share_title
+            >
+                <svg xmlns="http://www.w3.org/2000/svg" class="refresh-icon" viewBox="0 0 24 24" fill="currentColor">
+                    <path d="M18 16.08c-.76 0-1.44.3-1.96.77L8.91 12.7c.05-.23.09-.46.09-.7s-.04-.47-.09-.7l7.05-4.11c.54.5 1.25.81 2.04.81 1.66 0 3-1.34 3-3s-1.34-3-3-3-3 1.34-3 3c0 .24.04.47.09.7L8.04 9.81C7.5 9.31 6.79 9 6 9c-1.66 0-3 1.34-3 3s1.34 3 3 3c.79 0 1.5-.31 2.04-.81l7.12 4.16c-.05.21-.08.43-.08.65 0 1.61 1.31 2.92 2.92 2.92s2.92-1.31 2.92-2.92-1.31-2.92-2.92-2.92z" />
+                </svg>
+            </button>
+            // JSONの読み込みボタン（ファイル選択欄はラベルから開く）
+            <label class="refresh-button import-button" title=t.import_title>
+                <svg xmlns="http://www.w3.org/2000/svg" class="refresh-icon" viewBox="0 0 24 24" fill="currentColor">
+                    <path d="M9 16h6v-6h4l-7-7-7 7h4v6zm-4 2h14v2H5v-2z" />
+                </svg>
+                <input type="file" accept="application/json,.json" class="import-input" on:change=on_import />
+            </label>
             <div class="chat-container" style=move || chat_container_styles.get()>
+                // 過去の版のプレビュー中の案内
+                {move || {
+                    let number = previewing.get()?;
+                    let version = version_list
+                        .with(|list| list.iter().find(|v| v.number == number).cloned())?;
+                    Some(view! {
+                        <div class="preview-banner">
+                            <span class="preview-notice">{preview_notice(locale, &version)}</span>
+                            <button
+                                class="preview-action"
+                                on:click=move |_| on_restore_version.run(number)
+                            >
+                                {t.restore_version_title}
+                            </button>
+                            <button class="preview-action" on:click=on_close_preview>
+                                {t.close_preview}
+                            </button>
+                        </div>
+                    })
+                }}
                 // メッセージ履歴表示エリア
                 <div class="messages-area">
                     <For
@@ -123,101 +969,42 @@ pub fn ChatPage() -> impl IntoView {
                         each=move || messages.get()
                         key=|msg| msg.id
                         children=move |msg| {
-                            // ベーススタイル + 動的スタイルをマージ
-                            let msg_styles = move || {
-                                let base_styles = if msg.is_user {
-                                    "padding: 12px 16px; border-radius: 18px; border-bottom-right-radius: 4px; box-shadow: 0 2px 8px rgba(0, 0, 0, 0.1); max-width: 85%; text-align: left; background-color: #dbeafe; color: #1f2937;"
-                                } else {
-                                    "padding: 12px 16px; border-radius: 18px; border-bottom-left-radius: 4px; box-shadow: 0 2px 8px rgba(0, 0, 0, 0.1); max-width: 85%; text-align: left; background-color: #e5e7eb; color: #1f2937;"
-                                };
-                                // element_stylesの変更を明示的に追跡（リアクティブに更新される）
-                                let extras_map = element_styles.get();
-                                if let Some(extra) = extras_map.get(&msg.id) {
-                                    if extra.trim().is_empty() {
-                                        base_styles.to_string()
-                                    } else {
-                                        // 動的スタイルをベーススタイルの後に追加（後から適用されるため優先される）
-                                        format!("{} {}", base_styles, extra)
-                                    }
-                                } else {
-                                    base_styles.to_string()
-                                }
-                            };
                             view! {
-                                <div class=move || {
-                                    format!("message-item {}", if msg.is_user { "justify-end" } else { "justify-start" })
-                                }>
-                                    // アイコン
-                                    {(!msg.is_user).then(|| view! {
-                                        <div class="message-icon">
-                                            {"🤖"}
-                                        </div>
-                                    })}
-
-                                    // メッセージの吹き出し
-                                    <div style=msg_styles>
-                                        <p class="message-text">{msg.text.clone()}</p>
-                                    </div>
-                                </div>
-                                // このメッセージ直後に紐づいた動的要素を描画
-                                {move || {
-                                    let map = dynamic_elements.get();
-                                    let list = map.get(&msg.id).cloned().unwrap_or_default();
-                                    if list.is_empty() {
-                                        ().into_any()
-                                    } else {
-                                        view! {
-                                            <For
-                                                each=move || list.clone()
-                                                key=|elem| elem.id
-                                                children=move |elem| {
-                                                    let styles = elem.styles.clone().unwrap_or_default();
-                                                    let text = elem.text.clone().unwrap_or_default();
-                                                    let attrs = elem.attributes.clone().unwrap_or_default();
-                                                    let child: AnyView = match elem.tag.as_str() {
-                                                        "div" => view! { <div>{text}</div> }.into_any(),
-                                                        "p" => view! { <p>{text}</p> }.into_any(),
-                                                        "span" => view! { <span>{text}</span> }.into_any(),
-                                                        "button" => view! { <button>{text}</button> }.into_any(),
-                                                        "a" => {
-                                                            let href = attrs.get("href").cloned().unwrap_or_default();
-                                                            view! { <a href=href>{text}</a> }.into_any()
-                                                        },
-                                                        "img" => {
-                                                            let src = attrs.get("src").cloned().unwrap_or_default();
-                                                            let alt = attrs.get("alt").cloned().unwrap_or_default();
-                                                            view! { <img src=src alt=alt/> }.into_any()
-                                                        },
-                                                        "input" => {
-                                                            let input_type = attrs.get("type").cloned().unwrap_or_else(|| "text".to_string());
-                                                            let placeholder = attrs.get("placeholder").cloned().unwrap_or_default();
-                                                            let value = attrs.get("value").cloned().unwrap_or_default();
-                                                            view! { <input r#type=input_type placeholder=placeholder value=value/> }.into_any()
-                                                        },
-                                                        _ => view! { <div>{text}</div> }.into_any(),
-                                                    };
-                                                    view! { <div class="dynamic-element" style=styles>{child}</div> }.into_any()
-                                                }
-                                            />
-                                        }.into_any()
-                                    }
-                                }}
+                                <MessageView msg element_styles dynamic_elements role_styles on_fork/>
                             }
                         }
                     />
                 </div>
+                // トークン数と料金の表示
+                <Show when=move || session_usage.with(|u| u.turns != 0)>
+                    <div class="usage-footer">
+                        {move || usage_summary(locale, &session_usage.get())}
+                    </div>
+                </Show>
                 // 入力フォームとボタン
                 <form
                     on:submit=on_submit
                     class="input-form"
+                    style=move || role_styles.with(|roles| roles.chrome.clone())
                 >
+                    <select
+                        class="preset-select"
+                        title=t.preset_title
+                        on:change=move |ev| {
+                            set_preset.set(preset_from_value(&event_target_value(&ev)));
+                        }
+                    >
+                        <option value="standard" selected=move || preset.get().is_none()>{t.preset_standard}</option>
+                        <option value="creative" selected=move || preset.get() == Some(GenerationPreset::Creative)>{t.preset_creative}</option>
+                        <option value="precise" selected=move || preset.get() == Some(GenerationPreset::Precise)>{t.preset_precise}</option>
+                    </select>
                     <input
                         type="text"
                         prop:value=new_message_text
                         on:input=move |ev| {
                             set_new_message_text(event_target_value(&ev));
                         }
-                        placeholder="メッセージを入力..."
+                        placeholder=t.placeholder
                         class="input-field"
                     />
                     <button
@@ -233,3 +1020,21 @@ pub fn ChatPage() -> impl IntoView {
         </div>
     }
 }
+
+/// セッション名やテーマ名を入力してもらう（キャンセルまたは空なら None）
+fn ask_name(message: &str, default: &str) -> Option<String> {
+    window()
+        .prompt_with_message_and_default(message, default)
+        .ok()
+        .flatten()
+        .and_then(|name| normalize_name(&name))
+}
+
+/// 選択肢の値を生成プリセットに変換する（標準は None）
+fn preset_from_value(value: &str) -> Option<GenerationPreset> {
+    match value {
+        "creative" => Some(GenerationPreset::Creative),
+        "precise" => Some(GenerationPreset::Precise),
+        _ => None,
+    }
+}
@@ -0,0 +1,114 @@
+use crate::base_styles::bubble_styles;
+use crate::i18n::text;
+use crate::pages::chat_page::Message;
+use common::{DynamicElementData, Locale, RoleStyles};
+use leptos::prelude::*;
+use std::collections::HashMap;
+
+/// 1件のメッセージの吹き出しと、その直後に紐づいた動的要素を描画します
+/// （チャットページと共有ページで共通）
+#[component]
+pub fn MessageView(
+    msg: Message,
+    #[prop(into)] element_styles: Signal<HashMap<usize, String>>,
+    #[prop(into)] dynamic_elements: Signal<HashMap<usize, Vec<DynamicElementData>>>,
+    #[prop(into)] role_styles: Signal<RoleStyles>,
+    /// メッセージから分岐する操作（共有ページでは表示しない）
+    #[prop(optional)]
+    on_fork: Option<Callback<usize>>,
+) -> impl IntoView {
+    let t = text(use_context::<Locale>().unwrap_or_default());
+    let id = msg.id;
+    // ベーススタイル + テーマの役割ごとのスタイル + 動的スタイルをマージ
+    let msg_styles = move || {
+        let mut styles = bubble_styles(msg.is_user).to_string();
+        let role = role_styles.with(|roles| {
+            if msg.is_user {
+                roles.user_message.clone()
+            } else {
+                roles.ai_message.clone()
+            }
+        });
+        // element_stylesの変更を明示的に追跡（リアクティブに更新される）
+        let extra = element_styles.with(|extras| extras.get(&msg.id).cloned());
+        // 後から追加したスタイルほど優先される
+        for layer in [Some(role), extra].into_iter().flatten() {
+            if !layer.trim().is_empty() {
+                styles.push(' ');
+                styles.push_str(&layer);
+            }
+        }
+        styles
+    };
+    view! {
+        <div class=move || {
+            format!("message-item {}", if msg.is_user { "justify-end" } else { "justify-start" })
+        }>
+            // アイコン
+            {(!msg.is_user).then(|| view! {
+                <div class="message-icon">
+                    {"🤖"}
+                </div>
+            })}
+
+            // メッセージの吹き出し
+            <div style=msg_styles>
+                <p class="message-text">{msg.text.clone()}</p>
+            </div>
+
+            // このメッセージまでの会話から分岐するボタン
+            {on_fork.map(|on_fork| view! {
+                <button
+                    class="fork-button"
+                    title=t.fork_title
+                    on:click=move |_| on_fork.run(id)
+                >
+                    "⑂"
+                </button>
+            })}
+        </div>
+        // このメッセージ直後に紐づいた動的要素を描画
+        {move || {
+            let map = dynamic_elements.get();
+            let list = map.get(&msg.id).cloned().unwrap_or_default();
+            if list.is_empty() {
+                ().into_any()
+            } else {
+                view! {
+                    <For
+                        each=move || list.clone()
+                        key=|elem| elem.id
+                        children=move |elem| {
+                            let styles = elem.styles.clone().unwrap_or_default();
+                            let text = elem.text.clone().unwrap_or_default();
+                            let attrs = elem.attributes.clone().unwrap_or_default();
+                            let child: AnyView = match elem.tag.as_str() {
+                                "div" => view! { <div>{text}</div> }.into_any(),
+                                "p" => view! { <p>{text}</p> }.into_any(),
+                                "span" => view! { <span>{text}</span> }.into_any(),
+                                "button" => view! { <button>{text}</button> }.into_any(),
+                                "a" => {
+                                    let href = attrs.get("href").cloned().unwrap_or_default();
+                                    view! { <a href=href>{text}</a> }.into_any()
+                                },
+                                "img" => {
+                                    let src = attrs.get("src").cloned().unwrap_or_default();
+                                    let alt = attrs.get("alt").cloned().unwrap_or_default();
+                                    view! { <img src=src alt=alt/> }.into_any()
+                                },
+                                "input" => {
+                                    let input_type = attrs.get("type").cloned().unwrap_or_else(|| "text".to_string());
+                                    let placeholder = attrs.get("placeholder").cloned().unwrap_or_default();
+                                    let value = attrs.get("value").cloned().unwrap_or_default();
+                                    view! { <input r#type=input_type placeholder=placeholder value=value/> }.into_any()
+                                },
+                                _ => view! { <div>{text}</div> }.into_any(),
+                            };
+                            view! { <div class="dynamic-element" style=styles>{child}</div> }.into_any()
+                        }
+                    />
+                }.into_any()
+            }
+        }}
+    }
+}
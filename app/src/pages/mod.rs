@@ -1 +1,6 @@
 pub mod chat_page;
+pub mod message_view;
+pub mod session_sidebar;
+pub mod shared_page;
+pub mod theme_panel;
+pub mod version_timeline;
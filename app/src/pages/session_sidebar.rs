@@ -0,0 +1,91 @@
+use crate::branches::tree;
+use crate::i18n::text;
+use common::{Locale, SessionSummary};
+use leptos::prelude::*;
+
+/// 名前付きセッションの一覧（分岐したセッションは分岐元の下に字下げする）と、
+/// 作成・切り替え・名前の変更・複製・削除の操作を表示します
+#[component]
+pub fn SessionSidebar(
+    #[prop(into)] sessions: Signal<Vec<SessionSummary>>,
+    #[prop(into)] active: Signal<Option<String>>,
+    on_select: Callback<String>,
+    on_create: Callback<()>,
+    on_rename: Callback<String>,
+    on_duplicate: Callback<String>,
+    on_delete: Callback<String>,
+    on_close: Callback<()>,
+) -> impl IntoView {
+    let t = text(use_context::<Locale>().unwrap_or_default());
+    view! {
+        // 一覧の外をクリックすると閉じる
+        <div class="drawer-backdrop" on:click=move |_| on_close.run(())></div>
+        <aside class="drawer">
+            <div class="drawer-header">
+                <span>{t.sessions_title}</span>
+                <button class="drawer-new" title=t.new_session on:click=move |_| on_create.run(())>
+                    "+"
+                </button>
+            </div>
+            <ul class="drawer-list">
+                <For
+                    each=move || sessions.with(|sessions| tree(sessions))
+                    // 名前や木の中の深さが変わったら描画し直す
+                    key=|(session, depth)| (session.id.clone(), session.name.clone(), *depth)
+                    children=move |(session, depth)| {
+                        let id = session.id.clone();
+                        let is_active = {
+                            let id = id.clone();
+                            move || active.with(|active| active.as_deref() == Some(id.as_str()))
+                        };
+                        let name = if session.name.is_empty() {
+                            t.untitled_session.to_string()
+                        } else {
+                            session.name.clone()
+                        };
+                        // 分岐したセッションには印を付ける
+                        let label = if depth > 0 { format!("↳ {}", name) } else { name.clone() };
+                        let (select, rename, duplicate, delete) =
+                            (id.clone(), id.clone(), id.clone(), id);
+                        view! {
+                            <li
+                                class="drawer-item"
+                                class:active=is_active
+                                style=format!("padding-left: {}rem", 0.5 + depth as f32 * 0.75)
+                            >
+                                <button
+                                    class="drawer-name"
+                                    title=name.clone()
+                                    on:click=move |_| on_select.run(select.clone())
+                                >
+                                    {label}
+                                </button>
+                                <button
+                                    class="drawer-action"
+                                    title=t.rename_session_title
+                                    on:click=move |_| on_rename.run(rename.clone())
+                                >
+                                    "✎"
+                                </button>
+                                <button
+                                    class="drawer-action"
+                                    title=t.duplicate_session_title
+                                    on:click=move |_| on_duplicate.run(duplicate.clone())
+                                >
+                                    "⧉"
+                                </button>
+                                <button
+                                    class="drawer-action"
+                                    title=t.delete_session_title
+                                    on:click=move |_| on_delete.run(delete.clone())
+                                >
+                                    "×"
+                                </button>
+                            </li>
+                        }
+                    }
+                />
+            </ul>
+        </aside>
+    }
+}
@@ -0,0 +1,61 @@
+use crate::api::load_shared_snapshot;
+use crate::i18n::text;
+use crate::pages::chat_page::Message;
+use crate::pages::message_view::MessageView;
+use common::{Locale, SessionSnapshot};
+use leptos::prelude::*;
+use leptos_meta::Meta;
+use leptos_router::hooks::use_params_map;
+
+/// 共有されたスナップショットを読み取り専用で表示します（/s/{id}）
+#[component]
+pub fn SharedPage() -> impl IntoView {
+    let locale = use_context::<Locale>().unwrap_or_default();
+    let t = text(locale);
+    let params = use_params_map();
+    // サーバーで読み込んで描画し、ハイドレーション時は同じ結果を使う
+    let snapshot = Resource::new(
+        move || params.read().get("id").unwrap_or_default(),
+        load_shared_snapshot,
+    );
+
+    view! {
+        // 共有リンクは検索エンジンに載せない
+        <Meta name="robots" content="noindex"/>
+        <div class="main-container">
+            <Suspense fallback=move || view! { <div class="loading-text">{t.loading}</div> }>
+                {move || Suspend::new(async move {
+                    match snapshot.await {
+                        Ok(Some(snapshot)) => view! { <SharedSnapshot snapshot/> }.into_any(),
+                        Ok(None) => view! { <p class="shared-notice">{t.shared_not_found}</p> }.into_any(),
+                        Err(e) => {
+                            log::error!("共有されたスナップショットを読み込めません: {:?}", e);
+                            view! { <p class="shared-notice">{t.shared_not_found}</p> }.into_any()
+                        }
+                    }
+                })}
+            </Suspense>
+        </div>
+    }
+}
+
+#[component]
+fn SharedSnapshot(snapshot: SessionSnapshot) -> impl IntoView {
+    let t = text(use_context::<Locale>().unwrap_or_default());
+    let messages: Vec<Message> = snapshot.messages.into_iter().map(Message::from).collect();
+    let element_styles = Signal::stored(snapshot.ui_state.element_styles);
+    let dynamic_elements = Signal::stored(snapshot.ui_state.dynamic_elements);
+    let role_styles = Signal::stored(snapshot.ui_state.role_styles);
+
+    view! {
+        <div class="chat-container" style=snapshot.ui_state.chat_container_styles>
+            <div class="messages-area">
+                {messages
+                    .into_iter()
+                    .map(|msg| view! { <MessageView msg element_styles dynamic_elements role_styles/> })
+                    .collect_view()}
+            </div>
+            <div class="shared-notice">{t.shared_notice}</div>
+        </div>
+    }
+}
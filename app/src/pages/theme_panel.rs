@@ -0,0 +1,56 @@
+use crate::i18n::text;
+use common::{Locale, Theme};
+use leptos::prelude::*;
+
+/// 保存したテーマの一覧と、保存・適用・削除の操作を表示します
+#[component]
+pub fn ThemePanel(
+    #[prop(into)] themes: Signal<Vec<Theme>>,
+    on_save: Callback<()>,
+    on_apply: Callback<String>,
+    on_delete: Callback<String>,
+    on_close: Callback<()>,
+) -> impl IntoView {
+    let t = text(use_context::<Locale>().unwrap_or_default());
+    view! {
+        // 一覧の外をクリックすると閉じる
+        <div class="drawer-backdrop" on:click=move |_| on_close.run(())></div>
+        <aside class="drawer">
+            <div class="drawer-header">
+                <span>{t.themes_title}</span>
+                <button class="drawer-new" title=t.save_theme_title on:click=move |_| on_save.run(())>
+                    "+"
+                </button>
+            </div>
+            <p class="drawer-hint">{t.themes_hint}</p>
+            <ul class="drawer-list">
+                <For
+                    each=move || themes.get()
+                    // 上書き保存したら描画し直す
+                    key=|theme| (theme.name.clone(), theme.updated_at)
+                    children=move |theme| {
+                        let (apply, delete) = (theme.name.clone(), theme.name.clone());
+                        view! {
+                            <li class="drawer-item">
+                                <button
+                                    class="drawer-name"
+                                    title=theme.name.clone()
+                                    on:click=move |_| on_apply.run(apply.clone())
+                                >
+                                    {theme.name.clone()}
+                                </button>
+                                <button
+                                    class="drawer-action"
+                                    title=t.delete_theme_title
+                                    on:click=move |_| on_delete.run(delete.clone())
+                                >
+                                    "×"
+                                </button>
+                            </li>
+                        }
+                    }
+                />
+            </ul>
+        </aside>
+    }
+}
@@ -0,0 +1,78 @@
+use crate::i18n::{describe_changes, text, version_label};
+use crate::versions::diff;
+use common::{Locale, SessionSnapshot, SessionVersion};
+use leptos::prelude::*;
+
+/// セッションの版の一覧（新しい順）と、前の版からの差分、プレビュー・復元の操作を表示します
+#[component]
+pub fn VersionTimeline(
+    #[prop(into)] versions: Signal<Vec<SessionVersion>>,
+    #[prop(into)] previewing: Signal<Option<u32>>,
+    on_preview: Callback<u32>,
+    on_restore: Callback<u32>,
+    on_close: Callback<()>,
+) -> impl IntoView {
+    let locale = use_context::<Locale>().unwrap_or_default();
+    let t = text(locale);
+    // 各版と、その直前の版（最初の版は空の状態）との差分
+    let entries = move || {
+        versions.with(|versions| {
+            let empty = SessionSnapshot::default();
+            let mut entries: Vec<(SessionVersion, String)> = versions
+                .iter()
+                .enumerate()
+                .map(|(i, version)| {
+                    let before = i.checked_sub(1).map_or(&empty, |i| &versions[i].snapshot);
+                    let changes = describe_changes(locale, &diff(before, &version.snapshot));
+                    (version.clone(), changes)
+                })
+                .collect();
+            entries.reverse();
+            entries
+        })
+    };
+    view! {
+        // 一覧の外をクリックすると閉じる
+        <div class="drawer-backdrop" on:click=move |_| on_close.run(())></div>
+        <aside class="drawer">
+            <div class="drawer-header">
+                <span>{t.timeline_title}</span>
+            </div>
+            <Show when=move || versions.with(Vec::is_empty)>
+                <p class="drawer-hint">{t.timeline_empty}</p>
+            </Show>
+            <ul class="drawer-list">
+                <For
+                    each=entries
+                    key=|(version, _)| version.number
+                    children=move |(version, changes)| {
+                        let number = version.number;
+                        let label = version_label(locale, &version);
+                        view! {
+                            <li
+                                class="drawer-item"
+                                class:active=move || previewing.get() == Some(number)
+                            >
+                                <button
+                                    class="drawer-name"
+                                    title=t.preview_version_title
+                                    on:click=move |_| on_preview.run(number)
+                                >
+                                    {label}
+                                    <span class="drawer-detail">{changes}</span>
+                                </button>
+                                <button
+                                    class="drawer-action"
+                                    title=t.restore_version_title
+                                    on:click=move |_| on_restore.run(number)
+                                >
+                                    "↺"
+                                </button>
+                            </li>
+                        }
+                    }
+                />
+            </ul>
+        </aside>
+    }
+}
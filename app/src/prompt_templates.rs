@@ -0,0 +1,390 @@
+//! ファイルから読み込むプロンプトテンプレート
+//!
+//! テンプレートは先頭に `version` を持つヘッダーを書き、本文では `{{name}}` の
+//! 名前付きプレースホルダーを使う。起動時に全テンプレートを検証し、
+//! 開発中はファイルの変更を検知して再読み込みする。
+//!
+//! 既定の言語（日本語）のテンプレートは `prompts/` 直下に、
+//! ほかの言語は `prompts/<言語コード>/` に同じファイル名で置く。
+
+use crate::few_shot::{ExampleLibrary, EXAMPLES_FILE};
+use common::Locale;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::SystemTime;
+
+/// システム指示のテンプレート
+pub const SYSTEM_TEMPLATE: &str = "system.txt";
+/// システム指示のテンプレートに渡す値（要求に近い出力例）
+pub const SYSTEM_PLACEHOLDERS: &[&str] = &["examples"];
+/// ユーザーメッセージのテンプレート
+pub const USER_TEMPLATE: &str = "user.txt";
+/// ユーザーメッセージのテンプレートに渡す値
+pub const USER_PLACEHOLDERS: &[&str] = &["count", "history", "next_id", "request", "ui_state"];
+
+/// テンプレートの読み込み・検証エラー
+#[derive(Clone, Debug, PartialEq, thiserror::Error)]
+pub enum TemplateError {
+    #[error("{file}: 読み込みに失敗しました: {message}")]
+    Io { file: String, message: String },
+    #[error("{file}: ヘッダーに version がありません")]
+    MissingVersion { file: String },
+    #[error("{file}: 未知のプレースホルダー {{{{{name}}}}} があります")]
+    UnknownPlaceholder { file: String, name: String },
+    #[error("{file}: プレースホルダー {{{{{name}}}}} がありません")]
+    MissingPlaceholder { file: String, name: String },
+    #[error("{file}:{line}: 出力例が不正です: {message}")]
+    InvalidExample {
+        file: String,
+        line: usize,
+        message: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Text(String),
+    Placeholder(String),
+}
+
+/// 検証済みのテンプレート
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    pub version: String,
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    /// ヘッダーと本文を解析し、プレースホルダーが `placeholders` と過不足なく一致するか検証する
+    pub fn parse(file: &str, source: &str, placeholders: &[&str]) -> Result<Self, TemplateError> {
+        let (version, body) =
+            split_header(source).ok_or_else(|| TemplateError::MissingVersion {
+                file: file.to_string(),
+            })?;
+        let segments = parse_segments(body);
+
+        for segment in &segments {
+            if let Segment::Placeholder(name) = segment {
+                if !placeholders.contains(&name.as_str()) {
+                    return Err(TemplateError::UnknownPlaceholder {
+                        file: file.to_string(),
+                        name: name.clone(),
+                    });
+                }
+            }
+        }
+        for name in placeholders {
+            if !segments
+                .iter()
+                .any(|s| matches!(s, Segment::Placeholder(p) if p == name))
+            {
+                return Err(TemplateError::MissingPlaceholder {
+                    file: file.to_string(),
+                    name: name.to_string(),
+                });
+            }
+        }
+        Ok(Self { version, segments })
+    }
+
+    /// プレースホルダーを値で置き換える（置き換えた値の中は再展開しない）
+    pub fn render(&self, values: &[(&str, String)]) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.as_str(),
+                Segment::Placeholder(name) => values
+                    .iter()
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.as_str())
+                    .unwrap_or_default(),
+            })
+            .collect()
+    }
+}
+
+// "---\nversion: x\n---\n本文" を (version, 本文) に分ける
+fn split_header(source: &str) -> Option<(String, &str)> {
+    let source = source.strip_prefix('\u{feff}').unwrap_or(source);
+    let rest = source.strip_prefix("---\n")?;
+    let end = rest.find("\n---\n")?;
+    let version = rest[..end]
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim() == "version")
+        .map(|(_, value)| value.trim().to_string())
+        .filter(|v| !v.is_empty())?;
+    let body = &rest[end + 5..];
+    Some((version, body.strip_suffix('\n').unwrap_or(body)))
+}
+
+// `{{name}}`（英数字と_のみ）をプレースホルダーとして切り出す。それ以外の括弧は本文として扱う
+fn parse_segments(body: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut text = String::new();
+    let mut rest = body;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        let name_len = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(after.len());
+        if name_len > 0 && after[name_len..].starts_with("}}") {
+            text.push_str(&rest[..start]);
+            if !text.is_empty() {
+                segments.push(Segment::Text(std::mem::take(&mut text)));
+            }
+            segments.push(Segment::Placeholder(after[..name_len].to_string()));
+            rest = &after[name_len + 2..];
+        } else {
+            text.push_str(&rest[..start + 2]);
+            rest = after;
+        }
+    }
+    text.push_str(rest);
+    if !text.is_empty() {
+        segments.push(Segment::Text(text));
+    }
+    segments
+}
+
+/// 1回の生成で使うテンプレート一式
+#[derive(Clone, Debug, PartialEq)]
+pub struct PromptSet {
+    pub system: Template,
+    pub user: Template,
+    pub examples: ExampleLibrary,
+}
+
+impl PromptSet {
+    pub fn load(dir: &Path) -> Result<Self, TemplateError> {
+        Ok(Self {
+            system: load_template(dir, SYSTEM_TEMPLATE, SYSTEM_PLACEHOLDERS)?,
+            user: load_template(dir, USER_TEMPLATE, USER_PLACEHOLDERS)?,
+            examples: ExampleLibrary::parse(EXAMPLES_FILE, &read_file(dir, EXAMPLES_FILE)?)?,
+        })
+    }
+
+    /// レスポンスに記録するバージョン（テンプレートごとのversionと出力例のハッシュを連結したもの）
+    pub fn version(&self) -> String {
+        format!(
+            "system@{}+user@{}+examples@{}",
+            self.system.version, self.user.version, self.examples.version
+        )
+    }
+
+    /// 要求に近い出力例を `example_count` 件埋め込んだシステム指示
+    pub fn system_instruction(&self, request: &str, example_count: usize) -> String {
+        let examples = ExampleLibrary::render(&self.examples.select(request, example_count));
+        self.system.render(&[("examples", examples)])
+    }
+}
+
+fn read_file(dir: &Path, file: &str) -> Result<String, TemplateError> {
+    std::fs::read_to_string(dir.join(file)).map_err(|e| TemplateError::Io {
+        file: file.to_string(),
+        message: e.to_string(),
+    })
+}
+
+fn load_template(dir: &Path, file: &str, placeholders: &[&str]) -> Result<Template, TemplateError> {
+    Template::parse(file, &read_file(dir, file)?, placeholders)
+}
+
+fn modified_times(dir: &Path) -> Vec<Option<SystemTime>> {
+    [SYSTEM_TEMPLATE, USER_TEMPLATE, EXAMPLES_FILE]
+        .iter()
+        .map(|file| {
+            std::fs::metadata(dir.join(file))
+                .and_then(|m| m.modified())
+                .ok()
+        })
+        .collect()
+}
+
+/// 読み込み済みのテンプレートを保持し、必要に応じて再読み込みする
+pub struct PromptStore {
+    dir: PathBuf,
+    hot_reload: bool,
+    loaded: RwLock<(Arc<PromptSet>, Vec<Option<SystemTime>>)>,
+}
+
+impl PromptStore {
+    pub fn open(dir: PathBuf, hot_reload: bool) -> Result<Self, TemplateError> {
+        let times = modified_times(&dir);
+        let set = PromptSet::load(&dir)?;
+        Ok(Self {
+            dir,
+            hot_reload,
+            loaded: RwLock::new((Arc::new(set), times)),
+        })
+    }
+
+    /// 現在のテンプレートを返す。ホットリロード時はファイルが変わっていれば読み直す
+    pub fn current(&self) -> Arc<PromptSet> {
+        if self.hot_reload {
+            let times = modified_times(&self.dir);
+            let changed = self.loaded.read().map(|l| l.1 != times).unwrap_or(false);
+            if changed {
+                let result = PromptSet::load(&self.dir);
+                if let Ok(mut loaded) = self.loaded.write() {
+                    match result {
+                        Ok(set) => {
+                            log::info!("プロンプトを再読み込みしました: {}", set.version());
+                            loaded.0 = Arc::new(set);
+                        }
+                        // 編集途中の不正なテンプレートでは置き換えず、直前のものを使い続ける
+                        Err(e) => log::error!("プロンプトの再読み込みに失敗しました: {}", e),
+                    }
+                    loaded.1 = times;
+                }
+            }
+        }
+        self.loaded
+            .read()
+            .map(|l| l.0.clone())
+            .unwrap_or_else(|e| e.into_inner().0.clone())
+    }
+}
+
+/// 言語ごとのテンプレートのディレクトリ
+pub fn locale_dir(root: &Path, locale: Locale) -> PathBuf {
+    if locale == Locale::default() {
+        root.to_path_buf()
+    } else {
+        root.join(locale.code())
+    }
+}
+
+static STORES: OnceLock<Vec<(Locale, PromptStore)>> = OnceLock::new();
+
+/// 環境変数 PROMPT_DIR / PROMPT_HOT_RELOAD から言語ごとのテンプレートを読み込み、検証する
+pub fn init_from_env() -> Result<(), TemplateError> {
+    if STORES.get().is_some() {
+        return Ok(());
+    }
+    let root = std::env::var("PROMPT_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("prompts"));
+    // 明示されなければ cargo leptos watch での開発時のみ再読み込みする
+    let hot_reload = match std::env::var("PROMPT_HOT_RELOAD").as_deref() {
+        Ok("1") | Ok("true") => true,
+        Ok(_) => false,
+        Err(_) => std::env::var("LEPTOS_WATCH").is_ok(),
+    };
+    let mut stores = Vec::new();
+    for locale in Locale::ALL {
+        let dir = locale_dir(&root, locale);
+        // 既定の言語以外はディレクトリがなければ既定の言語のテンプレートを使う
+        if locale != Locale::default() && !dir.is_dir() {
+            log::info!(
+                "{} のプロンプトがないため既定の言語を使います",
+                locale.code()
+            );
+            continue;
+        }
+        stores.push((locale, PromptStore::open(dir, hot_reload)?));
+    }
+    let _ = STORES.set(stores);
+    Ok(())
+}
+
+/// 指定した言語の現在のテンプレート一式（なければ既定の言語のもの）
+pub fn current(locale: Locale) -> Result<Arc<PromptSet>, TemplateError> {
+    init_from_env()?;
+    let stores = STORES.get().expect("prompt stores are initialized");
+    let store = stores
+        .iter()
+        .find(|(l, _)| *l == locale)
+        .or_else(|| stores.iter().find(|(l, _)| *l == Locale::default()))
+        .map(|(_, store)| store)
+        .expect("default locale prompts are loaded");
+    Ok(store.current())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repo_prompts() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("../prompts")
+    }
+
+    #[test]
+    fn test_repository_templates_are_valid() {
+        let set = PromptSet::load(&repo_prompts()).unwrap();
+        assert!(!set.system.version.is_empty());
+        assert!(set.version().starts_with("system@"));
+        let instruction = set.system_instruction("画像を追加して", 2);
+        assert!(!instruction.contains("{{examples}}"));
+        assert_eq!(instruction.matches("- 画像を追加して: ").count(), 1);
+
+        let en = PromptSet::load(&locale_dir(&repo_prompts(), Locale::En)).unwrap();
+        assert_eq!(en.examples.len(), set.examples.len());
+        let instruction = en.system_instruction("Add an image please", 2);
+        assert_eq!(instruction.matches("- Add an image: ").count(), 1);
+    }
+
+    #[test]
+    fn test_placeholder_validation() {
+        let header = "---\nversion: 3\n---\n";
+        assert_eq!(
+            Template::parse("t", &format!("{}{{{{a}}}} {{{{b}}}}", header), &["a"]),
+            Err(TemplateError::UnknownPlaceholder {
+                file: "t".into(),
+                name: "b".into()
+            })
+        );
+        assert_eq!(
+            Template::parse("t", &format!("{}{{{{a}}}}", header), &["a", "b"]),
+            Err(TemplateError::MissingPlaceholder {
+                file: "t".into(),
+                name: "b".into()
+            })
+        );
+        assert_eq!(
+            Template::parse("t", "{{a}}", &["a"]),
+            Err(TemplateError::MissingVersion { file: "t".into() })
+        );
+
+        let template =
+            Template::parse("t", &format!("{}[{{{{a}}}}] {{{{ x }}}}\n", header), &["a"]).unwrap();
+        assert_eq!(template.version, "3");
+        assert_eq!(
+            template.render(&[("a", "{{a}}".to_string())]),
+            "[{{a}}] {{ x }}"
+        );
+    }
+
+    #[test]
+    fn test_hot_reload_keeps_last_valid_templates() {
+        let dir = std::env::temp_dir().join(format!("prompt-templates-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for file in [SYSTEM_TEMPLATE, USER_TEMPLATE, EXAMPLES_FILE] {
+            std::fs::copy(repo_prompts().join(file), dir.join(file)).unwrap();
+        }
+        let store = PromptStore::open(dir.clone(), true).unwrap();
+        assert_eq!(*store.current(), PromptSet::load(&repo_prompts()).unwrap());
+
+        let write_system = |source: &str| {
+            std::fs::write(dir.join(SYSTEM_TEMPLATE), source).unwrap();
+            // 更新時刻の分解能が粗いファイルシステムでも変更を検知できるようにする
+            let file = std::fs::File::options()
+                .write(true)
+                .open(dir.join(SYSTEM_TEMPLATE))
+                .unwrap();
+            file.set_modified(SystemTime::now() + std::time::Duration::from_secs(60))
+                .unwrap();
+        };
+        write_system("---\nversion: next\n---\n新しい指示\n{{examples}}\n");
+        assert_eq!(store.current().system.version, "next");
+        assert!(store
+            .current()
+            .system_instruction("背景", 1)
+            .starts_with("新しい指示\n- "));
+
+        write_system("---\nversion: 3\n---\n{{unknown}}\n");
+        assert_eq!(store.current().system.version, "next");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
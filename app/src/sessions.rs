@@ -0,0 +1,143 @@
+//! 名前付きセッションの操作
+//!
+//! データベースのあるデプロイではサーバー関数で、ないデプロイでは localStorage で、
+//! 同じ操作（一覧・作成・複製・名前の変更・削除・読み込み）を行う。
+
+use crate::api::{
+    create_session, delete_session, duplicate_session, list_sessions, load_session, rename_session,
+    reset_session, save_session,
+};
+use crate::local_store;
+use common::{SessionSnapshot, SessionSummary};
+use leptos::server_fn::ServerFnError;
+
+/// セッション名・テーマ名の最大文字数
+pub const MAX_SESSION_NAME_CHARS: usize = 80;
+
+/// セッション名・テーマ名を整える（前後の空白を除き、長すぎる分は切り詰める）。空なら None
+pub fn normalize_name(name: &str) -> Option<String> {
+    let name: String = name.trim().chars().take(MAX_SESSION_NAME_CHARS).collect();
+    let name = name.trim_end();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// セッションの保存先
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    Server,
+    Local,
+}
+
+/// 使える保存先と、そこにあるセッションの一覧
+pub async fn detect() -> (Backend, Vec<SessionSummary>) {
+    match list_sessions().await {
+        Ok(Some(sessions)) => (Backend::Server, sessions),
+        Ok(None) => (Backend::Local, local_store::list()),
+        Err(e) => {
+            log::error!("セッションの一覧を読み込めません: {:?}", e);
+            (Backend::Local, local_store::list())
+        }
+    }
+}
+
+pub async fn list(backend: Backend) -> Result<Vec<SessionSummary>, ServerFnError> {
+    match backend {
+        Backend::Server => Ok(list_sessions().await?.unwrap_or_default()),
+        Backend::Local => Ok(local_store::list()),
+    }
+}
+
+/// 空のセッションを作る
+pub async fn create(backend: Backend, name: String) -> Result<SessionSummary, ServerFnError> {
+    match backend {
+        Backend::Server => create_session(name).await,
+        Backend::Local => Ok(local_store::create(&name, &SessionSnapshot::default())),
+    }
+}
+
+pub async fn duplicate(
+    backend: Backend,
+    session_id: String,
+    name: String,
+) -> Result<SessionSummary, ServerFnError> {
+    match backend {
+        Backend::Server => duplicate_session(session_id, name).await,
+        Backend::Local => Ok(local_store::create(
+            &name,
+            &local_store::load(&session_id).unwrap_or_default(),
+        )),
+    }
+}
+
+pub async fn rename(
+    backend: Backend,
+    session_id: String,
+    name: String,
+) -> Result<(), ServerFnError> {
+    match backend {
+        Backend::Server => rename_session(session_id, name).await,
+        Backend::Local => {
+            local_store::rename(&session_id, &name);
+            Ok(())
+        }
+    }
+}
+
+pub async fn delete(backend: Backend, session_id: String) -> Result<(), ServerFnError> {
+    match backend {
+        Backend::Server => delete_session(session_id).await,
+        Backend::Local => {
+            local_store::delete(&session_id);
+            Ok(())
+        }
+    }
+}
+
+pub async fn load(
+    backend: Backend,
+    session_id: String,
+) -> Result<Option<SessionSnapshot>, ServerFnError> {
+    match backend {
+        Backend::Server => load_session(session_id).await,
+        Backend::Local => Ok(local_store::load(&session_id)),
+    }
+}
+
+/// クライアントで変更した会話とUI状態を保存する（localStorage には画面の変更時に保存される）
+pub async fn save(
+    backend: Backend,
+    session_id: String,
+    snapshot: SessionSnapshot,
+) -> Result<(), ServerFnError> {
+    match backend {
+        Backend::Server => save_session(session_id, snapshot).await,
+        Backend::Local => Ok(()),
+    }
+}
+
+/// セッションの会話とUI状態を空にする（localStorage には画面の変更時に保存される）
+pub async fn reset(backend: Backend, session_id: String) -> Result<(), ServerFnError> {
+    match backend {
+        Backend::Server => reset_session(session_id).await,
+        Backend::Local => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_name() {
+        assert_eq!(
+            normalize_name("  青いテーマ "),
+            Some("青いテーマ".to_string())
+        );
+        assert_eq!(normalize_name(" \n "), None);
+        let long = "あ".repeat(MAX_SESSION_NAME_CHARS + 10);
+        assert_eq!(
+            normalize_name(&long).unwrap().chars().count(),
+            MAX_SESSION_NAME_CHARS
+        );
+    }
+}
@@ -0,0 +1,379 @@
+//! 会話とUI状態の保存先と、セッションCookie
+//!
+//! 保存先の実装（SQLite）はサーバークレートが持ち、コンテキストとして提供する。
+//! 提供されていなければ保存せず、これまでどおりブラウザ上の状態だけで動く。
+//! Cookie はブラウザ（所有者）を識別し、所有者ごとに複数の名前付きセッションを持つ。
+
+use common::{
+    MessageInfo, SendMessageRequest, SendMessageResponse, SessionSnapshot, SessionSummary,
+    SessionVersion, Theme, UiDelta, UiOperations,
+};
+use http::header::{COOKIE, SET_COOKIE};
+use http::{HeaderMap, HeaderValue};
+use leptos::prelude::use_context;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// セッションIDを保持するCookie
+pub const SESSION_COOKIE: &str = "sc_session";
+
+/// 最後に開いていたセッションのIDを保持するCookie（サーバーで描画するセッションを選ぶのに使う）
+pub const ACTIVE_SESSION_COOKIE: &str = "sc_active";
+
+/// セッションCookieの有効期間（秒）
+const SESSION_MAX_AGE: u64 = 60 * 60 * 24 * 365;
+
+/// 保存先の読み書きエラー
+#[derive(Clone, Debug, PartialEq, thiserror::Error)]
+pub enum StoreError {
+    #[error("ストレージのエラー: {0}")]
+    Backend(String),
+    #[error("保存データが不正です: {0}")]
+    Corrupt(String),
+    #[error("セッションが見つかりません")]
+    NotFound,
+}
+
+/// 所有者（Cookie）ごとに、名前付きセッションの会話とUI状態を保存する。
+/// 他の所有者のセッションは読み書きできない（見つからないものとして扱う）
+pub trait SessionStore: Send + Sync {
+    /// 所有者のセッションの一覧（新しく保存した順）
+    fn list(&self, owner: &str) -> Result<Vec<SessionSummary>, StoreError>;
+    fn load(&self, owner: &str, session_id: &str) -> Result<Option<SessionSnapshot>, StoreError>;
+    /// 名前を付けて新しいセッションを作る。
+    /// `parent` は分岐したセッションの場合の、分岐元のセッションIDとメッセージID
+    fn create(
+        &self,
+        owner: &str,
+        session_id: &str,
+        name: &str,
+        parent: Option<(&str, usize)>,
+        snapshot: &SessionSnapshot,
+    ) -> Result<SessionSummary, StoreError>;
+    /// 会話とUI状態を保存する（なければ名前なしで作る）
+    fn save(
+        &self,
+        owner: &str,
+        session_id: &str,
+        snapshot: &SessionSnapshot,
+    ) -> Result<(), StoreError>;
+    fn rename(&self, owner: &str, session_id: &str, name: &str) -> Result<(), StoreError>;
+    /// セッションを削除する（版の履歴も消える）
+    fn delete(&self, owner: &str, session_id: &str) -> Result<(), StoreError>;
+
+    /// 会話とUI状態を保存し、新しい版として記録する（古い版は `crate::versions::MAX_VERSIONS` 件まで残す）。
+    /// `restored_from` は過去の版を復元した場合の、その版の番号
+    fn save_version(
+        &self,
+        owner: &str,
+        session_id: &str,
+        snapshot: &SessionSnapshot,
+        restored_from: Option<u32>,
+    ) -> Result<SessionVersion, StoreError>;
+    /// セッションの版の一覧（古い順）
+    fn list_versions(
+        &self,
+        owner: &str,
+        session_id: &str,
+    ) -> Result<Vec<SessionVersion>, StoreError>;
+
+    /// 共有用に固定したスナップショットを所有者のものとして保存する（`expires_at` と `now` はUNIX時刻の秒）。
+    /// 保存のたびに期限切れの共有を消し、所有者の共有は新しい順に `MAX_SHARES_PER_OWNER` 件まで残す
+    fn save_share(
+        &self,
+        owner: &str,
+        share_id: &str,
+        snapshot: &SessionSnapshot,
+        expires_at: Option<i64>,
+        now: i64,
+    ) -> Result<(), StoreError>;
+    /// 共有されたスナップショットを読み込む（`now` の時点で期限切れなら None）
+    fn load_share(&self, share_id: &str, now: i64) -> Result<Option<SessionSnapshot>, StoreError>;
+
+    /// 所有者のテーマの一覧（名前順）
+    fn list_themes(&self, owner: &str) -> Result<Vec<Theme>, StoreError>;
+    /// テーマを保存する（同じ名前のテーマは置き換える）。保存時刻を付けて返す
+    fn save_theme(&self, owner: &str, theme: &Theme) -> Result<Theme, StoreError>;
+    fn delete_theme(&self, owner: &str, name: &str) -> Result<(), StoreError>;
+}
+
+/// 所有者ごとに残す共有リンクの数（超えたら古いリンクから無効になる）
+pub const MAX_SHARES_PER_OWNER: usize = 100;
+
+/// コンテキストで共有する保存先
+pub type SharedSessionStore = Arc<dyn SessionStore>;
+
+/// 推測できない新しい所有者ID（Cookie の値）
+pub fn new_owner_id() -> String {
+    random_id()
+}
+
+/// 推測できない新しいセッションID
+pub fn new_session_id() -> String {
+    random_id()
+}
+
+/// 推測できない新しい共有ID
+pub fn new_share_id() -> String {
+    random_id()
+}
+
+/// 所有者ID・セッションID・共有IDの形式（32桁の16進数）か
+pub fn is_valid_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// 128ビットの乱数を16進数にしたもの
+fn random_id() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::fill(&mut bytes).expect("OSの乱数を取得できません");
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// 現在のUNIX時刻（秒）
+pub fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// Cookie ヘッダーから所有者IDを取り出す（形式が不正なものは無視する）
+pub fn owner_id_from_headers(headers: &HeaderMap) -> Option<String> {
+    id_cookie(headers, SESSION_COOKIE)
+}
+
+/// Cookie ヘッダーから最後に開いていたセッションのIDを取り出す（形式が不正なものは無視する）
+pub fn active_session_from_headers(headers: &HeaderMap) -> Option<String> {
+    id_cookie(headers, ACTIVE_SESSION_COOKIE)
+}
+
+// IDとして受け付けられる値の Cookie
+fn id_cookie(headers: &HeaderMap, cookie: &str) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, value)| *name == cookie && is_valid_id(value))
+        .map(|(_, value)| value.to_string())
+}
+
+/// セッションCookieの Set-Cookie の値（SESSION_COOKIE_SECURE=1 なら Secure を付ける）
+pub fn session_cookie(owner_id: &str) -> String {
+    cookie(SESSION_COOKIE, owner_id, SESSION_MAX_AGE)
+}
+
+/// 最後に開いていたセッションの Cookie の Set-Cookie の値（None なら消す）
+pub fn active_session_cookie(session_id: Option<&str>) -> String {
+    match session_id {
+        Some(id) => cookie(ACTIVE_SESSION_COOKIE, id, SESSION_MAX_AGE),
+        None => cookie(ACTIVE_SESSION_COOKIE, "", 0),
+    }
+}
+
+/// 現在のリクエストのレスポンスで、最後に開いていたセッションを設定する（None なら消す）
+pub fn set_active_session(session_id: Option<&str>) {
+    let response = use_context::<leptos_axum::ResponseOptions>();
+    if let (Some(response), Ok(value)) = (
+        response,
+        HeaderValue::from_str(&active_session_cookie(session_id)),
+    ) {
+        response.append_header(SET_COOKIE, value);
+    }
+}
+
+// サーバーだけが読む Cookie（SESSION_COOKIE_SECURE=1 なら Secure を付ける）
+fn cookie(name: &str, value: &str, max_age: u64) -> String {
+    let secure = matches!(
+        std::env::var("SESSION_COOKIE_SECURE").as_deref(),
+        Ok("1") | Ok("true")
+    );
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        name,
+        value,
+        max_age,
+        if secure { "; Secure" } else { "" }
+    )
+}
+
+/// 現在のリクエストの保存先と所有者ID。
+/// `create` なら、Cookieがないときに新しい所有者IDを発行してCookieを設定する
+pub fn current_owner(create: bool) -> Option<(SharedSessionStore, String)> {
+    let store = use_context::<SharedSessionStore>()?;
+    let parts = use_context::<http::request::Parts>()?;
+    if let Some(id) = owner_id_from_headers(&parts.headers) {
+        return Some((store, id));
+    }
+    if !create {
+        return None;
+    }
+    let id = new_owner_id();
+    let response = use_context::<leptos_axum::ResponseOptions>()?;
+    response.append_header(
+        SET_COOKIE,
+        HeaderValue::from_str(&session_cookie(&id)).ok()?,
+    );
+    Some((store, id))
+}
+
+/// AIの返信を加え、そのUI操作を適用した後の会話とUI状態。
+/// `ui_history` は保存済みのメッセージごとのUI状態の変更で、返信の分を加えて返す。
+/// クライアントから送られた会話とUI状態は、セッションの保存と同じく無害化してから使う
+pub fn snapshot_after_reply(
+    req: &SendMessageRequest,
+    res: &SendMessageResponse,
+    ui_history: BTreeMap<usize, UiDelta>,
+) -> SessionSnapshot {
+    let ops = UiOperations {
+        success: res.success,
+        chat_container_styles: res.chat_container_styles.clone(),
+        change_style_elements: res.change_style_elements.clone(),
+        new_elements: res.new_elements.clone(),
+    };
+    // 要素は送信されたユーザーのメッセージの後に挿入する（クライアントと同じ規則）
+    let anchor_id = req.messages.last().map(|m| m.id).unwrap_or(0);
+    let reply_id = req.messages.last().map(|m| m.id + 1).unwrap_or(0);
+    let mut snapshot = SessionSnapshot {
+        messages: req.messages.clone(),
+        ui_state: req.ui_state.clone(),
+        ui_history,
+    };
+    crate::ui_document::sanitize(&mut snapshot);
+    snapshot.ui_state.apply(anchor_id, reply_id, &ops);
+    snapshot.messages.push(MessageInfo {
+        id: reply_id,
+        is_user: false,
+        text: res.message.clone(),
+        ops: Some(ops),
+    });
+    crate::branches::record_history(&mut snapshot);
+    snapshot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_cookie_round_trip() {
+        let id = new_owner_id();
+        assert!(is_valid_id(&id));
+        assert_ne!(id, new_owner_id());
+        assert!(is_valid_id(&new_session_id()));
+        assert!(is_valid_id(&new_share_id()));
+
+        let cookie = session_cookie(&id);
+        assert!(cookie.starts_with(&format!("{}={};", SESSION_COOKIE, id)));
+        assert!(cookie.contains("HttpOnly"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_str(&format!("theme=dark; {}={}", SESSION_COOKIE, id)).unwrap(),
+        );
+        assert_eq!(owner_id_from_headers(&headers), Some(id.clone()));
+
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_static("sc_session=../../etc/passwd"),
+        );
+        assert_eq!(owner_id_from_headers(&headers), None);
+
+        // 最後に開いていたセッションは別の Cookie で、所有者IDとは混ざらない
+        let session_id = new_session_id();
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_str(&format!(
+                "{}={}; sc_active={}",
+                SESSION_COOKIE, id, session_id
+            ))
+            .unwrap(),
+        );
+        assert_eq!(owner_id_from_headers(&headers), Some(id));
+        assert_eq!(
+            active_session_from_headers(&headers),
+            Some(session_id.clone())
+        );
+        assert!(active_session_cookie(Some(&session_id))
+            .starts_with(&format!("{}={};", ACTIVE_SESSION_COOKIE, session_id)));
+        assert!(active_session_cookie(None).contains("Max-Age=0;"));
+    }
+
+    #[test]
+    fn test_snapshot_after_reply_applies_ops() {
+        let req = SendMessageRequest {
+            text: "ボタンを追加して".to_string(),
+            messages: vec![
+                MessageInfo {
+                    id: 0,
+                    is_user: false,
+                    text: "ようこそ".to_string(),
+                    ops: None,
+                },
+                MessageInfo {
+                    id: 1,
+                    is_user: true,
+                    text: "ボタンを追加して".to_string(),
+                    ops: None,
+                },
+            ],
+            ui_state: Default::default(),
+            generation: Default::default(),
+            locale: Default::default(),
+            session_id: None,
+        };
+        let res = SendMessageResponse {
+            success: true,
+            message: "追加しました".to_string(),
+            chat_container_styles: Some("background: #eee;".to_string()),
+            change_style_elements: None,
+            new_elements: Some(vec![common::DynamicElementData {
+                id: 10,
+                tag: "button".to_string(),
+                text: Some("押す".to_string()),
+                styles: None,
+                attributes: None,
+            }]),
+            contrast_reports: None,
+            prompt_version: None,
+            usage: None,
+        };
+
+        let snapshot = snapshot_after_reply(&req, &res, BTreeMap::new());
+        assert_eq!(snapshot.messages.len(), 3);
+        let reply = &snapshot.messages[2];
+        assert_eq!((reply.id, reply.is_user), (2, false));
+        assert_eq!(reply.ops.as_ref().unwrap().new_elements, res.new_elements);
+        assert_eq!(snapshot.ui_state.chat_container_styles, "background: #eee;");
+        assert_eq!(snapshot.ui_state.dynamic_elements[&1][0].id, 10);
+        // 返信の時点のUI状態の変更が記録される
+        assert_eq!(crate::branches::state_at(&snapshot, 2), snapshot.ui_state);
+
+        // クライアントが送ったUI状態と過去のUI操作は、無害化してから保存する
+        let mut tampered = req.clone();
+        tampered.ui_state.role_styles.chrome = "background: url(javascript:alert(1))".to_string();
+        tampered.ui_state.dynamic_elements.insert(
+            0,
+            vec![common::DynamicElementData {
+                id: 3,
+                tag: "script".to_string(),
+                text: Some("alert(1)".to_string()),
+                styles: None,
+                attributes: None,
+            }],
+        );
+        tampered.messages[0].ops = Some(UiOperations {
+            success: true,
+            chat_container_styles: Some("background: url(javascript:alert(1))".to_string()),
+            change_style_elements: None,
+            new_elements: None,
+        });
+        let snapshot = snapshot_after_reply(&tampered, &res, BTreeMap::new());
+        let saved = serde_json::to_string(&snapshot).unwrap();
+        assert!(!saved.contains("javascript"), "{}", saved);
+        assert!(!saved.contains("script"), "{}", saved);
+        assert_eq!(snapshot.ui_state.dynamic_elements[&1][0].id, 10);
+    }
+}
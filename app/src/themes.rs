@@ -0,0 +1,230 @@
+//! テーマのライブラリ
+//!
+//! 現在のスタイルの層（コンテナ、役割ごとのメッセージ、入力欄などの周辺部分）に名前を
+//! 付けて保存し、どのセッションにも重ねて適用できるようにする。保存先はセッションと同じく、
+//! データベースがあればサーバー、なければ localStorage。
+//! 「テーマ『夜』を適用して」のような指示はモデルを呼ばずにここで解釈する
+//! （保存済みのテーマが見つからなければ、ふつうの指示としてモデルに渡す）。
+
+use crate::api::{delete_theme, list_themes, save_theme};
+use crate::local_store;
+use crate::sessions::{normalize_name, Backend};
+use common::Theme;
+use leptos::server_fn::ServerFnError;
+
+/// テーマの適用を指示する動詞（日本語）
+const APPLY_VERBS_JA: [&str; 5] = ["適用", "使", "にして", "に変え", "に切り替え"];
+
+/// テーマ名を囲む括弧と引用符
+const QUOTES: [(char, char); 4] = [('『', '』'), ('「', '」'), ('"', '"'), ('“', '”')];
+
+pub async fn list(backend: Backend) -> Result<Vec<Theme>, ServerFnError> {
+    match backend {
+        Backend::Server => Ok(list_themes().await?.unwrap_or_default()),
+        Backend::Local => Ok(local_store::list_themes()),
+    }
+}
+
+/// テーマを保存する（同じ名前のテーマは置き換える）
+pub async fn save(backend: Backend, theme: Theme) -> Result<Theme, ServerFnError> {
+    match backend {
+        Backend::Server => save_theme(theme).await,
+        Backend::Local => Ok(local_store::save_theme(&theme)),
+    }
+}
+
+pub async fn delete(backend: Backend, name: String) -> Result<(), ServerFnError> {
+    match backend {
+        Backend::Server => delete_theme(name).await,
+        Backend::Local => {
+            local_store::delete_theme(&name);
+            Ok(())
+        }
+    }
+}
+
+/// 同じ名前のテーマを置き換えて、名前順に並べる
+pub fn upsert(themes: &mut Vec<Theme>, theme: Theme) {
+    themes.retain(|t| t.name != theme.name);
+    themes.push(theme);
+    themes.sort_by(|a, b| a.name.cmp(&b.name));
+}
+
+/// 名前でテーマを探す（完全に一致するものがなければ大文字と小文字を区別しない）
+pub fn find<'a>(themes: &'a [Theme], name: &str) -> Option<&'a Theme> {
+    themes
+        .iter()
+        .find(|t| t.name == name)
+        .or_else(|| themes.iter().find(|t| t.name.eq_ignore_ascii_case(name)))
+}
+
+/// テーマの適用の指示なら、そのテーマ名を返す。
+/// 「テーマ『夜』を適用して」「『夜』のテーマにして」「apply theme "Night"」「use the theme Night」
+/// のように、テーマ名が「テーマ」の直後・直前にあるか、英語では決まった形の指示だけを受け付ける
+pub fn parse_theme_command(text: &str) -> Option<String> {
+    let text = text.trim();
+    if let Some((before, name, after)) = quoted(text) {
+        // 「テーマ『夜』…」または「『夜』のテーマ…」で、その後ろに適用の動詞がある
+        let rest = if before.trim_end().ends_with("テーマ") {
+            Some(after)
+        } else {
+            after.strip_prefix("のテーマ")
+        };
+        if let Some(rest) = rest {
+            return APPLY_VERBS_JA
+                .iter()
+                .any(|verb| rest.contains(verb))
+                .then(|| normalize_name(name))
+                .flatten();
+        }
+    }
+
+    // apply|use [the] theme <名前>（名前が1語でなければ引用符で囲む）
+    let lower = text.to_ascii_lowercase();
+    let rest = ["apply ", "use "]
+        .iter()
+        .find_map(|verb| lower.strip_prefix(verb))?;
+    let rest = rest
+        .strip_prefix("the ")
+        .unwrap_or(rest)
+        .strip_prefix("theme ")?;
+    let name = text[text.len() - rest.len()..].trim_end_matches(['.', '!', '?']);
+    match quoted(name) {
+        Some((before, name, after)) if before.trim().is_empty() && after.trim().is_empty() => {
+            normalize_name(name)
+        }
+        Some(_) => None,
+        None if name.trim().contains(char::is_whitespace) => None,
+        None => normalize_name(name),
+    }
+}
+
+// 最初に括弧や引用符で囲まれた部分と、その前後の文字列
+fn quoted(text: &str) -> Option<(&str, &str, &str)> {
+    let (start, open, close) = QUOTES
+        .iter()
+        .filter_map(|&(open, close)| text.find(open).map(|start| (start, open, close)))
+        .min_by_key(|(start, _, _)| *start)?;
+    let inner = &text[start + open.len_utf8()..];
+    let end = inner.find(close)?;
+    Some((
+        &text[..start],
+        &inner[..end],
+        &inner[end + close.len_utf8()..],
+    ))
+}
+
+/// テーマのスタイルをサニタイズする（クライアントから送られたものを保存する前に使う）
+#[cfg(feature = "ssr")]
+pub fn sanitize_theme(theme: &mut Theme) {
+    let sanitizer = crate::css_sanitizer::CssSanitizer::new();
+    for styles in [
+        &mut theme.container_styles,
+        &mut theme.role_styles.user_message,
+        &mut theme.role_styles.ai_message,
+        &mut theme.role_styles.chrome,
+    ] {
+        *styles = sanitizer.sanitize_css_string(styles);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{MessageInfo, RoleStyles, UiState};
+
+    #[test]
+    fn test_parse_theme_command() {
+        let parse = |text: &str| parse_theme_command(text);
+        assert_eq!(parse("テーマ『夜』を適用して"), Some("夜".to_string()));
+        assert_eq!(parse("「朝 」のテーマにして"), Some("朝".to_string()));
+        assert_eq!(parse("apply theme \"Night\""), Some("Night".to_string()));
+        assert_eq!(parse("Use the theme Night."), Some("Night".to_string()));
+        // テーマの適用ではない指示はモデルに渡す
+        assert_eq!(parse("テーマ『夜』みたいな配色を考えて"), None);
+        assert_eq!(parse("背景を『夜』っぽくして"), None);
+        assert_eq!(parse("make the theme darker"), None);
+        assert_eq!(parse("テーマ『』を適用して"), None);
+        // テーマ名が「テーマ」と隣り合っていない、ふつうのスタイルの指示
+        assert_eq!(parse("テーマカラーを「青」にして"), None);
+        assert_eq!(parse("「青」をテーマカラーにして"), None);
+        assert_eq!(parse("apply theme colors to the header"), None);
+        assert_eq!(parse("use theme \"Night\" colors for the header"), None);
+        assert_eq!(parse("apply a theme-like look"), None);
+        assert_eq!(
+            parse("use theme \"Night Blue\"!"),
+            Some("Night Blue".to_string())
+        );
+
+        let themes = [Theme {
+            name: "Night".to_string(),
+            ..Default::default()
+        }];
+        assert!(find(&themes, "night").is_some());
+        assert!(find(&themes, "夜").is_none());
+
+        let theme = |name: &str, updated_at| Theme {
+            name: name.to_string(),
+            updated_at,
+            ..Default::default()
+        };
+        let mut themes = vec![theme("夜", 1)];
+        upsert(&mut themes, theme("朝", 2));
+        upsert(&mut themes, theme("夜", 3));
+        assert_eq!(themes, vec![theme("夜", 3), theme("朝", 2)]);
+    }
+
+    #[test]
+    fn test_capture_and_layer_themes() {
+        let messages = [
+            MessageInfo {
+                id: 0,
+                is_user: false,
+                text: "ようこそ".to_string(),
+                ops: None,
+            },
+            MessageInfo {
+                id: 1,
+                is_user: true,
+                text: "暗くして".to_string(),
+                ops: None,
+            },
+        ];
+        let mut state = UiState {
+            chat_container_styles: "background: #000".to_string(),
+            ..Default::default()
+        };
+        state.element_styles.insert(1, "color: white;".to_string());
+        state.role_styles.chrome = "background: #111;".to_string();
+
+        let night = state.capture_theme("夜", &messages);
+        assert_eq!(night.container_styles, "background: #000");
+        assert_eq!(
+            night.role_styles,
+            RoleStyles {
+                user_message: "color: white;".to_string(),
+                ai_message: String::new(),
+                chrome: "background: #111;".to_string(),
+            }
+        );
+
+        // 別のセッションに重ねて適用する（メッセージごとのスタイルはテーマに置き換わる）
+        let mut other = UiState::default();
+        other.element_styles.insert(3, "color: red;".to_string());
+        other.apply_theme(&night);
+        other.apply_theme(&Theme {
+            name: "大きな文字".to_string(),
+            role_styles: RoleStyles {
+                user_message: "font-size: 18px".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        assert_eq!(other.chat_container_styles, "background: #000;");
+        assert_eq!(
+            other.role_styles.user_message,
+            "color: white; font-size: 18px;"
+        );
+        assert!(other.element_styles.is_empty());
+    }
+}
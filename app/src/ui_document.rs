@@ -0,0 +1,373 @@
+//! UI状態のJSONドキュメント（書き出しと読み込み）
+//!
+//! 形式（バージョン1）:
+//!
+//! ```json
+//! {
+//!   "format": "self-changer/ui",
+//!   "version": 1,
+//!   "messages": [{ "id": 1, "is_user": true, "text": "...", "ops": null }],
+//!   "ui_state": {
+//!     "chat_container_styles": "background: #fff;",
+//!     "element_styles": { "1": "color: red;" },
+//!     "dynamic_elements": { "1": [{ "id": 0, "tag": "button", "text": "OK", "styles": null, "attributes": null }] },
+//!     "role_styles": { "user_message": "", "ai_message": "", "chrome": "" }
+//!   }
+//! }
+//! ```
+//!
+//! キーは常に同じ順で書き出すため、デザインのプリセットとしてリポジトリで差分を管理できる。
+//! 読み込むときはサーバーで上限と整合性を検証し、AIの出力と同じサニタイズを適用する。
+
+use crate::errors::ImportError;
+use common::{MessageInfo, SessionSnapshot, UiState};
+use serde::{Deserialize, Serialize};
+
+/// ドキュメントの種類を示す値
+pub const DOCUMENT_FORMAT: &str = "self-changer/ui";
+
+/// 現在のドキュメントのバージョン
+pub const DOCUMENT_VERSION: u32 = 1;
+
+/// 書き出したファイルの名前
+pub const DOCUMENT_FILE_NAME: &str = "self-changer.json";
+
+#[derive(Serialize, Deserialize)]
+struct UiDocument {
+    format: String,
+    version: u32,
+    #[serde(default)]
+    messages: Vec<MessageInfo>,
+    #[serde(default)]
+    ui_state: UiState,
+}
+
+/// 会話とUI状態をドキュメントとして書き出す（キーは辞書順）
+pub fn to_json(snapshot: &SessionSnapshot) -> String {
+    let document = UiDocument {
+        format: DOCUMENT_FORMAT.to_string(),
+        version: DOCUMENT_VERSION,
+        messages: snapshot.messages.clone(),
+        ui_state: snapshot.ui_state.clone(),
+    };
+    // Value を経由すると、HashMap のキーも並べ替えられる
+    serde_json::to_value(&document)
+        .and_then(|value| serde_json::to_string_pretty(&value))
+        .unwrap_or_default()
+}
+
+/// ドキュメントを読む（形式とバージョンのみ確認し、内容の検証はしない）
+pub fn parse(json: &str) -> Result<SessionSnapshot, ImportError> {
+    let value: serde_json::Value =
+        serde_json::from_str(json).map_err(|e| ImportError::InvalidDocument(e.to_string()))?;
+    if value.get("format").and_then(|f| f.as_str()) != Some(DOCUMENT_FORMAT) {
+        return Err(ImportError::UnknownFormat);
+    }
+    let version = value
+        .get("version")
+        .and_then(|v| v.as_u64())
+        .ok_or_else(|| ImportError::InvalidDocument("version がありません".to_string()))?;
+    if version != DOCUMENT_VERSION as u64 {
+        return Err(ImportError::UnsupportedVersion {
+            version: u32::try_from(version).unwrap_or(u32::MAX),
+            supported: DOCUMENT_VERSION,
+        });
+    }
+    let document: UiDocument =
+        serde_json::from_value(value).map_err(|e| ImportError::InvalidDocument(e.to_string()))?;
+    Ok(SessionSnapshot {
+        messages: document.messages,
+        ui_state: document.ui_state,
+        ui_history: Default::default(),
+    })
+}
+
+/// ドキュメントをJSONファイルとしてダウンロードさせる
+pub fn download_json(snapshot: &SessionSnapshot) {
+    crate::export::download_file(
+        &to_json(snapshot),
+        "application/json;charset=utf-8",
+        DOCUMENT_FILE_NAME,
+    );
+}
+
+/// ファイル選択欄で選ばれたファイルの内容を読む（サーバーでは常に None）
+pub async fn read_selected_file(ev: &leptos::ev::Event) -> Option<String> {
+    #[cfg(not(feature = "ssr"))]
+    {
+        use wasm_bindgen::JsCast;
+        let input: web_sys::HtmlInputElement = ev.target()?.dyn_into().ok()?;
+        let file = input.files()?.get(0)?;
+        // 同じファイルをもう一度選んでも change が発生するよう、選択を戻す
+        input.set_value("");
+        let text = wasm_bindgen_futures::JsFuture::from(file.text())
+            .await
+            .ok()?;
+        text.as_string()
+    }
+    #[cfg(feature = "ssr")]
+    {
+        let _ = ev;
+        None
+    }
+}
+
+/// 読み込んだ内容にAIの出力と同じサニタイズを適用する。除去した数を返す
+#[cfg(feature = "ssr")]
+pub fn sanitize(snapshot: &mut SessionSnapshot) -> usize {
+    use crate::ai_output::sanitize_elements;
+    use crate::css_sanitizer::CssSanitizer;
+
+    let sanitizer = CssSanitizer::new();
+    let mut removed = 0;
+    let mut clean = |css: &mut String| {
+        let sanitized = sanitizer.sanitize_css_string(css);
+        if sanitized != css.trim().trim_end_matches(';') {
+            removed += 1;
+        }
+        *css = sanitized;
+    };
+
+    let state = &mut snapshot.ui_state;
+    clean(&mut state.chat_container_styles);
+    state.element_styles.values_mut().for_each(&mut clean);
+    clean(&mut state.role_styles.user_message);
+    clean(&mut state.role_styles.ai_message);
+    clean(&mut state.role_styles.chrome);
+    for elements in state.dynamic_elements.values_mut() {
+        elements
+            .iter_mut()
+            .filter_map(|e| e.styles.as_mut())
+            .for_each(&mut clean);
+    }
+    // 分岐すると途中の時点のUI状態が表示されるため、その変更も同じように扱う
+    for delta in snapshot.ui_history.values_mut() {
+        delta.chat_container_styles.iter_mut().for_each(&mut clean);
+        delta
+            .element_styles
+            .values_mut()
+            .flatten()
+            .for_each(&mut clean);
+        if let Some(roles) = delta.role_styles.as_mut() {
+            clean(&mut roles.user_message);
+            clean(&mut roles.ai_message);
+            clean(&mut roles.chrome);
+        }
+        for elements in delta.dynamic_elements.values_mut().flatten() {
+            elements
+                .iter_mut()
+                .filter_map(|e| e.styles.as_mut())
+                .for_each(&mut clean);
+        }
+    }
+    // 過去の返信のUI操作も次のリクエストでモデルに渡すため、同じように扱う
+    for ops in snapshot.messages.iter_mut().filter_map(|m| m.ops.as_mut()) {
+        ops.chat_container_styles.iter_mut().for_each(&mut clean);
+        ops.change_style_elements
+            .iter_mut()
+            .flatten()
+            .for_each(|u| clean(&mut u.styles));
+        for elements in ops.new_elements.iter_mut() {
+            elements
+                .iter_mut()
+                .filter_map(|e| e.styles.as_mut())
+                .for_each(&mut clean);
+        }
+    }
+
+    for elements in state.dynamic_elements.values_mut() {
+        removed += sanitize_elements(elements);
+    }
+    for elements in snapshot
+        .ui_history
+        .values_mut()
+        .flat_map(|delta| delta.dynamic_elements.values_mut().flatten())
+    {
+        removed += sanitize_elements(elements);
+    }
+    for elements in snapshot
+        .messages
+        .iter_mut()
+        .filter_map(|m| m.ops.as_mut())
+        .filter_map(|ops| ops.new_elements.as_mut())
+    {
+        removed += sanitize_elements(elements);
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{DynamicElementData, UiOperations};
+    use std::collections::HashMap;
+
+    fn snapshot() -> SessionSnapshot {
+        let mut snapshot = SessionSnapshot {
+            messages: vec![
+                MessageInfo {
+                    id: 1,
+                    is_user: true,
+                    text: "ボタンを追加して".to_string(),
+                    ops: None,
+                },
+                MessageInfo {
+                    id: 2,
+                    is_user: false,
+                    text: "追加しました".to_string(),
+                    ops: Some(UiOperations {
+                        success: true,
+                        chat_container_styles: Some("background: #fafafa".to_string()),
+                        change_style_elements: None,
+                        new_elements: None,
+                    }),
+                },
+            ],
+            ..Default::default()
+        };
+        snapshot.ui_state.chat_container_styles = "background: #fafafa".to_string();
+        snapshot
+            .ui_state
+            .element_styles
+            .insert(2, "color: navy".to_string());
+        snapshot.ui_state.dynamic_elements.insert(
+            1,
+            vec![DynamicElementData {
+                id: 0,
+                tag: "a".to_string(),
+                text: Some("リンク".to_string()),
+                styles: Some("color: blue".to_string()),
+                attributes: Some(HashMap::from([(
+                    "href".to_string(),
+                    "https://example.com".to_string(),
+                )])),
+            }],
+        );
+        snapshot
+    }
+
+    #[test]
+    fn test_round_trip_is_lossless_and_stable() {
+        let json = to_json(&snapshot());
+        assert_eq!(parse(&json), Ok(snapshot()));
+        assert_eq!(to_json(&parse(&json).unwrap()), json);
+        assert!(json.contains("\"format\": \"self-changer/ui\""));
+    }
+
+    #[test]
+    fn test_rejects_other_formats_and_versions() {
+        assert!(matches!(
+            parse("{\"format\""),
+            Err(ImportError::InvalidDocument(_))
+        ));
+        assert_eq!(
+            parse("{\"version\": 1, \"messages\": []}"),
+            Err(ImportError::UnknownFormat)
+        );
+        assert_eq!(
+            parse("{\"format\": \"self-changer/ui\", \"version\": 2}"),
+            Err(ImportError::UnsupportedVersion {
+                version: 2,
+                supported: 1
+            })
+        );
+        // メッセージがなくてもよい（スタイルだけのプリセット）
+        let preset = parse("{\"format\": \"self-changer/ui\", \"version\": 1, \"ui_state\": {\"chat_container_styles\": \"background: #000\", \"element_styles\": {}, \"dynamic_elements\": {}}}").unwrap();
+        assert!(preset.messages.is_empty());
+        assert_eq!(preset.ui_state.chat_container_styles, "background: #000");
+    }
+
+    #[test]
+    fn test_failed_import_leaves_history_valid() {
+        use crate::errors::RequestRejection;
+        use crate::request_validation::{validate_history, RequestLimits};
+
+        let mut history = vec![
+            MessageInfo {
+                id: 0,
+                is_user: false,
+                text: "ようこそ".to_string(),
+                ops: None,
+            },
+            MessageInfo {
+                id: 1,
+                is_user: true,
+                text: "青くして".to_string(),
+                ops: None,
+            },
+            MessageInfo {
+                id: 2,
+                is_user: false,
+                text: "変更しました".to_string(),
+                ops: None,
+            },
+        ];
+        let limits = RequestLimits::default();
+        let ui_state = UiState::default();
+
+        // 読み込みに失敗しても会話には何も追加しないので、続けて送信できる
+        assert!(parse("{\"format\"").is_err());
+        history.push(MessageInfo {
+            id: 3,
+            is_user: true,
+            text: "赤くして".to_string(),
+            ops: None,
+        });
+        assert_eq!(validate_history(&history, &ui_state, &limits), Ok(()));
+
+        // エラーをAIのメッセージとして挟むと、以降の検証がすべて拒否される
+        history.insert(
+            3,
+            MessageInfo {
+                id: 3,
+                is_user: false,
+                text: "読み込めません".to_string(),
+                ops: None,
+            },
+        );
+        history[4].id = 4;
+        assert_eq!(
+            validate_history(&history, &ui_state, &limits),
+            Err(RequestRejection::UnexpectedAiMessage { id: 3 })
+        );
+    }
+
+    #[test]
+    fn test_sanitize_imported_document() {
+        let mut imported = snapshot();
+        imported.ui_state.chat_container_styles =
+            "background: url(javascript:alert(1))".to_string();
+        imported
+            .ui_state
+            .dynamic_elements
+            .get_mut(&1)
+            .unwrap()
+            .push(DynamicElementData {
+                id: 1,
+                tag: "script".to_string(),
+                text: Some("alert(1)".to_string()),
+                styles: None,
+                attributes: None,
+            });
+        if let Some(a) = imported.ui_state.dynamic_elements.get_mut(&1).unwrap()[0]
+            .attributes
+            .as_mut()
+        {
+            a.insert("onclick".to_string(), "alert(1)".to_string());
+        }
+
+        assert_eq!(sanitize(&mut imported), 3);
+        assert!(!imported
+            .ui_state
+            .chat_container_styles
+            .contains("javascript"));
+        let elements = &imported.ui_state.dynamic_elements[&1];
+        assert_eq!(elements.len(), 1);
+        assert_eq!(elements[0].attributes.as_ref().unwrap().len(), 1);
+
+        // 問題のないドキュメントは何も変わらない
+        let mut clean = snapshot();
+        assert_eq!(sanitize(&mut clean), 0);
+        assert_eq!(clean, snapshot());
+    }
+}
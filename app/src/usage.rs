@@ -0,0 +1,143 @@
+//! モデル呼び出しのトークン数と料金の計算
+
+use crate::context_window::estimate_tokens;
+use common::TokenUsage;
+use std::collections::HashMap;
+
+/// 100万トークンあたりの料金（USD）
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ModelPrice {
+    pub input_per_million: f64,
+    pub output_per_million: f64,
+}
+
+/// モデル名ごとの料金表
+#[derive(Clone, Debug, PartialEq)]
+pub struct PriceTable {
+    prices: HashMap<String, ModelPrice>,
+}
+
+impl Default for PriceTable {
+    fn default() -> Self {
+        let prices = [
+            ("gemini-2.0-flash", 0.10, 0.40),
+            ("gemini-2.0-flash-lite", 0.075, 0.30),
+        ]
+        .into_iter()
+        .map(|(model, input, output)| {
+            (
+                model.to_string(),
+                ModelPrice {
+                    input_per_million: input,
+                    output_per_million: output,
+                },
+            )
+        })
+        .collect();
+        Self { prices }
+    }
+}
+
+impl PriceTable {
+    /// 既定の料金表を環境変数 MODEL_PRICES（"モデル名=入力:出力,..."）で上書きする
+    pub fn from_env() -> Self {
+        let mut table = Self::default();
+        table.apply_overrides(&std::env::var("MODEL_PRICES").unwrap_or_default());
+        table
+    }
+
+    // 解釈できない項目はログに残して無視する
+    fn apply_overrides(&mut self, value: &str) {
+        for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let parsed = entry.split_once('=').and_then(|(model, prices)| {
+                let (input, output) = prices.split_once(':')?;
+                Some((
+                    model.trim().to_string(),
+                    ModelPrice {
+                        input_per_million: input.trim().parse().ok()?,
+                        output_per_million: output.trim().parse().ok()?,
+                    },
+                ))
+            });
+            match parsed {
+                Some((model, price)) => {
+                    self.prices.insert(model, price);
+                }
+                None => log::warn!("MODEL_PRICES の項目を解釈できません: {}", entry),
+            }
+        }
+    }
+
+    pub fn cost(&self, model: &str, prompt_tokens: u64, completion_tokens: u64) -> Option<f64> {
+        let price = self.prices.get(model)?;
+        Some(
+            (prompt_tokens as f64 * price.input_per_million
+                + completion_tokens as f64 * price.output_per_million)
+                / 1_000_000.0,
+        )
+    }
+}
+
+/// プロバイダーが報告したトークン数、なければ入出力のテキストから推定した値で使用量を求める
+pub fn token_usage(
+    model: &str,
+    reported: Option<(u64, u64)>,
+    estimated_prompt_tokens: usize,
+    completion_text: &str,
+    prices: &PriceTable,
+) -> TokenUsage {
+    let (prompt_tokens, completion_tokens, estimated) = match reported {
+        Some((prompt, completion)) if prompt > 0 => (prompt, completion, false),
+        _ => (
+            estimated_prompt_tokens as u64,
+            estimate_tokens(completion_text) as u64,
+            true,
+        ),
+    };
+    TokenUsage {
+        model: model.to_string(),
+        prompt_tokens,
+        completion_tokens,
+        estimated,
+        cost_usd: prices.cost(model, prompt_tokens, completion_tokens),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cost_from_reported_or_estimated_tokens() {
+        let prices = PriceTable::default();
+        let reported = token_usage("gemini-2.0-flash", Some((2_000, 500)), 9_999, "", &prices);
+        assert!(!reported.estimated);
+        assert_eq!(reported.prompt_tokens, 2_000);
+        assert!((reported.cost_usd.unwrap() - 0.0004).abs() < 1e-12);
+
+        let estimated = token_usage(
+            "gemini-2.0-flash",
+            None,
+            1_000,
+            "{\"success\": true}",
+            &prices,
+        );
+        assert!(estimated.estimated);
+        assert_eq!(estimated.prompt_tokens, 1_000);
+        assert_eq!(estimated.completion_tokens, 5);
+
+        assert_eq!(token_usage("unknown", None, 10, "", &prices).cost_usd, None);
+    }
+
+    #[test]
+    fn test_price_overrides() {
+        let mut prices = PriceTable::default();
+        prices.apply_overrides("gemini-2.0-flash=1:2, custom-model = 0.5:0.5, broken=1");
+        assert_eq!(
+            prices.cost("gemini-2.0-flash", 1_000_000, 1_000_000),
+            Some(3.0)
+        );
+        assert_eq!(prices.cost("custom-model", 2_000_000, 0), Some(1.0));
+        assert_eq!(prices.cost("broken", 1, 1), None);
+    }
+}
@@ -0,0 +1,323 @@
+//! セッションの版の履歴（タイムライン）
+//!
+//! アシスタントの返信やテーマの適用、読み込みのたびに、その時点の会話とUI状態を版として
+//! 記録する。データベースがあればサーバーが保存のたびに記録し、なければ localStorage に記録する。
+//! 過去の版の復元は履歴を書き換えず、その内容を新しい版として記録する。
+//! 前の版との差分は、表示する言語で書けるよう変更の一覧として求める。
+
+use crate::api::{list_versions, restore_version};
+use crate::base_styles::bubble_styles;
+use crate::css_sanitizer::declarations;
+use crate::local_store;
+use crate::sessions::Backend;
+use common::{DynamicElementData, SessionSnapshot, SessionVersion};
+use leptos::server_fn::ServerFnError;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// セッションごとに残す版の数（超えたら古い版から消す）
+pub const MAX_VERSIONS: usize = 50;
+
+pub async fn list(
+    backend: Backend,
+    session_id: String,
+) -> Result<Vec<SessionVersion>, ServerFnError> {
+    match backend {
+        Backend::Server => list_versions(session_id).await,
+        Backend::Local => Ok(local_store::list_versions(&session_id)),
+    }
+}
+
+/// 現在の会話とUI状態を版として記録する（サーバーは保存のたびに記録するため何もしない）
+pub fn record(backend: Backend, session_id: &str, snapshot: &SessionSnapshot) {
+    if backend == Backend::Local {
+        local_store::save_version(session_id, snapshot, None);
+    }
+}
+
+/// 過去の版の内容を新しい版として記録し、その版を返す
+pub async fn restore(
+    backend: Backend,
+    session_id: String,
+    number: u32,
+) -> Result<SessionVersion, ServerFnError> {
+    match backend {
+        Backend::Server => restore_version(session_id, number).await,
+        Backend::Local => local_store::list_versions(&session_id)
+            .into_iter()
+            .find(|v| v.number == number)
+            .map(|v| local_store::save_version(&session_id, &v.snapshot, Some(number)))
+            .ok_or_else(|| ServerFnError::new("版が見つかりません")),
+    }
+}
+
+/// 新しい版を末尾に加え、古い版を `MAX_VERSIONS` 件まで減らす
+pub fn push_version(
+    versions: &mut Vec<SessionVersion>,
+    snapshot: &SessionSnapshot,
+    restored_from: Option<u32>,
+    now: i64,
+) -> SessionVersion {
+    let version = SessionVersion {
+        number: versions.last().map_or(1, |v| v.number + 1),
+        created_at: now,
+        restored_from,
+        snapshot: snapshot.clone(),
+    };
+    versions.push(version.clone());
+    let excess = versions.len().saturating_sub(MAX_VERSIONS);
+    versions.drain(..excess);
+    version
+}
+
+/// スタイルの変更先
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StyleTarget {
+    Container,
+    UserMessages,
+    AiMessages,
+    Chrome,
+    /// メッセージID
+    Message(usize),
+}
+
+/// 前の版からの変更
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    /// プロパティの値の変更（追加は before が None、削除は after が None）
+    Style {
+        target: StyleTarget,
+        property: String,
+        before: Option<String>,
+        after: Option<String>,
+    },
+    /// メッセージの後ろの動的要素の増減（タグごと）
+    Elements {
+        anchor: usize,
+        tag: String,
+        delta: i64,
+    },
+}
+
+/// 2つの版の間のUIの変更（コンテナ、役割ごとのスタイル、メッセージ、動的要素の順）
+pub fn diff(before: &SessionSnapshot, after: &SessionSnapshot) -> Vec<Change> {
+    let (old, new) = (&before.ui_state, &after.ui_state);
+    let mut changes = Vec::new();
+    for (target, old_styles, new_styles) in [
+        (
+            StyleTarget::Container,
+            &old.chat_container_styles,
+            &new.chat_container_styles,
+        ),
+        (
+            StyleTarget::UserMessages,
+            &old.role_styles.user_message,
+            &new.role_styles.user_message,
+        ),
+        (
+            StyleTarget::AiMessages,
+            &old.role_styles.ai_message,
+            &new.role_styles.ai_message,
+        ),
+        (
+            StyleTarget::Chrome,
+            &old.role_styles.chrome,
+            &new.role_styles.chrome,
+        ),
+    ] {
+        style_changes(&mut changes, target, &[old_styles], &[new_styles]);
+    }
+
+    // メッセージごとのスタイルは吹き出しのベーススタイルに重ねた値で比べる
+    let ids: BTreeSet<usize> = old
+        .element_styles
+        .keys()
+        .chain(new.element_styles.keys())
+        .copied()
+        .collect();
+    for id in ids {
+        let (old_extra, new_extra) = (old.element_styles.get(&id), new.element_styles.get(&id));
+        if old_extra == new_extra {
+            continue;
+        }
+        let Some(is_user) = [after, before]
+            .iter()
+            .find_map(|s| s.messages.iter().find(|m| m.id == id).map(|m| m.is_user))
+        else {
+            continue;
+        };
+        let base = bubble_styles(is_user);
+        style_changes(
+            &mut changes,
+            StyleTarget::Message(id),
+            &[base, old_extra.map_or("", String::as_str)],
+            &[base, new_extra.map_or("", String::as_str)],
+        );
+    }
+
+    let anchors: BTreeSet<usize> = old
+        .dynamic_elements
+        .keys()
+        .chain(new.dynamic_elements.keys())
+        .copied()
+        .collect();
+    for anchor in anchors {
+        let old_tags = tag_counts(old.dynamic_elements.get(&anchor));
+        let new_tags = tag_counts(new.dynamic_elements.get(&anchor));
+        let tags: BTreeSet<&String> = old_tags.keys().chain(new_tags.keys()).collect();
+        for tag in tags {
+            let delta =
+                new_tags.get(tag).copied().unwrap_or(0) - old_tags.get(tag).copied().unwrap_or(0);
+            if delta != 0 {
+                changes.push(Change::Elements {
+                    anchor,
+                    tag: tag.clone(),
+                    delta,
+                });
+            }
+        }
+    }
+    changes
+}
+
+// スタイルの層を後勝ちで重ねた値を比べ、変わったプロパティを加える
+fn style_changes(changes: &mut Vec<Change>, target: StyleTarget, before: &[&str], after: &[&str]) {
+    let (before, after) = (effective(before), effective(after));
+    let properties: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    for property in properties {
+        let (old, new) = (before.get(property), after.get(property));
+        if old != new {
+            changes.push(Change::Style {
+                target: target.clone(),
+                property: property.clone(),
+                before: old.cloned(),
+                after: new.cloned(),
+            });
+        }
+    }
+}
+
+// プロパティごとの最終的な値
+fn effective(layers: &[&str]) -> BTreeMap<String, String> {
+    let mut values = BTreeMap::new();
+    for layer in layers {
+        for (property, value) in declarations(layer) {
+            if !property.is_empty() && !value.is_empty() {
+                values.insert(property, value.to_string());
+            }
+        }
+    }
+    values
+}
+
+fn tag_counts(elements: Option<&Vec<DynamicElementData>>) -> BTreeMap<String, i64> {
+    let mut counts = BTreeMap::new();
+    for element in elements.into_iter().flatten() {
+        *counts.entry(element.tag.clone()).or_default() += 1;
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::MessageInfo;
+
+    fn message(id: usize, is_user: bool) -> MessageInfo {
+        MessageInfo {
+            id,
+            is_user,
+            text: String::new(),
+            ops: None,
+        }
+    }
+
+    fn element(id: usize, tag: &str) -> DynamicElementData {
+        DynamicElementData {
+            id,
+            tag: tag.to_string(),
+            text: None,
+            styles: None,
+            attributes: None,
+        }
+    }
+
+    #[test]
+    fn test_diff_styles_and_elements() {
+        let mut before = SessionSnapshot {
+            messages: vec![message(0, false), message(1, true)],
+            ..Default::default()
+        };
+        before.ui_state.chat_container_styles = "background: #fff; padding: 8px".to_string();
+        before
+            .ui_state
+            .dynamic_elements
+            .insert(1, vec![element(10, "p")]);
+
+        let mut after = before.clone();
+        after.messages.extend([message(2, true), message(3, false)]);
+        after.ui_state.chat_container_styles =
+            "background: #fff; padding: 8px; background: #000;".to_string();
+        after.ui_state.element_styles.insert(
+            0,
+            "background-color: #3b82f6; font-weight: bold".to_string(),
+        );
+        after.ui_state.role_styles.chrome = "color: white".to_string();
+        after
+            .ui_state
+            .dynamic_elements
+            .insert(2, vec![element(11, "button"), element(12, "p")]);
+        after.ui_state.dynamic_elements.insert(1, Vec::new());
+
+        let style =
+            |target, property: &str, before: Option<&str>, after: Option<&str>| Change::Style {
+                target,
+                property: property.to_string(),
+                before: before.map(str::to_string),
+                after: after.map(str::to_string),
+            };
+        let elements = |anchor, tag: &str, delta| Change::Elements {
+            anchor,
+            tag: tag.to_string(),
+            delta,
+        };
+        assert_eq!(
+            diff(&before, &after),
+            vec![
+                style(
+                    StyleTarget::Container,
+                    "background",
+                    Some("#fff"),
+                    Some("#000")
+                ),
+                style(StyleTarget::Chrome, "color", None, Some("white")),
+                // AIの吹き出しのベーススタイルからの変更として表す
+                style(
+                    StyleTarget::Message(0),
+                    "background-color",
+                    Some("#e5e7eb"),
+                    Some("#3b82f6")
+                ),
+                style(StyleTarget::Message(0), "font-weight", None, Some("bold")),
+                elements(1, "p", -1),
+                elements(2, "button", 1),
+                elements(2, "p", 1),
+            ]
+        );
+        assert!(diff(&after, &after).is_empty());
+    }
+
+    #[test]
+    fn test_push_version_keeps_numbers_and_trims() {
+        let mut versions = Vec::new();
+        let first = push_version(&mut versions, &SessionSnapshot::default(), None, 1);
+        assert_eq!((first.number, first.restored_from), (1, None));
+        for now in 2..=(MAX_VERSIONS as i64 + 5) {
+            push_version(&mut versions, &SessionSnapshot::default(), None, now);
+        }
+        let restored = push_version(&mut versions, &first.snapshot, Some(1), 100);
+        assert_eq!(versions.len(), MAX_VERSIONS);
+        assert_eq!(restored.number, MAX_VERSIONS as u32 + 6);
+        assert_eq!(restored.restored_from, Some(1));
+        assert_eq!(versions.first().unwrap().number, 7);
+    }
+}
@@ -1,11 +1,291 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 // メッセージ送信APIへのリクエストボディ
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct SendMessageRequest {
     pub text: String,
     pub messages: Vec<MessageInfo>, // 現在のメッセージ履歴
+    #[serde(default)]
+    pub ui_state: UiState, // 現在のUI状態（コントラスト判定などに使用）
+    #[serde(default)]
+    pub generation: GenerationOverrides, // 生成パラメータの上書き（サーバー側の上限内で適用）
+    #[serde(default)]
+    pub locale: Locale, // UIの言語（プロンプトとサーバーが返すメッセージの言語）
+    #[serde(default)]
+    pub session_id: Option<String>, // 返信後の会話を保存する名前付きセッション
+}
+
+// UIとプロンプトの言語
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Locale {
+    #[default]
+    Ja,
+    En,
+}
+
+impl Locale {
+    pub const ALL: [Locale; 2] = [Locale::Ja, Locale::En];
+
+    // BCP 47 の言語コード
+    pub fn code(self) -> &'static str {
+        match self {
+            Locale::Ja => "ja",
+            Locale::En => "en",
+        }
+    }
+
+    // "en-US" のような言語タグから対応する言語を選ぶ
+    pub fn from_tag(tag: &str) -> Option<Locale> {
+        let primary = tag.trim().split(['-', '_']).next()?.to_ascii_lowercase();
+        Locale::ALL.into_iter().find(|l| l.code() == primary)
+    }
+}
+
+// 生成パラメータのプリセット
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GenerationPreset {
+    Creative, // 多様な提案をする
+    Precise,  // 指示どおりに変更する
+}
+
+// リクエストごとの生成パラメータの上書き（未指定の項目はサーバーの設定を使う）
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GenerationOverrides {
+    pub preset: Option<GenerationPreset>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_output_tokens: Option<i32>,
+}
+
+// クライアントが保持しているUI状態のスナップショット
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct UiState {
+    pub chat_container_styles: String,
+    pub element_styles: HashMap<usize, String>, // メッセージIDごとに蓄積されたスタイル
+    pub dynamic_elements: HashMap<usize, Vec<DynamicElementData>>, // メッセージIDごとの動的要素
+    #[serde(default)]
+    pub role_styles: RoleStyles, // テーマで設定した役割ごとのスタイル
+}
+
+// 役割ごとのメッセージと、入力欄などの周辺部分（chrome）に重ねるスタイル
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoleStyles {
+    #[serde(default)]
+    pub user_message: String,
+    #[serde(default)]
+    pub ai_message: String,
+    #[serde(default)]
+    pub chrome: String,
+}
+
+// 会話とは別に名前を付けて保存するスタイルの層
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Theme {
+    pub name: String,
+    pub container_styles: String,
+    pub role_styles: RoleStyles,
+    #[serde(default)]
+    pub updated_at: i64, // 最後に保存したUNIX時刻（秒）
+}
+
+// スタイルの後ろに宣言を追記する（後から追記したものが優先される）
+fn push_styles(styles: &mut String, extra: &str) {
+    if extra.trim().is_empty() {
+        return;
+    }
+    if !styles.trim().is_empty() {
+        // 既存のスタイルの最後にセミコロンがない場合は追加
+        if !styles.trim().ends_with(';') {
+            styles.push(';');
+        }
+        styles.push(' ');
+    }
+    styles.push_str(extra);
+    // 新しいスタイルの最後にセミコロンがない場合は追加
+    if !extra.trim().ends_with(';') {
+        styles.push(';');
+    }
+}
+
+impl UiState {
+    // AIの返信のUI操作を適用する（anchor_id: 要素を挿入するユーザーのメッセージ、reply_id: AIの返信）
+    pub fn apply(&mut self, anchor_id: usize, reply_id: usize, ops: &UiOperations) {
+        // 新しい要素を指定されたメッセージIDの後に挿入
+        if let Some(elements) = &ops.new_elements {
+            self.dynamic_elements.insert(anchor_id, elements.clone());
+        }
+
+        // スタイル更新は既存のスタイルの後ろに追記する
+        if let Some(updates) = &ops.change_style_elements {
+            for update in updates {
+                push_styles(
+                    self.element_styles.entry(update.id).or_default(),
+                    &update.styles,
+                );
+            }
+
+            // 全ての要素に同じスタイルを適用する一般的な変更要求なら、AIの返信にも適用
+            let same_style =
+                updates.len() > 1 && updates.iter().all(|u| u.styles == updates[0].styles);
+            if same_style {
+                self.element_styles
+                    .insert(reply_id, updates[0].styles.clone());
+            }
+        }
+
+        if let Some(styles) = &ops.chat_container_styles {
+            self.chat_container_styles = styles.clone();
+        }
+    }
+
+    // 現在のスタイルの層をテーマにする。役割ごとのスタイルには、その役割の最新のメッセージに
+    // 蓄積されたスタイルも含める
+    pub fn capture_theme(&self, name: &str, messages: &[MessageInfo]) -> Theme {
+        let latest = |is_user: bool, role: &str| {
+            let mut styles = role.to_string();
+            if let Some(extra) = messages
+                .iter()
+                .rev()
+                .find(|m| m.is_user == is_user)
+                .and_then(|m| self.element_styles.get(&m.id))
+            {
+                push_styles(&mut styles, extra);
+            }
+            styles
+        };
+        Theme {
+            name: name.to_string(),
+            container_styles: self.chat_container_styles.clone(),
+            role_styles: RoleStyles {
+                user_message: latest(true, &self.role_styles.user_message),
+                ai_message: latest(false, &self.role_styles.ai_message),
+                chrome: self.role_styles.chrome.clone(),
+            },
+            updated_at: 0,
+        }
+    }
+
+    // base からこの状態への変更
+    pub fn delta_from(&self, base: &UiState) -> UiDelta {
+        fn changed<V: Clone + PartialEq>(
+            base: &HashMap<usize, V>,
+            current: &HashMap<usize, V>,
+        ) -> HashMap<usize, Option<V>> {
+            let mut changes: HashMap<usize, Option<V>> = current
+                .iter()
+                .filter(|(id, value)| base.get(id) != Some(value))
+                .map(|(id, value)| (*id, Some(value.clone())))
+                .collect();
+            for id in base.keys().filter(|id| !current.contains_key(id)) {
+                changes.insert(*id, None);
+            }
+            changes
+        }
+        UiDelta {
+            chat_container_styles: (self.chat_container_styles != base.chat_container_styles)
+                .then(|| self.chat_container_styles.clone()),
+            element_styles: changed(&base.element_styles, &self.element_styles),
+            dynamic_elements: changed(&base.dynamic_elements, &self.dynamic_elements),
+            role_styles: (self.role_styles != base.role_styles).then(|| self.role_styles.clone()),
+        }
+    }
+
+    // delta_from で求めた変更を適用する
+    pub fn apply_delta(&mut self, delta: &UiDelta) {
+        fn merge<V: Clone>(target: &mut HashMap<usize, V>, changes: &HashMap<usize, Option<V>>) {
+            for (id, value) in changes {
+                match value {
+                    Some(value) => target.insert(*id, value.clone()),
+                    None => target.remove(id),
+                };
+            }
+        }
+        if let Some(styles) = &delta.chat_container_styles {
+            self.chat_container_styles = styles.clone();
+        }
+        merge(&mut self.element_styles, &delta.element_styles);
+        merge(&mut self.dynamic_elements, &delta.dynamic_elements);
+        if let Some(roles) = &delta.role_styles {
+            self.role_styles = roles.clone();
+        }
+    }
+
+    // テーマを現在のスタイルに重ねる（複数のテーマを順に重ねられる）。
+    // メッセージごとのスタイルはテーマの役割ごとのスタイルより優先されるため消す
+    pub fn apply_theme(&mut self, theme: &Theme) {
+        push_styles(&mut self.chat_container_styles, &theme.container_styles);
+        push_styles(
+            &mut self.role_styles.user_message,
+            &theme.role_styles.user_message,
+        );
+        push_styles(
+            &mut self.role_styles.ai_message,
+            &theme.role_styles.ai_message,
+        );
+        push_styles(&mut self.role_styles.chrome, &theme.role_styles.chrome);
+        self.element_styles.clear();
+    }
+}
+
+// 保存・復元する会話とUI状態
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionSnapshot {
+    pub messages: Vec<MessageInfo>,
+    pub ui_state: UiState,
+    #[serde(default)]
+    pub ui_history: BTreeMap<usize, UiDelta>, // メッセージIDごとの、そのメッセージが最後だった間のUI状態の変更
+}
+
+// UI状態の変更（値が Some のものだけを置き換え、None の要素は削除する）。
+// 会話の途中の時点のUI状態を、直前の時点からの変更として小さく保存するために使う
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct UiDelta {
+    #[serde(default)]
+    pub chat_container_styles: Option<String>,
+    #[serde(default)]
+    pub element_styles: HashMap<usize, Option<String>>,
+    #[serde(default)]
+    pub dynamic_elements: HashMap<usize, Option<Vec<DynamicElementData>>>,
+    #[serde(default)]
+    pub role_styles: Option<RoleStyles>,
+}
+
+impl UiDelta {
+    pub fn is_empty(&self) -> bool {
+        *self == UiDelta::default()
+    }
+}
+
+// セッションの版（アシスタントの返信や復元のたびに、その時点の会話とUI状態を記録する）
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SessionVersion {
+    pub number: u32,     // セッション内の通し番号（1から）
+    pub created_at: i64, // 記録したUNIX時刻（秒）
+    #[serde(default)]
+    pub restored_from: Option<u32>, // 過去の版を復元して記録した場合、その版の番号
+    pub snapshot: SessionSnapshot,
+}
+
+// 名前付きセッションの一覧の項目
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionSummary {
+    pub id: String,
+    pub name: String,
+    pub updated_at: i64, // 最後に保存したUNIX時刻（秒）
+    #[serde(default)]
+    pub parent_id: Option<String>, // 分岐元のセッション（分岐したセッションのみ）
+    #[serde(default)]
+    pub forked_at: Option<usize>, // 分岐元のどのメッセージIDから分岐したか
+}
+
+// ページを開いたときのセッションの一覧と、表示するセッションの会話とUI状態
+// （サーバーで求めて描画し、そのままハイドレーションに使う）
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InitialState {
+    pub sessions: Vec<SessionSummary>,
+    pub active: Option<String>, // 表示するセッションのID（一覧が空なら None）
+    pub snapshot: SessionSnapshot,
 }
 
 // メッセージ情報（AIにコンテキストを提供するため）
@@ -14,6 +294,17 @@ pub struct MessageInfo {
     pub id: usize,
     pub is_user: bool,
     pub text: String,
+    #[serde(default)]
+    pub ops: Option<UiOperations>, // AIの返信で適用したUI操作（ユーザーのメッセージではNone）
+}
+
+// AIの返信で適用したUI操作（次回以降の会話でモデルに自分の応答として渡す）
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct UiOperations {
+    pub success: bool,
+    pub chat_container_styles: Option<String>,
+    pub change_style_elements: Option<Vec<StyleUpdate>>,
+    pub new_elements: Option<Vec<DynamicElementData>>,
 }
 
 // メッセージ送信APIからのレスポンス
@@ -24,6 +315,44 @@ pub struct SendMessageResponse {
     pub chat_container_styles: Option<String>, // CSSプロパティ文字列
     pub change_style_elements: Option<Vec<StyleUpdate>>,
     pub new_elements: Option<Vec<DynamicElementData>>,
+    #[serde(default)]
+    pub contrast_reports: Option<Vec<ContrastReport>>, // コントラスト比が基準を下回った要素
+    #[serde(default)]
+    pub prompt_version: Option<String>, // 生成に使ったプロンプトテンプレートのバージョン
+    #[serde(default)]
+    pub usage: Option<TokenUsage>, // このターンのトークン数と料金
+}
+
+// 1回のモデル呼び出しのトークン数と料金
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub estimated: bool,       // プロバイダーが報告しなかったため推定した値か
+    pub cost_usd: Option<f64>, // 料金表にないモデルは None
+}
+
+// セッション全体のトークン数と料金の合計
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageTotals {
+    pub turns: usize,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost_usd: f64,
+    pub estimated: bool, // 推定値や料金不明のターンを含むか
+    pub last: Option<TokenUsage>,
+}
+
+impl UsageTotals {
+    pub fn add(&mut self, usage: &TokenUsage) {
+        self.turns += 1;
+        self.prompt_tokens += usage.prompt_tokens;
+        self.completion_tokens += usage.completion_tokens;
+        self.cost_usd += usage.cost_usd.unwrap_or_default();
+        self.estimated |= usage.estimated || usage.cost_usd.is_none();
+        self.last = Some(usage.clone());
+    }
 }
 
 // 動的に生成する要素のデータを表現する汎用的な構造体
@@ -42,3 +371,21 @@ pub struct StyleUpdate {
     pub id: usize,
     pub styles: String, // CSSプロパティ文字列（classesから変更）
 }
+
+// コントラスト判定の対象要素
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ContrastTarget {
+    Message(usize),    // メッセージID
+    NewElement(usize), // new_elements 内の位置
+}
+
+// 基準を下回ったコントラストの判定結果
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ContrastReport {
+    pub target: ContrastTarget,
+    pub foreground: String, // 判定時の文字色（#rrggbb）
+    pub background: String, // 判定時の背景色（#rrggbb）
+    pub ratio: f64,
+    pub required: f64,
+    pub adjusted_foreground: Option<String>, // 自動補正した場合の文字色
+}
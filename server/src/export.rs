@@ -0,0 +1,111 @@
+//! 保存されたセッションをHTMLファイルとして書き出す（/export?session=...）
+
+use app::storage::{is_valid_id, owner_id_from_headers, SharedSessionStore};
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+
+/// 書き出しのルートパス
+pub const ROUTE: &str = "/export";
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    session: String,
+}
+
+pub async fn handler(
+    State(store): State<SharedSessionStore>,
+    Query(query): Query<ExportQuery>,
+    headers: HeaderMap,
+) -> Response {
+    // Cookie の所有者のセッションだけを書き出せる
+    let Some(owner) = owner_id_from_headers(&headers) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if !is_valid_id(&query.session) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    match store.load(&owner, &query.session) {
+        Ok(Some(snapshot)) => (
+            [
+                (header::CONTENT_TYPE, "text/html; charset=utf-8".to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}\"", app::export::EXPORT_FILE_NAME),
+                ),
+            ],
+            app::export::export_stored_session(&snapshot, &headers),
+        )
+            .into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            log::error!("セッションを読み込めません: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session_store::SqliteSessionStore;
+    use app::storage::{SessionStore, SESSION_COOKIE};
+    use axum::body::Body;
+    use axum::http::Request;
+    use axum::routing::get;
+    use axum::Router;
+    use common::{MessageInfo, SessionSnapshot};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_exports_stored_session() {
+        let store = Arc::new(SqliteSessionStore::in_memory().unwrap());
+        let owner = "0123456789abcdef0123456789abcdef";
+        let session_id = "fedcba9876543210fedcba9876543210";
+        let mut snapshot = SessionSnapshot::default();
+        snapshot.messages.push(MessageInfo {
+            id: 0,
+            is_user: true,
+            text: "こんにちは".to_string(),
+            ops: None,
+        });
+        store.save(owner, session_id, &snapshot).unwrap();
+        let router = Router::new()
+            .route(ROUTE, get(handler))
+            .with_state(store as SharedSessionStore);
+
+        let request = |cookie: &str| {
+            Request::get(format!("{}?session={}", ROUTE, session_id))
+                .header(header::COOKIE, cookie)
+                .header(header::HOST, "example.com")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = router
+            .clone()
+            .oneshot(request(&format!("{}={}", SESSION_COOKIE, owner)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()[header::CONTENT_DISPOSITION]
+            .to_str()
+            .unwrap()
+            .starts_with("attachment"));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("こんにちは"));
+
+        // 他の所有者のセッションは見つからない
+        let response = router
+            .oneshot(request(&format!(
+                "{}=ffffffffffffffffffffffffffffffff",
+                SESSION_COOKIE
+            )))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
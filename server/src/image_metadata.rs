@@ -0,0 +1,326 @@
+//! 画像のバイト列から形式を判定し、EXIFやコメントなどのメタデータを取り除く
+
+/// 画像プロキシが扱う画像形式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+}
+
+impl ImageFormat {
+    /// 先頭のマジックバイトから形式を判定する
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(Self::Jpeg)
+        } else if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Self::Png)
+        } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+            Some(Self::Gif)
+        } else if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+            Some(Self::Webp)
+        } else {
+            None
+        }
+    }
+
+    /// Content-Type ヘッダーの値から形式を判定する
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        let essence = mime.split(';').next().unwrap_or("").trim();
+        match essence.to_ascii_lowercase().as_str() {
+            "image/jpeg" | "image/jpg" | "image/pjpeg" => Some(Self::Jpeg),
+            "image/png" => Some(Self::Png),
+            "image/gif" => Some(Self::Gif),
+            "image/webp" => Some(Self::Webp),
+            _ => None,
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Gif => "image/gif",
+            Self::Webp => "image/webp",
+        }
+    }
+}
+
+/// 画像の構造が壊れていて解析できなかった
+#[derive(Debug, PartialEq, Eq)]
+pub struct MalformedImage;
+
+/// メタデータを取り除いた画像を返す
+pub fn strip_metadata(format: ImageFormat, bytes: &[u8]) -> Result<Vec<u8>, MalformedImage> {
+    match format {
+        ImageFormat::Jpeg => strip_jpeg(bytes),
+        ImageFormat::Png => strip_png(bytes),
+        ImageFormat::Gif => strip_gif(bytes),
+        ImageFormat::Webp => strip_webp(bytes),
+    }
+}
+
+// JPEG: APP1(EXIF/XMP)やAPP13(IPTC)、コメントを除去する。
+// JFIF(APP0)、ICCプロファイル(APP2)、Adobe(APP14)は色の再現に必要なため残す。
+// EXIFの回転情報も失われるが、プロキシ経由の画像では許容する。
+fn strip_jpeg(bytes: &[u8]) -> Result<Vec<u8>, MalformedImage> {
+    let mut out = Vec::with_capacity(bytes.len());
+    out.extend_from_slice(&bytes[..2]);
+    let mut pos = 2;
+    loop {
+        if pos + 4 > bytes.len() || bytes[pos] != 0xFF {
+            return Err(MalformedImage);
+        }
+        let marker = bytes[pos + 1];
+        // スキャン開始以降はそのままコピーする
+        if marker == 0xDA {
+            out.extend_from_slice(&bytes[pos..]);
+            return Ok(out);
+        }
+        let len = u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]) as usize;
+        let end = pos + 2 + len;
+        if len < 2 || end > bytes.len() {
+            return Err(MalformedImage);
+        }
+        let payload = &bytes[pos + 4..end];
+        let keep = match marker {
+            0xE2 => payload.starts_with(b"ICC_PROFILE\0"),
+            0xE0 | 0xEE => true,
+            0xE1..=0xEF | 0xFE => false,
+            _ => true,
+        };
+        if keep {
+            out.extend_from_slice(&bytes[pos..end]);
+        }
+        pos = end;
+    }
+}
+
+// PNG: テキスト系チャンク、EXIF、タイムスタンプを除去する
+fn strip_png(bytes: &[u8]) -> Result<Vec<u8>, MalformedImage> {
+    let mut out = Vec::with_capacity(bytes.len());
+    out.extend_from_slice(&bytes[..8]);
+    let mut pos = 8;
+    while pos < bytes.len() {
+        if pos + 12 > bytes.len() {
+            return Err(MalformedImage);
+        }
+        let len = u32::from_be_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+        let end = pos
+            .checked_add(12)
+            .and_then(|p| p.checked_add(len))
+            .filter(|&e| e <= bytes.len())
+            .ok_or(MalformedImage)?;
+        let chunk_type = &bytes[pos + 4..pos + 8];
+        if !matches!(chunk_type, b"tEXt" | b"zTXt" | b"iTXt" | b"eXIf" | b"tIME") {
+            out.extend_from_slice(&bytes[pos..end]);
+        }
+        pos = end;
+        if chunk_type == b"IEND" {
+            return Ok(out);
+        }
+    }
+    Err(MalformedImage)
+}
+
+// GIF: コメント拡張とXMPのアプリケーション拡張を除去する
+fn strip_gif(bytes: &[u8]) -> Result<Vec<u8>, MalformedImage> {
+    // サブブロック列の終端（0x00の次の位置）を返す
+    fn sub_blocks_end(bytes: &[u8], mut pos: usize) -> Result<usize, MalformedImage> {
+        loop {
+            let size = *bytes.get(pos).ok_or(MalformedImage)? as usize;
+            pos += 1 + size;
+            if size == 0 {
+                return Ok(pos);
+            }
+        }
+    }
+    fn color_table_len(flags: u8) -> usize {
+        if flags & 0x80 != 0 {
+            3 * (1 << ((flags & 0x07) + 1))
+        } else {
+            0
+        }
+    }
+
+    if bytes.len() < 13 {
+        return Err(MalformedImage);
+    }
+    let mut pos = 13 + color_table_len(bytes[10]);
+    if pos > bytes.len() {
+        return Err(MalformedImage);
+    }
+    let mut out = Vec::with_capacity(bytes.len());
+    out.extend_from_slice(&bytes[..pos]);
+    loop {
+        match bytes.get(pos).ok_or(MalformedImage)? {
+            0x3B => {
+                out.push(0x3B);
+                return Ok(out);
+            }
+            0x21 => {
+                let label = *bytes.get(pos + 1).ok_or(MalformedImage)?;
+                let end = sub_blocks_end(bytes, pos + 2)?;
+                if end > bytes.len() {
+                    return Err(MalformedImage);
+                }
+                let is_xmp = label == 0xFF && bytes.get(pos + 3..pos + 14) == Some(b"XMP DataXMP");
+                if label != 0xFE && !is_xmp {
+                    out.extend_from_slice(&bytes[pos..end]);
+                }
+                pos = end;
+            }
+            0x2C => {
+                let flags = *bytes.get(pos + 9).ok_or(MalformedImage)?;
+                // 画像記述子(10) + ローカルカラーテーブル + LZW最小コードサイズ(1)
+                let data = pos + 10 + color_table_len(flags) + 1;
+                let end = sub_blocks_end(bytes, data)?;
+                if end > bytes.len() {
+                    return Err(MalformedImage);
+                }
+                out.extend_from_slice(&bytes[pos..end]);
+                pos = end;
+            }
+            _ => return Err(MalformedImage),
+        }
+    }
+}
+
+// WebP: EXIFとXMPチャンクを除去し、VP8Xのフラグとファイルサイズを更新する
+fn strip_webp(bytes: &[u8]) -> Result<Vec<u8>, MalformedImage> {
+    let mut body = Vec::with_capacity(bytes.len());
+    body.extend_from_slice(b"WEBP");
+    let mut pos = 12;
+    while pos < bytes.len() {
+        if pos + 8 > bytes.len() {
+            return Err(MalformedImage);
+        }
+        let fourcc = &bytes[pos..pos + 4];
+        let len = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let padded = len + (len & 1);
+        let end = (pos + 8)
+            .checked_add(padded)
+            .filter(|&e| e <= bytes.len())
+            .ok_or(MalformedImage)?;
+        match fourcc {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" => {
+                let start = body.len();
+                body.extend_from_slice(&bytes[pos..end]);
+                if let Some(flags) = body.get_mut(start + 8) {
+                    *flags &= !(0x08 | 0x04);
+                }
+            }
+            _ => body.extend_from_slice(&bytes[pos..end]),
+        }
+        pos = end;
+    }
+    let mut out = Vec::with_capacity(body.len() + 8);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut c = (data.len() as u32).to_be_bytes().to_vec();
+        c.extend_from_slice(kind);
+        c.extend_from_slice(data);
+        c.extend_from_slice(&[0, 0, 0, 0]); // CRCは検証しない
+        c
+    }
+
+    #[test]
+    fn test_sniff_and_mime() {
+        assert_eq!(
+            ImageFormat::sniff(b"\xFF\xD8\xFF\xE0"),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(ImageFormat::sniff(b"GIF89a......"), Some(ImageFormat::Gif));
+        assert_eq!(ImageFormat::sniff(b"<svg></svg>"), None);
+        assert_eq!(
+            ImageFormat::from_mime("image/png; charset=binary"),
+            Some(ImageFormat::Png)
+        );
+        assert_eq!(ImageFormat::from_mime("image/svg+xml"), None);
+    }
+
+    #[test]
+    fn test_strip_jpeg_removes_exif_and_comments() {
+        let mut jpeg = vec![0xFF, 0xD8];
+        jpeg.extend_from_slice(&[0xFF, 0xE0, 0x00, 0x04, b'J', b'F']); // APP0
+        jpeg.extend_from_slice(&[0xFF, 0xE1, 0x00, 0x06, b'E', b'x', b'i', b'f']); // APP1
+        jpeg.extend_from_slice(&[0xFF, 0xFE, 0x00, 0x04, b'h', b'i']); // COM
+        jpeg.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34, 0xFF, 0xD9]);
+
+        let stripped = strip_metadata(ImageFormat::Jpeg, &jpeg).unwrap();
+        assert_eq!(
+            stripped,
+            vec![
+                0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, b'J', b'F', 0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34,
+                0xFF, 0xD9
+            ]
+        );
+        assert_eq!(
+            strip_metadata(ImageFormat::Jpeg, &jpeg[..7]),
+            Err(MalformedImage)
+        );
+    }
+
+    #[test]
+    fn test_strip_png_removes_text_chunks() {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend(png_chunk(b"IHDR", &[0; 13]));
+        png.extend(png_chunk(b"tEXt", b"Author\0someone"));
+        png.extend(png_chunk(b"IDAT", &[1, 2, 3]));
+        png.extend(png_chunk(b"IEND", &[]));
+
+        let stripped = strip_metadata(ImageFormat::Png, &png).unwrap();
+        assert!(!stripped.windows(4).any(|w| w == b"tEXt"));
+        assert!(stripped.windows(4).any(|w| w == b"IDAT"));
+        assert!(stripped.ends_with(&png_chunk(b"IEND", &[])));
+    }
+
+    #[test]
+    fn test_strip_gif_removes_comment() {
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend_from_slice(&[1, 0, 1, 0, 0x80, 0, 0]); // 2色のグローバルカラーテーブル
+        gif.extend_from_slice(&[0, 0, 0, 255, 255, 255]);
+        gif.extend_from_slice(&[0x21, 0xFE, 3, b'a', b'b', b'c', 0]); // コメント
+        gif.extend_from_slice(&[0x2C, 0, 0, 0, 0, 1, 0, 1, 0, 0, 2, 2, 0x4C, 0x01, 0]);
+        gif.push(0x3B);
+
+        let stripped = strip_metadata(ImageFormat::Gif, &gif).unwrap();
+        assert_eq!(stripped.len(), gif.len() - 7);
+        assert!(!stripped.windows(3).any(|w| w == b"abc"));
+        assert_eq!(stripped.last(), Some(&0x3B));
+    }
+
+    #[test]
+    fn test_strip_webp_removes_exif_and_updates_header() {
+        let mut body = b"WEBP".to_vec();
+        body.extend_from_slice(b"VP8X");
+        body.extend_from_slice(&10u32.to_le_bytes());
+        body.extend_from_slice(&[0x08, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        body.extend_from_slice(b"VP8L");
+        body.extend_from_slice(&1u32.to_le_bytes());
+        body.extend_from_slice(&[0x2F, 0]); // パディング付き
+        body.extend_from_slice(b"EXIF");
+        body.extend_from_slice(&4u32.to_le_bytes());
+        body.extend_from_slice(b"Exif");
+        let mut webp = b"RIFF".to_vec();
+        webp.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        webp.extend_from_slice(&body);
+
+        let stripped = strip_metadata(ImageFormat::Webp, &webp).unwrap();
+        assert!(!stripped.windows(4).any(|w| w == b"EXIF"));
+        assert_eq!(stripped[20], 0x00); // EXIFフラグが落ちている
+        let riff_len = u32::from_le_bytes(stripped[4..8].try_into().unwrap()) as usize;
+        assert_eq!(riff_len, stripped.len() - 8);
+    }
+}
@@ -0,0 +1,527 @@
+//! 外部画像をサーバー経由で配信するプロキシ（/img-proxy?url=...）
+//!
+//! 閲覧者のブラウザがAIの選んだ任意のホストへ直接アクセスしないよう、
+//! 画像の取得・検証・メタデータ除去・キャッシュをサーバー側で行う。
+//! ホスト名は取得のたびに名前解決し、公開アドレスだけを返すリゾルバーで接続するため、
+//! 内部アドレスに解決されるホスト名やDNSリバインディングでも内部には接続しない。
+
+use crate::image_metadata::{strip_metadata, ImageFormat};
+use axum::extract::Query;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use once_cell::sync::Lazy;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// プロキシのルートパス
+pub const ROUTE: &str = "/img-proxy";
+
+/// `IMG_PROXY_ALLOWED_HOSTS` がないときに許可するホスト（プロンプトの例で使う画像サービス）
+pub const DEFAULT_ALLOWED_HOSTS: &[&str] = &["cataas.com", "picsum.photos"];
+
+/// 環境変数から読み込むプロキシ設定
+#[derive(Clone, Debug)]
+pub struct ImgProxyConfig {
+    pub cache_dir: PathBuf,
+    pub max_bytes: usize,
+    pub cache_max_bytes: u64, // キャッシュ全体の上限（超えたら古く使われたものから消す）
+    pub allowed_hosts: Vec<String>, // 取得を許可するホスト（サブドメインを含む）
+    pub offline: bool,        // trueならキャッシュにある画像のみ配信
+}
+
+impl ImgProxyConfig {
+    pub fn from_env() -> Self {
+        let cache_dir = std::env::var("IMG_PROXY_CACHE_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("img-cache"));
+        let max_bytes = std::env::var("IMG_PROXY_MAX_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5 * 1024 * 1024);
+        let cache_max_bytes = std::env::var("IMG_PROXY_CACHE_MAX_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(256 * 1024 * 1024);
+        let allowed_hosts =
+            parse_allowed_hosts(&std::env::var("IMG_PROXY_ALLOWED_HOSTS").unwrap_or_default());
+        let offline = matches!(
+            std::env::var("IMG_PROXY_OFFLINE").as_deref(),
+            Ok("1") | Ok("true")
+        );
+        Self {
+            cache_dir,
+            max_bytes,
+            cache_max_bytes,
+            allowed_hosts,
+            offline,
+        }
+    }
+
+    /// 取得してよいURLかどうか（https、公開ホスト、許可リスト）。
+    /// ホスト名の解決先は接続時に `PublicResolver` が検証する
+    pub fn is_allowed_url(&self, url: &reqwest::Url) -> bool {
+        if url.scheme() != "https" {
+            return false;
+        }
+        let Some(host) = url.host_str().map(|h| h.to_ascii_lowercase()) else {
+            return false;
+        };
+        if host == "localhost" || host.ends_with(".localhost") || host.ends_with(".internal") {
+            return false;
+        }
+        if let Ok(ip) = host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            if !is_public_ip(&ip) {
+                return false;
+            }
+        }
+        self.allowed_hosts
+            .iter()
+            .any(|allowed| host == *allowed || host.ends_with(&format!(".{}", allowed)))
+    }
+}
+
+/// カンマ区切りの許可ホスト（空なら `DEFAULT_ALLOWED_HOSTS`）
+fn parse_allowed_hosts(raw: &str) -> Vec<String> {
+    let hosts: Vec<String> = raw
+        .split(',')
+        .map(|h| h.trim().to_ascii_lowercase())
+        .filter(|h| !h.is_empty())
+        .collect();
+    if hosts.is_empty() {
+        DEFAULT_ALLOWED_HOSTS
+            .iter()
+            .map(|h| h.to_string())
+            .collect()
+    } else {
+        hosts
+    }
+}
+
+// インターネット上の公開アドレスか（内部・予約済みのアドレスは false）
+fn is_public_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => is_public_ipv4(v4),
+        IpAddr::V6(v6) => is_public_ipv6(v6),
+    }
+}
+
+fn is_public_ipv4(v4: &Ipv4Addr) -> bool {
+    let [a, b, c, _] = v4.octets();
+    !(v4.is_private()
+        || v4.is_loopback()
+        || v4.is_link_local()
+        || v4.is_unspecified()
+        || v4.is_broadcast()
+        || v4.is_documentation()
+        || v4.is_multicast()
+        || a == 0
+        || (a == 100 && (b & 0xc0) == 64) // CGNAT 100.64.0.0/10
+        || (a == 192 && b == 0 && c == 0) // 192.0.0.0/24
+        || (a == 198 && (b & 0xfe) == 18) // ベンチマーク用 198.18.0.0/15
+        || a >= 240)
+}
+
+fn is_public_ipv6(v6: &Ipv6Addr) -> bool {
+    // IPv4射影アドレスとNAT64（64:ff9b::/96）は中のIPv4アドレスで判定する
+    if let Some(v4) = v6.to_ipv4_mapped() {
+        return is_public_ipv4(&v4);
+    }
+    let segments = v6.segments();
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [.., hi, lo] = segments;
+        return is_public_ipv4(&Ipv4Addr::from((u32::from(hi) << 16) | u32::from(lo)));
+    }
+    !(v6.is_loopback()
+        || v6.is_unspecified()
+        || v6.is_multicast()
+        || (segments[0] & 0xfe00) == 0xfc00 // ユニークローカル
+        || (segments[0] & 0xffc0) == 0xfe80 // リンクローカル
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8) // ドキュメント用
+        || segments[..5] == [0, 0, 0, 0, 0]) // IPv4互換などの予約済み
+}
+
+/// ホスト名を解決し、すべてのアドレスが公開アドレスのときだけ返すリゾルバー。
+/// 接続には検証したアドレスをそのまま使うため、検証後に解決先が変わっても内部には接続しない
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = resolve_public(name.as_str()).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// ホスト名の解決先（内部アドレスが1つでも含まれれば拒否する）
+async fn resolve_public(host: &str) -> Result<Vec<SocketAddr>, ImgProxyError> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0))
+        .await
+        .map_err(|e| ImgProxyError::Upstream(e.to_string()))?
+        .collect();
+    if addrs.is_empty() || addrs.iter().any(|addr| !is_public_ip(&addr.ip())) {
+        return Err(ImgProxyError::Forbidden);
+    }
+    Ok(addrs)
+}
+
+/// プロキシ処理の失敗理由
+#[derive(Debug, thiserror::Error)]
+pub enum ImgProxyError {
+    #[error("URLが不正です")]
+    InvalidUrl,
+    #[error("許可されていないURLです")]
+    Forbidden,
+    #[error("キャッシュに画像がありません（オフライン）")]
+    NotCached,
+    #[error("画像の取得に失敗しました: {0}")]
+    Upstream(String),
+    #[error("画像サイズが上限を超えています")]
+    TooLarge,
+    #[error("対応していない画像形式です")]
+    UnsupportedType,
+}
+
+impl IntoResponse for ImgProxyError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ImgProxyError::InvalidUrl => StatusCode::BAD_REQUEST,
+            ImgProxyError::Forbidden => StatusCode::FORBIDDEN,
+            ImgProxyError::NotCached => StatusCode::NOT_FOUND,
+            ImgProxyError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            ImgProxyError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ImgProxyError::UnsupportedType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+static CONFIG: Lazy<ImgProxyConfig> = Lazy::new(ImgProxyConfig::from_env);
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    // リダイレクト先も同じ基準で検証する
+    let policy = reqwest::redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() >= 5 {
+            attempt.error("リダイレクトが多すぎます")
+        } else if CONFIG.is_allowed_url(attempt.url()) {
+            attempt.follow()
+        } else {
+            attempt.stop()
+        }
+    });
+    reqwest::Client::builder()
+        .redirect(policy)
+        .dns_resolver(Arc::new(PublicResolver))
+        .no_proxy()
+        .timeout(Duration::from_secs(10))
+        .user_agent("self-changer-img-proxy")
+        .build()
+        .expect("reqwest client should build")
+});
+
+#[derive(Deserialize)]
+pub struct ImgProxyQuery {
+    url: String,
+}
+
+/// GET /img-proxy?url=...
+pub async fn handler(Query(query): Query<ImgProxyQuery>) -> Result<Response, ImgProxyError> {
+    let url = reqwest::Url::parse(&query.url).map_err(|_| ImgProxyError::InvalidUrl)?;
+    if !CONFIG.is_allowed_url(&url) {
+        return Err(ImgProxyError::Forbidden);
+    }
+
+    let cache_path = CONFIG
+        .cache_dir
+        .join(format!("{:016x}", fnv1a(url.as_str())));
+    let (format, bytes) = match read_cache(&cache_path, &url).await {
+        Some(hit) => hit,
+        None if CONFIG.offline => return Err(ImgProxyError::NotCached),
+        None => {
+            let (format, bytes) = fetch(&url, CONFIG.max_bytes).await?;
+            let stripped =
+                strip_metadata(format, &bytes).map_err(|_| ImgProxyError::UnsupportedType)?;
+            write_cache(&cache_path, &url, format, &stripped).await;
+            evict_cache(&CONFIG.cache_dir, CONFIG.cache_max_bytes).await;
+            (format, stripped)
+        }
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.mime()),
+            (header::CACHE_CONTROL, "public, max-age=86400"),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        ],
+        bytes,
+    )
+        .into_response())
+}
+
+async fn fetch(
+    url: &reqwest::Url,
+    max_bytes: usize,
+) -> Result<(ImageFormat, Vec<u8>), ImgProxyError> {
+    let mut response = CLIENT
+        .get(url.clone())
+        .send()
+        .await
+        .map_err(|e| ImgProxyError::Upstream(e.to_string()))?;
+    if !response.status().is_success() {
+        return Err(ImgProxyError::Upstream(response.status().to_string()));
+    }
+    let declared = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(ImageFormat::from_mime)
+        .ok_or(ImgProxyError::UnsupportedType)?;
+    if response.content_length().unwrap_or(0) > max_bytes as u64 {
+        return Err(ImgProxyError::TooLarge);
+    }
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| ImgProxyError::Upstream(e.to_string()))?
+    {
+        if bytes.len() + chunk.len() > max_bytes {
+            return Err(ImgProxyError::TooLarge);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    // Content-Typeと中身が一致しない場合は拒否する
+    if ImageFormat::sniff(&bytes) != Some(declared) {
+        return Err(ImgProxyError::UnsupportedType);
+    }
+    Ok((declared, bytes))
+}
+
+// キャッシュは「1行目にURL、2行目にMIMEタイプ、以降に画像本体」の1ファイルで保持する。
+// ファイル名のハッシュは衝突し得るため、1行目のURLが一致するものだけを使う。
+// 読んだファイルは更新日時を今にし、上限を超えたときに消す順を最後にする
+async fn read_cache(path: &Path, url: &reqwest::Url) -> Option<(ImageFormat, Vec<u8>)> {
+    let data = tokio::fs::read(path).await.ok()?;
+    let mut parts = data.splitn(3, |&b| b == b'\n');
+    if parts.next()? != url.as_str().as_bytes() {
+        return None;
+    }
+    let format = ImageFormat::from_mime(std::str::from_utf8(parts.next()?).ok()?)?;
+    let body = parts.next()?.to_vec();
+    let path = path.to_path_buf();
+    let _ = tokio::task::spawn_blocking(move || {
+        if let Ok(file) = std::fs::File::options().append(true).open(path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+    })
+    .await;
+    Some((format, body))
+}
+
+async fn write_cache(path: &Path, url: &reqwest::Url, format: ImageFormat, bytes: &[u8]) {
+    // 解析済みのURLは改行を含まない
+    let mut data = format!("{}\n{}\n", url, format.mime()).into_bytes();
+    data.extend_from_slice(bytes);
+    if let Some(dir) = path.parent() {
+        let _ = tokio::fs::create_dir_all(dir).await;
+    }
+    if let Err(e) = tokio::fs::write(path, data).await {
+        log::warn!("画像キャッシュの書き込みに失敗しました: {}", e);
+    }
+}
+
+// キャッシュの合計サイズが上限を超えていれば、使われていない順に消す
+async fn evict_cache(dir: &Path, max_bytes: u64) {
+    // 同時に複数の書き込みが消す対象を選ばないようにする
+    static EVICTION: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+    let _guard = EVICTION.lock().await;
+    let dir = dir.to_path_buf();
+    let result = tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_file() {
+                let used = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                entries.push((used, meta.len(), entry.path()));
+            }
+        }
+        let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort();
+        for (_, len, path) in entries {
+            if total <= max_bytes {
+                break;
+            }
+            std::fs::remove_file(&path)?;
+            total -= len;
+        }
+        Ok(())
+    })
+    .await;
+    if let Ok(Err(e)) = result {
+        log::warn!("画像キャッシュを整理できません: {}", e);
+    }
+}
+
+// 実行環境によらず安定したキャッシュキーを得るためのFNV-1aハッシュ
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(allowed: &[&str]) -> ImgProxyConfig {
+        ImgProxyConfig {
+            cache_dir: PathBuf::from("img-cache"),
+            max_bytes: 1024,
+            cache_max_bytes: 4096,
+            allowed_hosts: allowed.iter().map(|s| s.to_string()).collect(),
+            offline: false,
+        }
+    }
+
+    fn url(s: &str) -> reqwest::Url {
+        reqwest::Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_rejects_non_https_and_private_hosts() {
+        let c = config(&[
+            "cataas.com",
+            "127.0.0.1",
+            "192.168.0.10",
+            "[::1]",
+            "localhost",
+        ]);
+        assert!(c.is_allowed_url(&url("https://cataas.com/cat")));
+        assert!(!c.is_allowed_url(&url("http://cataas.com/cat")));
+        assert!(!c.is_allowed_url(&url("https://localhost/secret")));
+        assert!(!c.is_allowed_url(&url("https://127.0.0.1/")));
+        assert!(!c.is_allowed_url(&url("https://192.168.0.10/")));
+        assert!(!c.is_allowed_url(&url("https://[::1]/")));
+    }
+
+    #[test]
+    fn test_rewritten_sources_are_accepted() {
+        // クライアントが書き換えたsrcは、http の画像も含めてプロキシが受け付ける
+        let c = config(&["cataas.com"]);
+        for src in ["https://cataas.com/cat", "http://cataas.com/cat?size=2"] {
+            let proxied = app::image_rewrite::proxied_src(src).unwrap();
+            assert!(proxied.starts_with(ROUTE));
+            let target = url(&format!("https://self-changer.test{}", proxied))
+                .query_pairs()
+                .find(|(key, _)| key == "url")
+                .map(|(_, value)| url(&value))
+                .unwrap();
+            assert!(c.is_allowed_url(&target), "{}", target);
+        }
+    }
+
+    #[test]
+    fn test_internal_addresses_are_not_public() {
+        for addr in [
+            "10.0.0.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.1.2.3",
+            "198.18.0.1",
+            "::ffff:10.0.0.1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "fd00::1",
+            "::127.0.0.1",
+        ] {
+            assert!(!is_public_ip(&addr.parse().unwrap()), "{}", addr);
+        }
+        for addr in ["93.184.215.14", "::ffff:93.184.215.14", "2606:4700::1111"] {
+            assert!(is_public_ip(&addr.parse().unwrap()), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn test_default_allow_list_is_not_empty() {
+        let c = ImgProxyConfig {
+            allowed_hosts: parse_allowed_hosts(" , "),
+            ..config(&[])
+        };
+        assert!(c.is_allowed_url(&url("https://fastly.picsum.photos/id/1/300/200.jpg")));
+        assert!(!c.is_allowed_url(&url("https://attacker.example/")));
+    }
+
+    #[tokio::test]
+    async fn test_hostname_resolving_to_private_address_is_rejected() {
+        // 名前による判定を通るホスト名でも、解決先が内部アドレスなら接続しない
+        assert!(matches!(
+            resolve_public("localhost").await,
+            Err(ImgProxyError::Forbidden)
+        ));
+        let resolved = PublicResolver.resolve("localhost".parse().unwrap()).await;
+        assert!(resolved.is_err());
+
+        // 取得用のクライアントも、内部アドレスに解決されるホストには接続しない
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let result = fetch(&url(&format!("https://localhost:{}/cat.png", port)), 1024).await;
+        assert!(matches!(result, Err(ImgProxyError::Upstream(_))));
+        let accepted = tokio::time::timeout(Duration::from_millis(200), listener.accept()).await;
+        assert!(accepted.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cache_is_trimmed_to_limit() {
+        let dir = std::env::temp_dir().join(format!("img-cache-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let source = |i: u8| url(&format!("https://picsum.photos/{}", i));
+        for i in 0..4u8 {
+            let path = dir.join(format!("{}", i));
+            write_cache(&path, &source(i), ImageFormat::Png, &[i; 1000]).await;
+            // 更新日時の順が決まるよう、少し間を空ける
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        // 最初に書いたものを読むと、消す順は最後になる
+        assert!(read_cache(&dir.join("0"), &source(0)).await.is_some());
+        evict_cache(&dir, 2100).await;
+        let mut left: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["0", "3"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_cache_hit_requires_same_url() {
+        let dir = std::env::temp_dir().join(format!("img-cache-url-{}", std::process::id()));
+        let path = dir.join("shared-key");
+        let original = url("https://picsum.photos/200");
+        write_cache(&path, &original, ImageFormat::Png, b"image").await;
+
+        // ファイル名が衝突した別のURLには、別の画像を返さない
+        let other = url("https://picsum.photos/200?collide=1");
+        assert!(read_cache(&path, &other).await.is_none());
+        let (format, bytes) = read_cache(&path, &original).await.unwrap();
+        assert_eq!(format, ImageFormat::Png);
+        assert_eq!(bytes, b"image");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_allowed_hosts_include_subdomains() {
+        let c = config(&["picsum.photos"]);
+        assert!(c.is_allowed_url(&url("https://picsum.photos/300/200")));
+        assert!(c.is_allowed_url(&url("https://fastly.picsum.photos/id/1/300/200.jpg")));
+        assert!(!c.is_allowed_url(&url("https://cataas.com/cat")));
+        assert!(!c.is_allowed_url(&url("https://evilpicsum.photos/")));
+    }
+}
@@ -1,13 +1,28 @@
+mod export;
+mod image_metadata;
+mod img_proxy;
+mod security_headers;
+mod session_store;
+
 use app::*;
+use axum::routing::get;
 use axum::Router;
 use dotenvy::dotenv;
 use leptos::logging::log;
 use leptos::prelude::*;
 use leptos_axum::{generate_route_list, LeptosRoutes};
+use security_headers::{security_headers, SecurityHeadersConfig};
+use session_store::SqliteSessionStore;
+use std::sync::Arc;
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
+    // プロンプトテンプレートは起動時に検証し、不正なら起動しない
+    if let Err(e) = app::prompt_templates::init_from_env() {
+        eprintln!("プロンプトテンプレートが不正です: {}", e);
+        std::process::exit(1);
+    }
     let conf = get_configuration(None).unwrap();
     let addr = conf.leptos_options.site_addr;
     let leptos_options = conf.leptos_options;
@@ -15,13 +30,43 @@ async fn main() {
     // Generate the list of routes in your Leptos App
     let routes = generate_route_list(App);
 
-    let app = Router::new()
-        .leptos_routes(&leptos_options, routes, {
-            let leptos_options = leptos_options.clone();
-            move || shell(leptos_options.clone())
-        })
+    // DATABASE_PATH が設定されていれば、会話とUI状態をセッションごとに保存する
+    let session_store: Option<app::storage::SharedSessionStore> =
+        match std::env::var("DATABASE_PATH") {
+            Ok(path) if !path.trim().is_empty() => match SqliteSessionStore::open(path.trim()) {
+                Ok(store) => Some(Arc::new(store)),
+                Err(e) => {
+                    eprintln!("データベースを開けません: {}", e);
+                    std::process::exit(1);
+                }
+            },
+            _ => None,
+        };
+
+    let mut app = Router::new().route(img_proxy::ROUTE, get(img_proxy::handler));
+    if let Some(store) = session_store.clone() {
+        app = app.route(export::ROUTE, get(export::handler).with_state(store));
+    }
+    let app = app
+        .leptos_routes_with_context(
+            &leptos_options,
+            routes,
+            move || {
+                if let Some(store) = session_store.clone() {
+                    provide_context(store);
+                }
+            },
+            {
+                let leptos_options = leptos_options.clone();
+                move || shell(leptos_options.clone())
+            },
+        )
         .fallback(leptos_axum::file_and_error_handler(shell))
-        .with_state(leptos_options);
+        .with_state(leptos_options)
+        .layer(axum::middleware::from_fn_with_state(
+            Arc::new(SecurityHeadersConfig::from_env()),
+            security_headers,
+        ));
 
     // run our app with hyper
     // `axum::Server` is a re-export of `hyper::Server`
@@ -0,0 +1,190 @@
+//! Content-Security-Policy などのセキュリティヘッダーを付与するミドルウェア
+//!
+//! AIが生成したマークアップとスタイルを表示するアプリのため、
+//! スクリプトはリクエストごとのnonce付きのものだけを許可する。
+
+use app::CSP_NONCE_HEADER;
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::sync::Arc;
+
+/// 環境変数から読み込むCSPの追加許可設定
+#[derive(Clone, Debug, Default)]
+pub struct SecurityHeadersConfig {
+    pub img_sources: Vec<String>,     // img-src に追加するソース
+    pub style_sources: Vec<String>,   // style-src-elem に追加するソース
+    pub connect_sources: Vec<String>, // connect-src に追加するソース
+}
+
+impl SecurityHeadersConfig {
+    pub fn from_env() -> Self {
+        let mut connect_sources = Vec::new();
+        // cargo leptos watch の自動リロードはWebSocketで接続する
+        if std::env::var("LEPTOS_WATCH").is_ok() {
+            connect_sources.extend(["ws:".to_string(), "wss:".to_string()]);
+        }
+        Self {
+            img_sources: parse_sources(&std::env::var("CSP_IMG_SOURCES").unwrap_or_default()),
+            style_sources: parse_sources(&std::env::var("CSP_STYLE_SOURCES").unwrap_or_default()),
+            connect_sources,
+        }
+    }
+
+    /// nonceを埋め込んだCSPヘッダーの値を組み立てる
+    pub fn content_security_policy(&self, nonce: Option<&str>) -> String {
+        let nonce_source = nonce
+            .filter(|n| is_valid_source(n))
+            .map(|n| format!(" 'nonce-{}'", n))
+            .unwrap_or_default();
+        let extra =
+            |sources: &[String]| -> String { sources.iter().map(|s| format!(" {}", s)).collect() };
+        [
+            "default-src 'self'".to_string(),
+            format!("script-src 'self'{} 'wasm-unsafe-eval'", nonce_source),
+            // 吹き出しや動的要素はstyle属性でスタイルを適用するため、属性のみインラインを許可する
+            format!(
+                "style-src-elem 'self'{}{}",
+                nonce_source,
+                extra(&self.style_sources)
+            ),
+            "style-src-attr 'unsafe-inline'".to_string(),
+            format!("img-src 'self' data:{}", extra(&self.img_sources)),
+            "font-src 'self' data:".to_string(),
+            format!("connect-src 'self'{}", extra(&self.connect_sources)),
+            "object-src 'none'".to_string(),
+            "base-uri 'self'".to_string(),
+            "form-action 'self'".to_string(),
+            "frame-ancestors 'none'".to_string(),
+        ]
+        .join("; ")
+    }
+}
+
+/// スペースまたはカンマ区切りのソース一覧を解釈する（不正なトークンは捨てる）
+fn parse_sources(value: &str) -> Vec<String> {
+    value
+        .split([' ', ','])
+        .map(str::trim)
+        .filter(|s| !s.is_empty() && is_valid_source(s))
+        .map(str::to_string)
+        .collect()
+}
+
+// ディレクティブの区切りやヘッダーインジェクションに使える文字を含むものは拒否する
+fn is_valid_source(source: &str) -> bool {
+    source.chars().all(|c| {
+        c.is_ascii_alphanumeric()
+            || matches!(c, ':' | '/' | '.' | '-' | '_' | '*' | '\'' | '+' | '=')
+    })
+}
+
+/// 全レスポンスにセキュリティヘッダーを付与し、HTMLにはCSPを付与する
+pub async fn security_headers(
+    State(config): State<Arc<SecurityHeadersConfig>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let mut res = next.run(req).await;
+    let headers = res.headers_mut();
+
+    let nonce = headers
+        .remove(HeaderName::from_static(CSP_NONCE_HEADER))
+        .and_then(|v| v.to_str().ok().map(str::to_string));
+    let is_html = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("text/html"));
+    if is_html {
+        if let Ok(csp) = HeaderValue::from_str(&config.content_security_policy(nonce.as_deref())) {
+            headers.insert(header::CONTENT_SECURITY_POLICY, csp);
+        }
+    }
+
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+    headers.insert(
+        header::REFERRER_POLICY,
+        HeaderValue::from_static("strict-origin-when-cross-origin"),
+    );
+    headers.insert(
+        HeaderName::from_static("permissions-policy"),
+        HeaderValue::from_static("camera=(), microphone=(), geolocation=()"),
+    );
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_policy_contains_nonce_and_configured_sources() {
+        let config = SecurityHeadersConfig {
+            img_sources: parse_sources("https://picsum.photos, https://cataas.com"),
+            style_sources: parse_sources("https://fonts.googleapis.com"),
+            connect_sources: Vec::new(),
+        };
+        let csp = config.content_security_policy(Some("abc123"));
+        assert!(csp.contains("script-src 'self' 'nonce-abc123' 'wasm-unsafe-eval'"));
+        assert!(csp.contains("img-src 'self' data: https://picsum.photos https://cataas.com"));
+        assert!(csp.contains("style-src-elem 'self' 'nonce-abc123' https://fonts.googleapis.com"));
+        assert!(csp.contains("frame-ancestors 'none'"));
+    }
+
+    #[test]
+    fn test_invalid_sources_are_dropped() {
+        assert_eq!(
+            parse_sources("https://evil.example;script-src https://fine.example"),
+            vec!["https://fine.example"]
+        );
+        let csp = SecurityHeadersConfig::default().content_security_policy(Some("x; y"));
+        assert!(!csp.contains("nonce-"));
+    }
+
+    #[tokio::test]
+    async fn test_middleware_moves_nonce_into_csp() {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async {
+                    (
+                        [
+                            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+                            (HeaderName::from_static(CSP_NONCE_HEADER), "n0nce"),
+                        ],
+                        "<html></html>",
+                    )
+                }),
+            )
+            .route("/plain", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::new(SecurityHeadersConfig::default()),
+                security_headers,
+            ));
+
+        let req = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        let headers = res.headers();
+        assert!(headers.get(CSP_NONCE_HEADER).is_none());
+        let csp = headers[header::CONTENT_SECURITY_POLICY].to_str().unwrap();
+        assert!(csp.contains("'nonce-n0nce'"));
+        assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+
+        let req = Request::builder()
+            .uri("/plain")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert!(res.headers().get(header::CONTENT_SECURITY_POLICY).is_none());
+        assert_eq!(res.headers()[header::X_FRAME_OPTIONS], "DENY");
+    }
+}
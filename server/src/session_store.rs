@@ -0,0 +1,872 @@
+//! SQLiteによるセッションの保存先
+//!
+//! セッションごとにUI状態（JSON）を1行、メッセージと適用したUI操作（JSON）を
+//! メッセージごとに1行保存する。保存は毎回スナップショット全体の置き換えで行う。
+//! セッションは Cookie の所有者IDに紐づき、所有者ごとに名前を付けて複数持てる。
+//! テーマも所有者ごとに、会話とは別のテーブルに名前をキーとして保存する。
+//! 版の履歴は、記録した時点のスナップショット全体（JSON）を版ごとに1行保存する。
+//! 分岐に使うメッセージごとのUI状態の変更（JSON）はメッセージの行に、分岐元はセッションの行に持つ。
+
+use app::storage::{SessionStore, StoreError, MAX_SHARES_PER_OWNER};
+use app::versions::MAX_VERSIONS;
+use common::{
+    MessageInfo, SessionSnapshot, SessionSummary, SessionVersion, Theme, UiDelta, UiOperations,
+};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+const SCHEMA: &str = "
+    PRAGMA foreign_keys = ON;
+    CREATE TABLE IF NOT EXISTS sessions (
+        id TEXT PRIMARY KEY,
+        owner TEXT NOT NULL,
+        name TEXT NOT NULL DEFAULT '',
+        ui_state TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL,
+        parent_id TEXT,
+        forked_at INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS messages (
+        session_id TEXT NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+        id INTEGER NOT NULL,
+        is_user INTEGER NOT NULL,
+        text TEXT NOT NULL,
+        ops TEXT,
+        ui_delta TEXT,
+        PRIMARY KEY (session_id, id)
+    );
+    CREATE TABLE IF NOT EXISTS versions (
+        session_id TEXT NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+        number INTEGER NOT NULL,
+        restored_from INTEGER,
+        snapshot TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        PRIMARY KEY (session_id, number)
+    );
+    CREATE TABLE IF NOT EXISTS shares (
+        id TEXT PRIMARY KEY,
+        owner TEXT NOT NULL DEFAULT '',
+        snapshot TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        expires_at INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS themes (
+        owner TEXT NOT NULL,
+        name TEXT NOT NULL,
+        container_styles TEXT NOT NULL,
+        role_styles TEXT NOT NULL,
+        updated_at INTEGER NOT NULL,
+        PRIMARY KEY (owner, name)
+    );
+";
+
+// 名前付きセッション以前の sessions テーブルは、Cookie の値がそのままセッションIDだった。
+// 所有者をそのIDにして、同じブラウザから名前なしのセッションとして見えるようにする
+const MIGRATE_OWNER: &str = "
+    ALTER TABLE sessions ADD COLUMN owner TEXT NOT NULL DEFAULT '';
+    ALTER TABLE sessions ADD COLUMN name TEXT NOT NULL DEFAULT '';
+    UPDATE sessions SET owner = id;
+";
+
+// 分岐以前のテーブルには、分岐元とメッセージごとのUI状態の変更の列がない
+const MIGRATE_PARENT: &str = "
+    ALTER TABLE sessions ADD COLUMN parent_id TEXT;
+    ALTER TABLE sessions ADD COLUMN forked_at INTEGER;
+";
+const MIGRATE_UI_DELTA: &str = "ALTER TABLE messages ADD COLUMN ui_delta TEXT";
+
+// 所有者ごとの上限以前の共有には所有者がない（上限の対象外として残す）
+const MIGRATE_SHARE_OWNER: &str = "ALTER TABLE shares ADD COLUMN owner TEXT NOT NULL DEFAULT ''";
+
+pub struct SqliteSessionStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteSessionStore {
+    /// データベースファイルを開き、なければテーブルを作る
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        Self::init(Connection::open(path).map_err(backend)?)
+    }
+
+    /// テスト用のメモリ上のデータベース
+    #[cfg(test)]
+    pub fn in_memory() -> Result<Self, StoreError> {
+        Self::init(Connection::open_in_memory().map_err(backend)?)
+    }
+
+    fn init(conn: Connection) -> Result<Self, StoreError> {
+        conn.execute_batch(SCHEMA).map_err(backend)?;
+        if !has_column(&conn, "sessions", "owner")? {
+            conn.execute_batch(MIGRATE_OWNER).map_err(backend)?;
+        }
+        if !has_column(&conn, "sessions", "parent_id")? {
+            conn.execute_batch(MIGRATE_PARENT).map_err(backend)?;
+        }
+        if !has_column(&conn, "messages", "ui_delta")? {
+            conn.execute_batch(MIGRATE_UI_DELTA).map_err(backend)?;
+        }
+        if !has_column(&conn, "shares", "owner")? {
+            conn.execute_batch(MIGRATE_SHARE_OWNER).map_err(backend)?;
+        }
+        conn.execute_batch(
+            "CREATE INDEX IF NOT EXISTS sessions_owner ON sessions (owner);
+             CREATE INDEX IF NOT EXISTS shares_owner ON shares (owner)",
+        )
+        .map_err(backend)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        // 書き込み中にパニックしてもトランザクションは巻き戻るため、接続はそのまま使える
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl SessionStore for SqliteSessionStore {
+    fn list(&self, owner: &str) -> Result<Vec<SessionSummary>, StoreError> {
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare(
+                "SELECT id, name, updated_at, parent_id, forked_at FROM sessions WHERE owner = ?1
+                 ORDER BY updated_at DESC, rowid DESC",
+            )
+            .map_err(backend)?;
+        let rows = stmt
+            .query_map([owner], |row| {
+                Ok((
+                    SessionSummary {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                        updated_at: row.get(2)?,
+                        parent_id: row.get(3)?,
+                        forked_at: None,
+                    },
+                    row.get::<_, Option<i64>>(4)?,
+                ))
+            })
+            .map_err(backend)?;
+        let mut sessions = Vec::new();
+        for row in rows {
+            let (mut session, forked_at) = row.map_err(backend)?;
+            session.forked_at = forked_at
+                .map(usize::try_from)
+                .transpose()
+                .map_err(corrupt)?;
+            sessions.push(session);
+        }
+        Ok(sessions)
+    }
+
+    fn load(&self, owner: &str, session_id: &str) -> Result<Option<SessionSnapshot>, StoreError> {
+        let conn = self.conn();
+        let ui_state: Option<String> = conn
+            .query_row(
+                "SELECT ui_state FROM sessions WHERE id = ?1 AND owner = ?2",
+                [session_id, owner],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(backend)?;
+        let Some(ui_state) = ui_state else {
+            return Ok(None);
+        };
+
+        let mut stmt = conn
+            .prepare(
+                "SELECT id, is_user, text, ops, ui_delta FROM messages
+                 WHERE session_id = ?1 ORDER BY id",
+            )
+            .map_err(backend)?;
+        let rows = stmt
+            .query_map([session_id], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, bool>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                ))
+            })
+            .map_err(backend)?;
+        let mut messages = Vec::new();
+        let mut ui_history = BTreeMap::new();
+        for row in rows {
+            let (id, is_user, text, ops, ui_delta) = row.map_err(backend)?;
+            let id = usize::try_from(id).map_err(corrupt)?;
+            let ops = ops
+                .map(|ops| serde_json::from_str::<UiOperations>(&ops))
+                .transpose()
+                .map_err(corrupt)?;
+            if let Some(ui_delta) = ui_delta {
+                ui_history.insert(
+                    id,
+                    serde_json::from_str::<UiDelta>(&ui_delta).map_err(corrupt)?,
+                );
+            }
+            messages.push(MessageInfo {
+                id,
+                is_user,
+                text,
+                ops,
+            });
+        }
+        Ok(Some(SessionSnapshot {
+            messages,
+            ui_state: serde_json::from_str(&ui_state).map_err(corrupt)?,
+            ui_history,
+        }))
+    }
+
+    fn create(
+        &self,
+        owner: &str,
+        session_id: &str,
+        name: &str,
+        parent: Option<(&str, usize)>,
+        snapshot: &SessionSnapshot,
+    ) -> Result<SessionSummary, StoreError> {
+        let ui_state = serde_json::to_string(&snapshot.ui_state).map_err(corrupt)?;
+        let (parent_id, forked_at) = parent.unzip();
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(backend)?;
+        let updated_at = tx
+            .query_row(
+                "INSERT INTO sessions
+                     (id, owner, name, ui_state, created_at, updated_at, parent_id, forked_at)
+                 VALUES (?1, ?2, ?3, ?4, unixepoch(), unixepoch(), ?5, ?6)
+                 RETURNING updated_at",
+                params![
+                    session_id,
+                    owner,
+                    name,
+                    ui_state,
+                    parent_id,
+                    forked_at.map(|id| id as i64)
+                ],
+                |row| row.get(0),
+            )
+            .map_err(backend)?;
+        insert_messages(&tx, session_id, snapshot)?;
+        tx.commit().map_err(backend)?;
+        Ok(SessionSummary {
+            id: session_id.to_string(),
+            name: name.to_string(),
+            updated_at,
+            parent_id: parent_id.map(str::to_string),
+            forked_at,
+        })
+    }
+
+    fn save(
+        &self,
+        owner: &str,
+        session_id: &str,
+        snapshot: &SessionSnapshot,
+    ) -> Result<(), StoreError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(backend)?;
+        write_snapshot(&tx, owner, session_id, snapshot)?;
+        tx.commit().map_err(backend)
+    }
+
+    fn rename(&self, owner: &str, session_id: &str, name: &str) -> Result<(), StoreError> {
+        let updated = self
+            .conn()
+            .execute(
+                "UPDATE sessions SET name = ?3 WHERE id = ?1 AND owner = ?2",
+                params![session_id, owner, name],
+            )
+            .map_err(backend)?;
+        if updated == 0 {
+            return Err(StoreError::NotFound);
+        }
+        Ok(())
+    }
+
+    fn delete(&self, owner: &str, session_id: &str) -> Result<(), StoreError> {
+        self.conn()
+            .execute(
+                "DELETE FROM sessions WHERE id = ?1 AND owner = ?2",
+                [session_id, owner],
+            )
+            .map(|_| ())
+            .map_err(backend)
+    }
+
+    fn save_version(
+        &self,
+        owner: &str,
+        session_id: &str,
+        snapshot: &SessionSnapshot,
+        restored_from: Option<u32>,
+    ) -> Result<SessionVersion, StoreError> {
+        let json = serde_json::to_string(snapshot).map_err(corrupt)?;
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(backend)?;
+        write_snapshot(&tx, owner, session_id, snapshot)?;
+        let (number, created_at): (u32, i64) = tx
+            .query_row(
+                "INSERT INTO versions (session_id, number, restored_from, snapshot, created_at)
+                 SELECT ?1, COALESCE(MAX(number), 0) + 1, ?2, ?3, unixepoch()
+                 FROM versions WHERE session_id = ?1
+                 RETURNING number, created_at",
+                params![session_id, restored_from, json],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .map_err(backend)?;
+        // 番号は振り直さず、古い版から消す
+        tx.execute(
+            "DELETE FROM versions WHERE session_id = ?1 AND number <= ?2",
+            params![session_id, i64::from(number) - MAX_VERSIONS as i64],
+        )
+        .map_err(backend)?;
+        tx.commit().map_err(backend)?;
+        Ok(SessionVersion {
+            number,
+            created_at,
+            restored_from,
+            snapshot: snapshot.clone(),
+        })
+    }
+
+    fn list_versions(
+        &self,
+        owner: &str,
+        session_id: &str,
+    ) -> Result<Vec<SessionVersion>, StoreError> {
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare(
+                "SELECT v.number, v.created_at, v.restored_from, v.snapshot
+                 FROM versions v JOIN sessions s ON s.id = v.session_id
+                 WHERE v.session_id = ?1 AND s.owner = ?2
+                 ORDER BY v.number",
+            )
+            .map_err(backend)?;
+        let rows = stmt
+            .query_map([session_id, owner], |row| {
+                Ok((
+                    row.get::<_, u32>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, Option<u32>>(2)?,
+                    row.get::<_, String>(3)?,
+                ))
+            })
+            .map_err(backend)?;
+        let mut versions = Vec::new();
+        for row in rows {
+            let (number, created_at, restored_from, snapshot) = row.map_err(backend)?;
+            versions.push(SessionVersion {
+                number,
+                created_at,
+                restored_from,
+                snapshot: serde_json::from_str(&snapshot).map_err(corrupt)?,
+            });
+        }
+        Ok(versions)
+    }
+
+    fn save_share(
+        &self,
+        owner: &str,
+        share_id: &str,
+        snapshot: &SessionSnapshot,
+        expires_at: Option<i64>,
+        now: i64,
+    ) -> Result<(), StoreError> {
+        let snapshot = serde_json::to_string(snapshot).map_err(corrupt)?;
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(backend)?;
+        // 開かれないまま期限切れになった共有も残さない
+        tx.execute("DELETE FROM shares WHERE expires_at <= ?1", [now])
+            .map_err(backend)?;
+        tx.execute(
+            "INSERT INTO shares (id, owner, snapshot, created_at, expires_at)
+             VALUES (?1, ?2, ?3, unixepoch(), ?4)",
+            params![share_id, owner, snapshot, expires_at],
+        )
+        .map_err(backend)?;
+        tx.execute(
+            "DELETE FROM shares WHERE owner = ?1 AND rowid NOT IN (
+                 SELECT rowid FROM shares WHERE owner = ?1 ORDER BY rowid DESC LIMIT ?2
+             )",
+            params![owner, MAX_SHARES_PER_OWNER as i64],
+        )
+        .map_err(backend)?;
+        tx.commit().map_err(backend)
+    }
+
+    fn load_share(&self, share_id: &str, now: i64) -> Result<Option<SessionSnapshot>, StoreError> {
+        let conn = self.conn();
+        let row: Option<(String, Option<i64>)> = conn
+            .query_row(
+                "SELECT snapshot, expires_at FROM shares WHERE id = ?1",
+                [share_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(backend)?;
+        match row {
+            Some((_, Some(expires_at))) if expires_at <= now => {
+                // 期限切れの共有はその場で削除する
+                conn.execute("DELETE FROM shares WHERE id = ?1", [share_id])
+                    .map_err(backend)?;
+                Ok(None)
+            }
+            Some((snapshot, _)) => serde_json::from_str(&snapshot).map(Some).map_err(corrupt),
+            None => Ok(None),
+        }
+    }
+
+    fn list_themes(&self, owner: &str) -> Result<Vec<Theme>, StoreError> {
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare(
+                "SELECT name, container_styles, role_styles, updated_at FROM themes
+                 WHERE owner = ?1 ORDER BY name",
+            )
+            .map_err(backend)?;
+        let rows = stmt
+            .query_map([owner], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, i64>(3)?,
+                ))
+            })
+            .map_err(backend)?;
+        let mut themes = Vec::new();
+        for row in rows {
+            let (name, container_styles, role_styles, updated_at) = row.map_err(backend)?;
+            themes.push(Theme {
+                name,
+                container_styles,
+                role_styles: serde_json::from_str(&role_styles).map_err(corrupt)?,
+                updated_at,
+            });
+        }
+        Ok(themes)
+    }
+
+    fn save_theme(&self, owner: &str, theme: &Theme) -> Result<Theme, StoreError> {
+        let role_styles = serde_json::to_string(&theme.role_styles).map_err(corrupt)?;
+        let updated_at = self
+            .conn()
+            .query_row(
+                "INSERT INTO themes (owner, name, container_styles, role_styles, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, unixepoch())
+                 ON CONFLICT (owner, name) DO UPDATE SET
+                     container_styles = ?3, role_styles = ?4, updated_at = unixepoch()
+                 RETURNING updated_at",
+                params![owner, theme.name, theme.container_styles, role_styles],
+                |row| row.get(0),
+            )
+            .map_err(backend)?;
+        Ok(Theme {
+            updated_at,
+            ..theme.clone()
+        })
+    }
+
+    fn delete_theme(&self, owner: &str, name: &str) -> Result<(), StoreError> {
+        self.conn()
+            .execute(
+                "DELETE FROM themes WHERE owner = ?1 AND name = ?2",
+                [owner, name],
+            )
+            .map(|_| ())
+            .map_err(backend)
+    }
+}
+
+// セッションの会話とUI状態を置き換える（なければ名前なしで作る。他の所有者のものは NotFound）
+fn write_snapshot(
+    tx: &Transaction<'_>,
+    owner: &str,
+    session_id: &str,
+    snapshot: &SessionSnapshot,
+) -> Result<(), StoreError> {
+    let ui_state = serde_json::to_string(&snapshot.ui_state).map_err(corrupt)?;
+    let existing: Option<String> = tx
+        .query_row(
+            "SELECT owner FROM sessions WHERE id = ?1",
+            [session_id],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(backend)?;
+    match existing {
+        // 他の所有者のセッションは上書きしない
+        Some(existing) if existing != owner => return Err(StoreError::NotFound),
+        Some(_) => {
+            tx.execute(
+                "UPDATE sessions SET ui_state = ?2, updated_at = unixepoch() WHERE id = ?1",
+                params![session_id, ui_state],
+            )
+            .map_err(backend)?;
+            tx.execute("DELETE FROM messages WHERE session_id = ?1", [session_id])
+                .map_err(backend)?;
+        }
+        None => {
+            tx.execute(
+                "INSERT INTO sessions (id, owner, ui_state, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, unixepoch(), unixepoch())",
+                params![session_id, owner, ui_state],
+            )
+            .map_err(backend)?;
+        }
+    }
+    insert_messages(tx, session_id, snapshot)
+}
+
+// セッションのメッセージを1行ずつ追加する
+fn insert_messages(
+    tx: &Transaction<'_>,
+    session_id: &str,
+    snapshot: &SessionSnapshot,
+) -> Result<(), StoreError> {
+    let mut insert = tx
+        .prepare(
+            "INSERT INTO messages (session_id, id, is_user, text, ops, ui_delta)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )
+        .map_err(backend)?;
+    for message in &snapshot.messages {
+        let ops = message
+            .ops
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(corrupt)?;
+        let ui_delta = snapshot
+            .ui_history
+            .get(&message.id)
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(corrupt)?;
+        insert
+            .execute(params![
+                session_id,
+                message.id as i64,
+                message.is_user,
+                message.text,
+                ops,
+                ui_delta
+            ])
+            .map_err(backend)?;
+    }
+    Ok(())
+}
+
+// テーブルに列があるか（古いデータベースの移行に使う）
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool, StoreError> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+        [table, column],
+        |row| row.get(0),
+    )
+    .map_err(backend)
+}
+
+fn backend(e: impl std::fmt::Display) -> StoreError {
+    StoreError::Backend(e.to_string())
+}
+
+fn corrupt(e: impl std::fmt::Display) -> StoreError {
+    StoreError::Corrupt(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{StyleUpdate, UiState};
+
+    fn snapshot(reply: &str) -> SessionSnapshot {
+        let mut ui_state = UiState::default();
+        ui_state.element_styles.insert(1, "color: red;".to_string());
+        SessionSnapshot {
+            messages: vec![
+                MessageInfo {
+                    id: 1,
+                    is_user: true,
+                    text: "赤くして".to_string(),
+                    ops: None,
+                },
+                MessageInfo {
+                    id: 2,
+                    is_user: false,
+                    text: reply.to_string(),
+                    ops: Some(UiOperations {
+                        success: true,
+                        chat_container_styles: None,
+                        change_style_elements: Some(vec![StyleUpdate {
+                            id: 1,
+                            styles: "color: red;".to_string(),
+                        }]),
+                        new_elements: None,
+                    }),
+                },
+            ],
+            // 返信の時点のUI状態の変更
+            ui_history: BTreeMap::from([(2, ui_state.delta_from(&UiState::default()))]),
+            ui_state,
+        }
+    }
+
+    #[test]
+    fn test_save_load_and_delete() {
+        let store = SqliteSessionStore::in_memory().unwrap();
+        assert_eq!(store.load("me", "a").unwrap(), None);
+
+        store.save("me", "a", &snapshot("変更しました")).unwrap();
+        store.save("me", "b", &snapshot("別のセッション")).unwrap();
+        assert_eq!(
+            store.load("me", "a").unwrap(),
+            Some(snapshot("変更しました"))
+        );
+
+        // 保存し直すとメッセージごと置き換わる（なくなったメッセージのUI状態の変更も消える）
+        let mut shorter = snapshot("変更しました");
+        shorter.messages.truncate(1);
+        store.save("me", "a", &shorter).unwrap();
+        shorter.ui_history.clear();
+        assert_eq!(store.load("me", "a").unwrap(), Some(shorter));
+
+        store.delete("me", "a").unwrap();
+        assert_eq!(store.load("me", "a").unwrap(), None);
+        assert_eq!(
+            store.load("me", "b").unwrap(),
+            Some(snapshot("別のセッション"))
+        );
+    }
+
+    #[test]
+    fn test_named_sessions_belong_to_owner() {
+        let store = SqliteSessionStore::in_memory().unwrap();
+        let created = store
+            .create("me", "a", "青いテーマ", None, &snapshot("a"))
+            .unwrap();
+        assert_eq!(created.name, "青いテーマ");
+        store
+            .create("me", "b", "赤いテーマ", Some(("a", 2)), &snapshot("b"))
+            .unwrap();
+        store.rename("me", "b", "暗いテーマ").unwrap();
+
+        let mut names: Vec<String> = store
+            .list("me")
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["暗いテーマ", "青いテーマ"]);
+        // 分岐したセッションは分岐元を覚えている
+        let sessions = store.list("me").unwrap();
+        let branch = sessions.iter().find(|s| s.id == "b").unwrap();
+        assert_eq!(
+            (branch.parent_id.as_deref(), branch.forked_at),
+            (Some("a"), Some(2))
+        );
+        assert_eq!(created.parent_id, None);
+
+        // 他の所有者からは見えず、変更もできない
+        assert!(store.list("other").unwrap().is_empty());
+        assert_eq!(store.load("other", "a").unwrap(), None);
+        assert_eq!(
+            store.save("other", "a", &SessionSnapshot::default()),
+            Err(StoreError::NotFound)
+        );
+        assert_eq!(
+            store.rename("other", "a", "乗っ取り"),
+            Err(StoreError::NotFound)
+        );
+        store.delete("other", "a").unwrap();
+        assert_eq!(store.load("me", "a").unwrap(), Some(snapshot("a")));
+    }
+
+    #[test]
+    fn test_migrates_sessions_without_owner() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE sessions (
+                 id TEXT PRIMARY KEY,
+                 ui_state TEXT NOT NULL,
+                 created_at INTEGER NOT NULL,
+                 updated_at INTEGER NOT NULL
+             );
+             CREATE TABLE messages (
+                 session_id TEXT NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+                 id INTEGER NOT NULL,
+                 is_user INTEGER NOT NULL,
+                 text TEXT NOT NULL,
+                 ops TEXT,
+                 PRIMARY KEY (session_id, id)
+             );
+             INSERT INTO sessions VALUES ('cookie',
+                 '{\"chat_container_styles\":\"\",\"element_styles\":{},\"dynamic_elements\":{}}',
+                 1, 2);
+             INSERT INTO messages VALUES ('cookie', 0, 1, 'こんにちは', NULL);",
+        )
+        .unwrap();
+
+        let store = SqliteSessionStore::init(conn).unwrap();
+        assert_eq!(
+            store.list("cookie").unwrap(),
+            vec![SessionSummary {
+                id: "cookie".to_string(),
+                name: String::new(),
+                updated_at: 2,
+                parent_id: None,
+                forked_at: None,
+            }]
+        );
+        let loaded = store.load("cookie", "cookie").unwrap().unwrap();
+        assert_eq!(loaded.messages.len(), 1);
+        assert!(loaded.ui_history.is_empty());
+        store.save("cookie", "cookie", &snapshot("移行後")).unwrap();
+        assert_eq!(
+            store.load("cookie", "cookie").unwrap(),
+            Some(snapshot("移行後"))
+        );
+    }
+
+    #[test]
+    fn test_themes_replace_by_name() {
+        let store = SqliteSessionStore::in_memory().unwrap();
+        let theme = |name: &str, background: &str| Theme {
+            name: name.to_string(),
+            container_styles: format!("background: {};", background),
+            role_styles: common::RoleStyles {
+                user_message: "color: white;".to_string(),
+                ..Default::default()
+            },
+            updated_at: 0,
+        };
+        store.save_theme("me", &theme("夜", "#000")).unwrap();
+        store.save_theme("me", &theme("朝", "#fff")).unwrap();
+        let saved = store.save_theme("me", &theme("夜", "#111")).unwrap();
+        assert!(saved.updated_at > 0);
+
+        let themes = store.list_themes("me").unwrap();
+        assert_eq!(themes.len(), 2);
+        let night = themes.iter().find(|t| t.name == "夜").unwrap();
+        assert_eq!(night.container_styles, "background: #111;");
+        assert_eq!(night.role_styles.user_message, "color: white;");
+        assert!(store.list_themes("other").unwrap().is_empty());
+
+        store.delete_theme("other", "夜").unwrap();
+        store.delete_theme("me", "夜").unwrap();
+        assert_eq!(store.list_themes("me").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_versions_are_recorded_and_trimmed() {
+        let store = SqliteSessionStore::in_memory().unwrap();
+        let first = store
+            .save_version("me", "a", &snapshot("1回目"), None)
+            .unwrap();
+        assert_eq!((first.number, first.restored_from), (1, None));
+        store
+            .save_version("me", "a", &snapshot("2回目"), None)
+            .unwrap();
+        // 保存のたびにセッションも更新される
+        assert_eq!(store.load("me", "a").unwrap(), Some(snapshot("2回目")));
+
+        // 復元は新しい版として記録する
+        let restored = store
+            .save_version("me", "a", &first.snapshot, Some(1))
+            .unwrap();
+        assert_eq!((restored.number, restored.restored_from), (3, Some(1)));
+        assert_eq!(store.load("me", "a").unwrap(), Some(snapshot("1回目")));
+        let versions = store.list_versions("me", "a").unwrap();
+        assert_eq!(
+            versions.iter().map(|v| v.number).collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+        assert_eq!(versions[1].snapshot, snapshot("2回目"));
+
+        // 他の所有者からは見えず、記録もできない
+        assert!(store.list_versions("other", "a").unwrap().is_empty());
+        assert_eq!(
+            store.save_version("other", "a", &SessionSnapshot::default(), None),
+            Err(StoreError::NotFound)
+        );
+
+        // 古い版から消え、番号は振り直さない
+        for _ in 0..MAX_VERSIONS {
+            store
+                .save_version("me", "a", &snapshot("続き"), None)
+                .unwrap();
+        }
+        let versions = store.list_versions("me", "a").unwrap();
+        assert_eq!(versions.len(), MAX_VERSIONS);
+        assert_eq!(versions[0].number, 4);
+
+        // セッションを消すと版も消える
+        store.delete("me", "a").unwrap();
+        assert!(store.list_versions("me", "a").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_shares_expire() {
+        let store = SqliteSessionStore::in_memory().unwrap();
+        store
+            .save_share("me", "forever", &snapshot("a"), None, 0)
+            .unwrap();
+        store
+            .save_share("me", "week", &snapshot("b"), Some(1_000 + 7 * 86_400), 0)
+            .unwrap();
+
+        assert_eq!(
+            store.load_share("forever", i64::MAX).unwrap(),
+            Some(snapshot("a"))
+        );
+        assert_eq!(
+            store.load_share("week", 1_000).unwrap(),
+            Some(snapshot("b"))
+        );
+        assert_eq!(store.load_share("week", 1_000 + 7 * 86_400).unwrap(), None);
+        // 期限切れの共有は削除されている
+        assert_eq!(store.load_share("week", 0).unwrap(), None);
+        assert_eq!(store.load_share("missing", 0).unwrap(), None);
+
+        // 共有はセッションとは別に保存される
+        assert_eq!(store.load("forever", "forever").unwrap(), None);
+    }
+
+    #[test]
+    fn test_saving_share_purges_expired_and_caps_per_owner() {
+        let store = SqliteSessionStore::in_memory().unwrap();
+        store
+            .save_share("other", "expired", &snapshot("a"), Some(500), 0)
+            .unwrap();
+        store
+            .save_share("other", "kept", &snapshot("b"), None, 0)
+            .unwrap();
+        for i in 0..=MAX_SHARES_PER_OWNER {
+            let id = format!("share-{}", i);
+            store
+                .save_share("me", &id, &snapshot("c"), None, 1_000)
+                .unwrap();
+        }
+
+        // 誰も開かなかった期限切れの共有も、次の保存で消える
+        let count =
+            |sql: &str| -> i64 { store.conn().query_row(sql, [], |row| row.get(0)).unwrap() };
+        assert_eq!(count("SELECT COUNT(*) FROM shares WHERE id = 'expired'"), 0);
+        // 所有者ごとに新しいものから上限まで残し、他の所有者の共有は消さない
+        assert_eq!(
+            count("SELECT COUNT(*) FROM shares WHERE owner = 'me'"),
+            MAX_SHARES_PER_OWNER as i64
+        );
+        assert_eq!(store.load_share("share-0", 1_000).unwrap(), None);
+        assert!(store.load_share("share-1", 1_000).unwrap().is_some());
+        assert!(store.load_share("kept", 1_000).unwrap().is_some());
+    }
+}